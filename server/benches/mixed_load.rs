@@ -0,0 +1,161 @@
+//! Throughput of index queries while the storage server applies tag updates.
+//!
+//! Run with `cargo bench --bench mixed_load`, or with `--profile dev` to skip building the web
+//! client. Each round queries the shared snapshot from a number of reader threads for `ROUND`
+//! and is run once without and once with a concurrent writer, for each index in `INDEXES`.
+
+use std::{
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use pea_server::utils::storage::{FileIndex, Message, StorageHandle, StorageServer, TagUpdate};
+use tokio::sync::oneshot;
+
+/// Index file names and the number of files in them. The JSON index rewrites the whole file on
+/// every tag update, so it is only measured with a small library where writes still keep up,
+/// while the SQLite index is measured with a medium and a large library.
+const INDEXES: [(&str, usize); 3] = [
+    ("index.json", 2_000),
+    ("index.db", 20_000),
+    ("index.db", 100_000),
+];
+const ROUND: Duration = Duration::from_secs(1);
+const READERS: [usize; 4] = [1, 2, 4, 8];
+
+fn main() {
+    for (index_name, files) in INDEXES {
+        println!("{index_name} with {files} files");
+        run_index(index_name, files);
+    }
+}
+
+fn run_index(index_name: &str, files: usize) {
+    let dir = std::env::temp_dir().join(format!("pea-mixed-load-{}", std::process::id()));
+    let index_path = dir.join(index_name);
+    let ids = create_index(&dir, &index_path, files);
+    let storage = StorageServer::initialize(&index_path).expect("failed to start storage server");
+
+    println!("readers  writer  reads/s     writes/s");
+    for readers in READERS {
+        for writer in [false, true] {
+            let (reads, writes) = run_round(&storage, &ids, readers, writer);
+            println!(
+                "{readers:<8} {:<7} {:<11.0} {:.0}",
+                if writer { "yes" } else { "no" },
+                reads as f64 / ROUND.as_secs_f64(),
+                writes as f64 / ROUND.as_secs_f64(),
+            );
+        }
+    }
+
+    let (tx, rx) = oneshot::channel();
+    storage
+        .transmitter
+        .send(Message::ShutDown(tx))
+        .expect("failed to shut down storage server");
+    rx.blocking_recv()
+        .expect("storage server dropped the shutdown")
+        .expect("failed to close index");
+    fs::remove_dir_all(&dir).expect("failed to remove benchmark directory");
+}
+
+fn create_index(dir: &Path, index_path: &Path, files: usize) -> Vec<u64> {
+    let files_dir = dir.join("files");
+    fs::create_dir_all(files_dir.join("media")).expect("failed to create benchmark directory");
+    for i in 0..files {
+        let ty = if i % 2 == 0 { "txt" } else { "md" };
+        fs::write(
+            files_dir.join(format!("media/{i}.{ty}")),
+            format!("file {i}"),
+        )
+        .expect("failed to create benchmark file");
+    }
+    let mut index = FileIndex::new(index_path).expect("failed to create index");
+    index.add_dir(&files_dir).expect("failed to index files");
+    index
+        .files()
+        .expect("failed to read index")
+        .into_iter()
+        .map(|file| file.id)
+        .collect()
+}
+
+/// Returns the number of reads and writes done during the round.
+fn run_round(storage: &StorageHandle, ids: &[u64], readers: usize, writer: bool) -> (u64, u64) {
+    let stop = Arc::new(AtomicBool::new(false));
+    let reads = Arc::new(AtomicU64::new(0));
+    let mut threads = Vec::new();
+    for reader in 0..readers {
+        let snapshot = storage.snapshot.clone();
+        let ids = ids.to_vec();
+        let stop = stop.clone();
+        let reads = reads.clone();
+        threads.push(thread::spawn(move || {
+            let tags = vec!["media".to_string()];
+            let mut count = 0;
+            while !stop.load(Ordering::Relaxed) {
+                let snapshot = snapshot.load();
+                let found = match count % 4 {
+                    0 => snapshot.files().len(),
+                    1 => snapshot.files_of_type("txt").len(),
+                    2 => snapshot.files_of_tags(&tags).len(),
+                    _ => snapshot
+                        .get_file(ids[(count + reader) % ids.len()])
+                        .map_or(0, |_| 1),
+                };
+                assert!(found > 0, "query found no files");
+                count += 1;
+            }
+            reads.fetch_add(count as u64, Ordering::Relaxed);
+        }));
+    }
+    let writes = Arc::new(AtomicU64::new(0));
+    if writer {
+        let transmitter = storage.transmitter.clone();
+        let ids = ids.to_vec();
+        let stop = stop.clone();
+        let writes = writes.clone();
+        threads.push(thread::spawn(move || {
+            let mut count = 0;
+            while !stop.load(Ordering::Relaxed) {
+                let tag = vec!["bench".to_string()];
+                let update = if count % 2 == 0 {
+                    TagUpdate {
+                        add: tag,
+                        ..Default::default()
+                    }
+                } else {
+                    TagUpdate {
+                        remove: tag,
+                        ..Default::default()
+                    }
+                };
+                let (tx, rx) = oneshot::channel();
+                transmitter
+                    .send(Message::UpdateTags(ids[count / 2 % ids.len()], update, tx))
+                    .expect("failed to send update");
+                rx.blocking_recv()
+                    .expect("storage server dropped the update")
+                    .expect("failed to update tags");
+                count += 1;
+            }
+            writes.fetch_add(count as u64, Ordering::Relaxed);
+        }));
+    }
+    thread::sleep(ROUND);
+    stop.store(true, Ordering::Relaxed);
+    for thread in threads {
+        thread.join().expect("benchmark thread panicked");
+    }
+    (
+        reads.load(Ordering::Relaxed),
+        writes.load(Ordering::Relaxed),
+    )
+}
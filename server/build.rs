@@ -55,6 +55,7 @@ fn create_server_config(config_path: &Path) {
     let mut f = std::fs::OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(true)
         .open(config_path)
         .unwrap();
     f.write_all(config.as_bytes()).unwrap();
@@ -1,7 +1,14 @@
 use std::{env, path::PathBuf};
 
-use pea_server::utils::storage::FileIndex;
+use log::{info, warn};
+use pea_server::utils::{
+    config::{ContentRoot, ServerConfig},
+    rules::IndexRules,
+    storage::FileIndex,
+};
 
+/// Usage: `index_builder [index file] [content root]...` where each content root is either a
+/// path or `name=path` to register it as a named root.
 fn main() {
     simple_logger::SimpleLogger::new().init().unwrap();
     let index_file = PathBuf::from(
@@ -9,11 +16,46 @@ fn main() {
             .nth(1)
             .unwrap_or_else(|| env::var("PEA_INDEX_FILE").unwrap()),
     );
-    let content_root = PathBuf::from(
-        std::env::args()
-            .nth(2)
-            .unwrap_or_else(|| env::var("PEA_FILES_DIR").unwrap()),
-    );
-    let mut index = FileIndex::new(&index_file);
-    index.add_dir(&content_root).unwrap();
+    let mut content_roots: Vec<String> = std::env::args().skip(2).collect();
+    if content_roots.is_empty() {
+        content_roots.push(env::var("PEA_FILES_DIR").unwrap());
+    }
+    let config = ServerConfig::load().unwrap();
+    let mut index = FileIndex::new(&index_file).unwrap();
+    index.set_rules(IndexRules::new(&config.indexing).unwrap());
+    for content_root in content_roots {
+        let path = match content_root.split_once('=') {
+            Some((name, path)) => {
+                // keep the settings of roots that are already registered
+                let root = index
+                    .roots()
+                    .unwrap()
+                    .into_iter()
+                    .find(|root| root.name == name)
+                    .unwrap_or_else(|| ContentRoot {
+                        name: name.to_string(),
+                        ..Default::default()
+                    });
+                let root = index
+                    .register_root(ContentRoot {
+                        path: PathBuf::from(path),
+                        ..root
+                    })
+                    .unwrap();
+                root.path
+            }
+            None => PathBuf::from(content_root),
+        };
+        let report = index.rescan(&path).unwrap();
+        info!(
+            "{path:?}: added {} files, updated {} files, removed {} files",
+            report.added, report.updated, report.removed
+        );
+        for failure in &report.failed {
+            warn!(
+                "failed to index {:?} due to {}",
+                failure.path, failure.error
+            );
+        }
+    }
 }
@@ -1,19 +1,40 @@
 use std::{
+    cmp::Ordering,
+    collections::HashMap,
     env,
     net::{self, SocketAddr},
-    path::PathBuf,
+    path::{Path, PathBuf},
     thread,
-    time::Duration,
+    time::{Duration, UNIX_EPOCH},
     vec::IntoIter,
 };
 
+use actix_web::{
+    http::{
+        header::{self, HeaderName, HeaderValue},
+        Method,
+    },
+    HttpMessage as _,
+};
+use base64::Engine as _;
 use futures_util::StreamExt as _;
 use log::{debug, error, info};
 use pea_server::utils::{
+    config::{ContentRoot, ServerConfig},
     get_local_ip_address,
+    query::parse,
     registry::{register_server, unregister_server, RegistryData},
-    storage::{FileMetadata, Message, StorageServer},
+    snapshot::SharedSnapshot,
+    storage::{
+        lossless_path, ConflictPolicy, FileErr, FileMetadata, FileUpdate, Message,
+        ReplyTransmitter, StorageHandle, StorageServer, TagUpdate, Upload, UploadOptions,
+    },
+    systemd,
+    trash::TrashEntry,
+    uploads::PendingUpload,
+    watcher::{watch_roots, RootWatcher},
 };
+use tokio::io::AsyncWriteExt as _;
 
 struct Config {
     id: uuid::Uuid,
@@ -21,8 +42,28 @@ struct Config {
     index_path: PathBuf,
 }
 
+const WATCH_DEBOUNCE: Duration = Duration::from_secs(1);
+/// How long handlers wait for a reply of the storage server by default.
+const STORAGE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long in-flight requests may take to finish once the server is shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
 struct ServerState {
     storage_server_transmitter: crossbeam_channel::Sender<Message>,
+    /// Queries read the latest snapshot directly instead of waiting for the storage server.
+    snapshot: SharedSnapshot,
+    /// How long to wait for a reply of the storage server before giving up with 503.
+    storage_timeout: Duration,
+}
+
+impl ServerState {
+    fn new(storage: StorageHandle) -> Self {
+        Self {
+            storage_server_transmitter: storage.transmitter,
+            snapshot: storage.snapshot,
+            storage_timeout: STORAGE_TIMEOUT,
+        }
+    }
 }
 
 #[tokio::main]
@@ -32,9 +73,15 @@ async fn main() -> std::io::Result<()> {
         .init()
         .unwrap();
     let discovery_enable = std::env::args().any(|each| each == "--discovery");
+    let watch_enable = std::env::args().any(|each| each == "--watch");
+    // runs without reading keys from a terminal, e.g. as a systemd service or in a container
+    let daemon = std::env::args().any(|each| each == "--daemon" || each == "--no-tty");
+    let pid_file =
+        std::env::args().find_map(|each| each.strip_prefix("--pid-file=").map(PathBuf::from));
+    let mut positional = positional_args(std::env::args().skip(1));
     let index_path =
-        std::env::args()
-            .nth(1)
+        positional
+            .next()
             .or_else(|| {
                 Some(env::var("PEA_INDEX_FILE").expect(
                     "failed to read PEA_INDEX_FILE env var and no index file path was given",
@@ -43,8 +90,8 @@ async fn main() -> std::io::Result<()> {
             .map(PathBuf::from)
             .unwrap();
     let address = Box::new(
-        std::env::args()
-            .nth(2)
+        positional
+            .next()
             .unwrap_or_else(|| SocketAddr::from((get_local_ip_address(), 8080)).to_string()),
     );
     info!("trying to run server on address: http://{address}");
@@ -59,67 +106,288 @@ async fn main() -> std::io::Result<()> {
     } else {
         None
     };
-    let tx = StorageServer::initialize(&config.index_path);
-    tokio::spawn(async move {
-        create_and_run_server(config, tx.clone())
-            .expect("server creation should not fail")
-            .await
-            .expect("server running should not fail");
-    });
+    let storage = match StorageServer::initialize(&config.index_path) {
+        Ok(storage) => storage,
+        Err(err) => {
+            error!("failed to load index {:?} due to {err}", config.index_path);
+            std::process::exit(1);
+        }
+    };
+    let mut watcher = watch_enable
+        .then(|| watch_content_roots(&storage))
+        .flatten();
+    let server = create_and_run_server(config, storage.clone())?;
+    let server_handle = server.handle();
+    let mut server_task = tokio::spawn(server);
     if let Some(server) = &register_data {
         register_server(server).expect("expect server registration to succeed");
     }
-    let input_handler = thread::spawn(|| {
-        input_listener(register_data);
-    });
-    input_handler.join().expect("input handler should not fail");
-    Ok(())
+    if let Some(pid_file) = &pid_file {
+        if let Err(err) = std::fs::write(pid_file, format!("{}\n", std::process::id())) {
+            error!("failed to write pid file {pid_file:?} due to {err}");
+        }
+    }
+    let (quit_tx, quit_rx) = tokio::sync::oneshot::channel();
+    if !daemon {
+        thread::spawn(|| {
+            input_listener(quit_tx);
+        });
+    }
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    notify_service_manager("READY=1");
+    if let Some(interval) = systemd::watchdog_interval() {
+        tokio::spawn(keep_watchdog_alive(storage.clone(), interval));
+    }
+    let shutdown_requested = shutdown_requested(quit_rx);
+    tokio::pin!(shutdown_requested);
+    let mut status = loop {
+        tokio::select! {
+            reason = &mut shutdown_requested => break match reason {
+                Ok(reason) => {
+                    info!("shutting down after {reason}");
+                    0
+                }
+                Err(err) => {
+                    error!("failed to listen for shutdown signals due to {err}");
+                    1
+                }
+            },
+            result = &mut server_task => {
+                error!("server stopped unexpectedly: {result:?}");
+                break 1;
+            }
+            _ = hangup.recv() => {
+                notify_service_manager("RELOADING=1");
+                if reload_config(&storage, ServerConfig::load()).await && watch_enable {
+                    // drop the old watcher first so roots that are still configured aren't
+                    // watched twice while the new one starts
+                    drop(watcher.take());
+                    watcher = watch_content_roots(&storage);
+                }
+                notify_service_manager("READY=1");
+            }
+        }
+    };
+    notify_service_manager("STOPPING=1");
+    // changes found by the watcher can't be stored once the index is closed
+    drop(watcher);
+    if !shutdown(server_handle, &storage, register_data).await {
+        status = 1;
+    }
+    if let Some(pid_file) = &pid_file {
+        if let Err(err) = std::fs::remove_file(pid_file) {
+            error!("failed to remove pid file {pid_file:?} due to {err}");
+        }
+    }
+    std::process::exit(status);
+}
+
+/// The index path and address, which can be given before, between or after the `--` flags.
+fn positional_args(args: impl Iterator<Item = String>) -> impl Iterator<Item = String> {
+    args.filter(|each| !each.starts_with("--"))
+}
+
+/// Watch the registered content roots, or `PEA_FILES_DIR` if there are none, for changes.
+fn watch_content_roots(storage: &StorageHandle) -> Option<RootWatcher> {
+    let mut roots: Vec<PathBuf> = storage
+        .snapshot
+        .load()
+        .roots()
+        .into_iter()
+        .map(|root| root.path)
+        .collect();
+    if roots.is_empty() {
+        roots.push(PathBuf::from(
+            env::var("PEA_FILES_DIR").expect("make sure PEA_FILES_DIR is set"),
+        ));
+    }
+    match watch_roots(&roots, storage.transmitter.clone(), WATCH_DEBOUNCE) {
+        Ok(watcher) => Some(watcher),
+        Err(err) => {
+            error!("failed to watch content root due to {err}");
+            None
+        }
+    }
+}
+
+/// Apply the reloaded configuration, keeping the current one if it couldn't be loaded or is
+/// invalid. Returns whether the configuration was reloaded.
+async fn reload_config(storage: &StorageHandle, config: Result<ServerConfig, FileErr>) -> bool {
+    info!("reloading configuration");
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => {
+            error!("failed to reload configuration due to {err}");
+            return false;
+        }
+    };
+    let state = ServerState::new(storage.clone());
+    match storage_reply(&state, |tx| Message::ReloadConfig(config, tx)).await {
+        Ok(()) => true,
+        Err(err) => {
+            error!(
+                "failed to apply reloaded configuration due to {}",
+                err.message
+            );
+            false
+        }
+    }
+}
+
+fn notify_service_manager(state: &str) {
+    if let Err(err) = systemd::notify(state) {
+        error!("failed to notify service manager of {state} due to {err}");
+    }
+}
+
+/// Tell the service manager that the server is alive twice per watchdog interval, as long as the
+/// storage server answers in time. Otherwise the service manager restarts the server once the
+/// interval passes without a keep-alive.
+async fn keep_watchdog_alive(storage: StorageHandle, interval: Duration) {
+    let state = ServerState {
+        storage_timeout: interval / 2,
+        ..ServerState::new(storage)
+    };
+    let mut ticks = tokio::time::interval(interval / 2);
+    loop {
+        ticks.tick().await;
+        if is_storage_responsive(&state).await {
+            notify_service_manager("WATCHDOG=1");
+        }
+    }
 }
 
-fn input_listener(registry_data: Option<RegistryData>) {
+async fn is_storage_responsive(state: &ServerState) -> bool {
+    match storage_reply(state, Message::Ping).await {
+        Ok(()) => true,
+        Err(err) => {
+            error!("skipping watchdog keep-alive since {}", err.message);
+            false
+        }
+    }
+}
+
+fn input_listener(quit: tokio::sync::oneshot::Sender<()>) {
     println!("Enter q to shutdown server");
     loop {
-        if crossterm::event::poll(Duration::from_millis(1000)).expect("polling should not fail") {
-            if let crossterm::event::Event::Key(event) =
-                crossterm::event::read().expect("reading key event should not fail")
+        let event = crossterm::event::poll(Duration::from_millis(1000))
+            .and_then(|ready| ready.then(crossterm::event::read).transpose());
+        match event {
+            Ok(Some(crossterm::event::Event::Key(event)))
+                if event.code == crossterm::event::KeyCode::Char('q') =>
             {
-                if event.code == crossterm::event::KeyCode::Char('q') {
-                    break;
-                }
+                break;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                info!("not listening for the q key due to {err}, use SIGINT or SIGTERM instead");
+                return;
             }
         }
     }
-    shutdown_server(registry_data);
+    let _ = quit.send(());
+}
+
+/// Wait until the server is asked to shut down by SIGINT, SIGTERM or the `q` key, returning
+/// what asked for it.
+async fn shutdown_requested(
+    quit: tokio::sync::oneshot::Receiver<()>,
+) -> std::io::Result<&'static str> {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    let quit = async {
+        // without a terminal to read keys from only signals can stop the server
+        if quit.await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT"),
+        _ = terminate.recv() => Ok("SIGTERM"),
+        _ = quit => Ok("q key"),
+    }
 }
 
-fn shutdown_server(server: Option<RegistryData>) {
-    debug!("unregistering server");
-    if let Some(server) = server {
-        unregister_server(server).expect("unregistering server should not fail");
+/// Stop accepting connections and give in-flight requests `SHUTDOWN_TIMEOUT` to finish, then
+/// close the index and unregister from discovery. Every step runs even if an earlier one failed,
+/// returns whether all of them succeeded.
+async fn shutdown(
+    server: actix_web::dev::ServerHandle,
+    storage: &StorageHandle,
+    registry_data: Option<RegistryData>,
+) -> bool {
+    let mut succeeded = true;
+    debug!("waiting for in-flight requests to finish");
+    server.stop(true).await;
+    debug!("closing index");
+    let state = ServerState::new(storage.clone());
+    if let Err(err) = storage_reply(&state, Message::ShutDown).await {
+        error!("failed to close index due to {}", err.message);
+        succeeded = false;
+    }
+    if let Some(server) = registry_data {
+        debug!("unregistering server");
+        // the registry client blocks, so it can't run on the runtime's worker threads
+        let unregistered = tokio::task::spawn_blocking(move || {
+            unregister_server(server).map_err(|err| err.to_string())
+        })
+        .await
+        .unwrap_or_else(|err| Err(err.to_string()));
+        if let Err(err) = unregistered {
+            error!("failed to unregister server due to {err}");
+            succeeded = false;
+        }
     }
-    debug!("starting shutdown");
-    std::process::exit(0);
+    info!("shut down");
+    succeeded
 }
 
 fn create_and_run_server(
     config: Config,
-    storage_server_transmitter: crossbeam_channel::Sender<Message>,
+    storage: StorageHandle,
 ) -> std::io::Result<actix_web::dev::Server> {
     info!("starting server at: {:?}", config.address.to_socket_addrs());
-    let server_state = actix_web::web::Data::new(ServerState {
-        storage_server_transmitter,
-    });
+    let server_state = actix_web::web::Data::new(ServerState::new(storage));
     let server = actix_web::HttpServer::new(move || {
         let cors = actix_cors::Cors::permissive();
         actix_web::App::new()
             .app_data(server_state.clone())
+            .configure(request_errors)
             .wrap(cors)
             .route("/", actix_web::web::get().to(index))
             .route("/files", actix_web::web::get().to(get_files))
             .route("/tags", actix_web::web::get().to(get_tags))
+            .route("/roots", actix_web::web::get().to(get_roots))
             .route("/file", actix_web::web::post().to(post_file))
+            .route(
+                "/uploads",
+                actix_web::web::method(Method::OPTIONS).to(tus_options),
+            )
+            .route("/uploads", actix_web::web::post().to(create_upload))
+            .route(
+                "/uploads/{id}",
+                actix_web::web::head().to(get_upload_offset),
+            )
+            .route("/uploads/{id}", actix_web::web::patch().to(append_upload))
+            .route(
+                "/uploads/{id}",
+                actix_web::web::delete().to(terminate_upload),
+            )
             .route("/files/{type}", actix_web::web::get().to(get_file_by_type))
             .route("/query", actix_web::web::post().to(get_files_by_tags))
+            .route("/file/{id}", actix_web::web::delete().to(delete_file))
+            .route("/file/{id}", actix_web::web::patch().to(update_file))
+            .route("/file/{id}/tags", actix_web::web::post().to(add_tags))
+            .route("/file/{id}/tags", actix_web::web::delete().to(remove_tags))
+            .route(
+                "/query/tags",
+                actix_web::web::post().to(update_tags_of_query),
+            )
+            .route("/trash", actix_web::web::get().to(get_trash))
+            .route(
+                "/trash/{id}/restore",
+                actix_web::web::post().to(restore_file),
+            )
+            .route("/trash/{id}", actix_web::web::delete().to(purge_file))
             .service(
                 actix_web::web::resource("/content/{file_name}")
                     .route(actix_web::web::get().to(get_content)),
@@ -136,6 +404,9 @@ fn create_and_run_server(
                 .show_files_listing(),
             )
     })
+    .shutdown_timeout(SHUTDOWN_TIMEOUT.as_secs())
+    // shutdown is coordinated with the storage server in `main`
+    .disable_signals()
     .bind(config.address.as_ref())?;
     Ok(server.run())
 }
@@ -156,55 +427,204 @@ async fn index(_req: actix_web::HttpRequest) -> actix_web::Result<actix_files::N
         env::var("PEA_CLIENT_CONTENT_DIR").expect("make sure PEA_CLIENT_CONTENT_DIR is set"),
     )
     .join("index.html");
-    Ok(actix_files::NamedFile::open(path)?)
+    let named_file = actix_web::web::block(move || actix_files::NamedFile::open(path))
+        .await
+        .map_err(json_error)??;
+    Ok(named_file)
 }
 
 type State = actix_web::web::Data<ServerState>;
 
-async fn get_files(state: State) -> actix_web::HttpResponse {
+async fn get_files(
+    params: actix_web::web::Query<ListParams>,
+    state: State,
+) -> actix_web::Result<actix_web::HttpResponse> {
     info!("get file request received");
-    let storage_tx = &state.storage_server_transmitter.clone();
-    let (tx, rx) = crossbeam_channel::bounded(1);
-    if storage_tx.send(Message::GetAllFiles(tx)).is_err() {
-        error!("failed to send get all files to storage server");
-        return actix_web::HttpResponse::InternalServerError().finish();
-    }
-    match rx.recv() {
-        Ok(files) => {
-            let body = serde_json::to_string(&files).unwrap();
-            actix_web::HttpResponse::Ok()
-                .content_type("application/json")
-                .body(body)
+    let files = state.snapshot.load().files();
+    Ok(list_response(files, &params, FileData::from))
+}
+
+async fn get_roots(state: State) -> actix_web::Result<actix_web::HttpResponse> {
+    info!("get roots request received");
+    let roots = state.snapshot.load().roots();
+    Ok(actix_web::HttpResponse::Ok().json(roots))
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Mtime,
+    Type,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Query parameters of the routes listing files.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+struct ListParams {
+    /// Maximum number of files in the response, all files if not set.
+    limit: Option<usize>,
+    /// Opaque cursor from the `X-Next-Cursor` header of the previous page.
+    cursor: Option<String>,
+    #[serde(default)]
+    sort: SortKey,
+    #[serde(default)]
+    order: SortOrder,
+    /// Comma separated list of the fields to include in each file, unknown fields are ignored.
+    fields: Option<String>,
+    /// Only list the files in the content root with the name.
+    root: Option<String>,
+}
+
+/// Position after the last file of a page. It holds the sort values of that file rather than
+/// an offset, so pages don't skip or repeat files when files are added or removed in between.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct Cursor {
+    sort: SortKey,
+    order: SortOrder,
+    id: u64,
+    name: String,
+    ty: String,
+    size: u64,
+    modified: Option<u64>,
+}
+
+impl Cursor {
+    fn after(file: &FileMetadata, sort: SortKey, order: SortOrder) -> Self {
+        Self {
+            sort,
+            order,
+            id: file.id,
+            name: file.name.clone(),
+            ty: file.ty.clone(),
+            size: file.size,
+            modified: file.modified,
         }
-        Err(_) => {
-            error!("failed to receive files from storage server");
-            actix_web::HttpResponse::InternalServerError().finish()
+    }
+
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("cursor serialization can't fail");
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(json)
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let json = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    /// The cursor as a file that compares like the last file of the previous page.
+    fn as_file(&self) -> FileMetadata {
+        FileMetadata {
+            id: self.id,
+            name: self.name.clone(),
+            ty: self.ty.clone(),
+            size: self.size,
+            modified: self.modified,
+            ..Default::default()
         }
     }
 }
 
-async fn get_tags(state: State) -> actix_web::HttpResponse {
-    info!("get tags request received");
-    let storage_tx = &state.storage_server_transmitter.clone();
-    let (tx, rx) = crossbeam_channel::bounded(1);
-    if storage_tx.send(Message::GetAllTags(tx)).is_err() {
-        error!("failed to send get all tags to storage server");
-        return actix_web::HttpResponse::InternalServerError().finish();
-    }
-    match rx.recv() {
-        Ok(tags) => {
-            let body = serde_json::to_string(&tags).unwrap();
-            actix_web::HttpResponse::Ok()
-                .content_type("application/json")
-                .body(body)
+/// Respond with a page of the sorted files. The total number of files is sent in the
+/// `X-Total-Count` header and the cursor of the next page, if any, in `X-Next-Cursor`.
+fn list_response<T: serde::Serialize>(
+    mut files: Vec<FileMetadata>,
+    params: &ListParams,
+    into: impl Fn(FileMetadata) -> T,
+) -> actix_web::HttpResponse {
+    let cursor = match params.cursor.as_deref().map(Cursor::decode) {
+        None => None,
+        Some(Some(cursor)) if cursor.sort == params.sort && cursor.order == params.order => {
+            Some(cursor)
         }
-        Err(_) => {
-            error!("failed to receive files from storage server");
-            actix_web::HttpResponse::InternalServerError().finish()
+        Some(_) => {
+            let status = actix_web::http::StatusCode::BAD_REQUEST;
+            return ErrorData::new(status, "invalid cursor").response();
         }
+    };
+    if let Some(root) = &params.root {
+        files.retain(|file| &file.root == root);
+    }
+    sort_files(&mut files, params.sort, params.order);
+    let total = files.len();
+    let start = match cursor {
+        Some(cursor) => {
+            let last = cursor.as_file();
+            files.partition_point(|file| {
+                compare_files(file, &last, params.sort, params.order) != Ordering::Greater
+            })
+        }
+        None => 0,
+    };
+    let end = match params.limit {
+        Some(limit) => start.saturating_add(limit).min(total),
+        None => total,
+    };
+    let next = (params.limit.unwrap_or_default() > 0 && start < end && end < total)
+        .then(|| Cursor::after(&files[end - 1], params.sort, params.order).encode());
+    let page: Vec<T> = files
+        .into_iter()
+        .skip(start)
+        .take(end - start)
+        .map(into)
+        .collect();
+    let mut page = match serde_json::to_value(page) {
+        Ok(page) => page,
+        Err(err) => {
+            error!("failed to serialize files due to {err:?}");
+            return actix_web::HttpResponse::InternalServerError().finish();
+        }
+    };
+    if let (Some(fields), Some(page)) = (&params.fields, page.as_array_mut()) {
+        let fields: Vec<&str> = fields.split(',').map(str::trim).collect();
+        for file in page.iter_mut().filter_map(|each| each.as_object_mut()) {
+            file.retain(|key, _| fields.contains(&key.as_str()));
+        }
+    }
+    let mut response = actix_web::HttpResponse::Ok();
+    response.insert_header(("X-Total-Count", total.to_string()));
+    if let Some(next) = next {
+        response.insert_header(("X-Next-Cursor", next));
+    }
+    response.json(page)
+}
+
+fn sort_files(files: &mut [FileMetadata], key: SortKey, order: SortOrder) {
+    files.sort_by(|a, b| compare_files(a, b, key, order));
+}
+
+/// Ties are broken by the id so pages don't overlap.
+fn compare_files(a: &FileMetadata, b: &FileMetadata, key: SortKey, order: SortOrder) -> Ordering {
+    let ordering = match key {
+        SortKey::Name => a.name.cmp(&b.name),
+        SortKey::Size => a.size.cmp(&b.size),
+        SortKey::Mtime => a.modified.cmp(&b.modified),
+        SortKey::Type => a.ty.cmp(&b.ty).then_with(|| a.name.cmp(&b.name)),
+    }
+    .then_with(|| a.id.cmp(&b.id));
+    match order {
+        SortOrder::Asc => ordering,
+        SortOrder::Desc => ordering.reverse(),
     }
 }
 
+async fn get_tags(state: State) -> actix_web::Result<actix_web::HttpResponse> {
+    info!("get tags request received");
+    let tags = state.snapshot.load().tags();
+    Ok(actix_web::HttpResponse::Ok().json(tags))
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
 struct TagQueryData {
     // if ty is "" then it is ignored
@@ -217,220 +637,1031 @@ struct TagQuery {
     data: TagQueryData,
 }
 
+/// A query in the boolean query language, see `query::parse`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct ExpressionQuery {
+    query: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum QueryRequest {
+    Tags(TagQuery),
+    Expression(ExpressionQuery),
+}
+
+/// Error data of an invalid query, with where it went wrong.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct QueryError {
+    #[serde(flatten)]
+    error: ErrorData,
+    position: usize,
+    token: String,
+}
+
 async fn get_files_by_tags(
-    query: actix_web::web::Json<TagQuery>,
+    query: actix_web::web::Json<QueryRequest>,
+    params: actix_web::web::Query<ListParams>,
     state: State,
-) -> actix_web::HttpResponse {
-    let data = &query.data;
-    info!("get files by request received with query: {:?}", &data);
-    let storage_tx = &state.storage_server_transmitter.clone();
-    let (tx, rx) = crossbeam_channel::bounded(1);
-    if storage_tx
-        .send(Message::GetFilesOfTags(data.tags.clone(), tx))
-        .is_err()
-    {
-        error!("failed to send get files of tags to storage server");
-        return actix_web::HttpResponse::InternalServerError().finish();
-    }
-    match rx.recv() {
-        Ok(files) => {
-            let files = if data.ty.is_empty() {
-                files
-            } else {
-                files
-                    .into_iter()
-                    .filter(|file| file.ty == data.ty)
-                    .collect()
-            };
-            let file_data: Vec<FileData> = files.into_iter().map(|f| f.into()).collect();
-            let body = serde_json::to_string(&file_data).unwrap();
-            actix_web::HttpResponse::Ok()
-                .content_type("application/json")
-                .body(body)
-        }
-        Err(_) => {
-            error!("failed to receive files from storage server");
-            actix_web::HttpResponse::InternalServerError().finish()
+) -> actix_web::Result<actix_web::HttpResponse> {
+    info!("get files by request received with query: {:?}", &query);
+    let (files, ty) = match query.into_inner() {
+        QueryRequest::Tags(query) => {
+            let files = state.snapshot.load().files_of_tags(&query.data.tags);
+            (files, query.data.ty)
         }
-    }
+        QueryRequest::Expression(query) => match parse(&query.query) {
+            Ok(query) => {
+                let files = state.snapshot.load().files_of_query(&query);
+                (files, String::new())
+            }
+            Err(err) => {
+                error!("failed to parse query: {}", err);
+                let status = actix_web::http::StatusCode::BAD_REQUEST;
+                return Ok(actix_web::HttpResponse::BadRequest().json(QueryError {
+                    error: ErrorData::new(status, &err),
+                    position: err.position,
+                    token: err.token,
+                }));
+            }
+        },
+    };
+    let files = if ty.is_empty() {
+        files
+    } else {
+        files.into_iter().filter(|file| file.ty == ty).collect()
+    };
+    Ok(list_response(files, &params, FileData::from))
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+struct UploadParams {
+    /// Name of the content root to upload to, the received files dir if not set.
+    root: Option<String>,
+    #[serde(default)]
+    on_conflict: ConflictPolicy,
+}
+
+/// Outcome of uploading one part of a multipart upload.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct UploadResult {
+    file_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file: Option<FileData>,
+    /// Error the part would have failed with on its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorData>,
 }
 
+/// Longest value accepted for the form fields of a multipart upload.
+const MAX_FORM_FIELD_SIZE: usize = 64 * 1024;
+
+/// Store every file part of the multipart body and reply with the outcome of each, with 207 if
+/// some of them failed. Parts without a file name are form fields applying to the files after
+/// them: `tags` holds comma separated tags and `directory` the `/` separated folder in the root to
+/// upload to.
 async fn post_file(
     mut payload: actix_multipart::Multipart,
+    params: actix_web::web::Query<UploadParams>,
     state: State,
 ) -> actix_web::Result<actix_web::HttpResponse> {
+    let mut options = UploadOptions {
+        root: params.root.clone(),
+        on_conflict: params.on_conflict,
+        ..Default::default()
+    };
+    let mut results = Vec::new();
     while let Some(item) = payload.next().await {
         info!("post file request received");
-        let mut field = item?;
-        let content_disposition = field.content_disposition();
-        match content_disposition.get_filename() {
+        let mut field = item.map_err(json_error)?;
+        let file_name = match field.content_disposition().get_filename() {
+            Some(file_name) => file_name.to_string(),
             None => {
-                return Ok(actix_web::HttpResponse::Forbidden().into());
-            }
-            Some(file_name) => {
-                let mut content: Vec<u8> = Vec::new();
-                let file_name = file_name.to_string();
-                while let Some(chunk) = field.next().await {
-                    for each in chunk? {
-                        content.push(each);
-                    }
-                }
-                info!("creating {} with size {} bytes", file_name, content.len());
-                let storage_tx = &state.storage_server_transmitter.clone();
-                let (tx, rx) = crossbeam_channel::bounded(1);
-                if storage_tx
-                    .send(Message::CreateFile(file_name, content, tx))
-                    .is_err()
-                {
-                    error!("failed to send create file to storage server");
-                    return Ok(actix_web::HttpResponse::InternalServerError().into());
+                let name = field.name().to_string();
+                let value = read_form_field(&mut field).await?;
+                match name.as_str() {
+                    "tags" => options.tags.extend(split_tags(&value)),
+                    "directory" => options.directory = Some(value),
+                    _ => debug!("ignoring form field {name}"),
                 }
-                match rx.recv() {
-                    Ok(result) => match result {
-                        Ok(_) => {
-                            info!("file created successfully");
-                        }
-                        Err(e) => {
-                            error!("failed to create file: {}", e);
-                            return Ok(actix_web::HttpResponse::InternalServerError().into());
-                        }
-                    },
-                    Err(_) => {
-                        error!("failed to receive create file response from storage server");
-                        return Ok(actix_web::HttpResponse::InternalServerError().into());
-                    }
+                continue;
+            }
+        };
+        let result = match upload_part(&mut field, file_name.clone(), &options, &state).await {
+            Ok(file) => {
+                info!("file stored as {:?}", file.path);
+                UploadResult {
+                    file_name,
+                    file: Some(FileData::from(file)),
+                    error: None,
                 }
             }
+            Err(err) => UploadResult {
+                file_name,
+                file: None,
+                error: Some(err),
+            },
+        };
+        results.push(result);
+    }
+    if results.iter().all(|each| each.error.is_none()) {
+        Ok(actix_web::HttpResponse::Ok().json(results))
+    } else {
+        Ok(actix_web::HttpResponse::MultiStatus().json(results))
+    }
+}
+
+fn split_tags(tags: &str) -> impl Iterator<Item = String> + '_ {
+    tags.split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+}
+
+async fn read_form_field(field: &mut actix_multipart::Field) -> actix_web::Result<String> {
+    let mut value = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(json_error)?;
+        if value.len() + chunk.len() > MAX_FORM_FIELD_SIZE {
+            let status = actix_web::http::StatusCode::PAYLOAD_TOO_LARGE;
+            let message = format!("form fields can't be larger than {MAX_FORM_FIELD_SIZE} bytes");
+            return Err(error_response(ErrorData::new(status, message)));
         }
+        value.extend_from_slice(&chunk);
     }
-    Ok(actix_web::HttpResponse::Ok().into())
+    String::from_utf8(value).map_err(|_| {
+        let status = actix_web::http::StatusCode::BAD_REQUEST;
+        error_response(ErrorData::new(status, "form fields must be valid utf-8"))
+    })
 }
 
-async fn get_file_by_type(
-    path: actix_web::web::Path<String>,
-    state: State,
-) -> actix_web::HttpResponse {
-    let file_type = path.into_inner();
-    info!("get file by type request received: {}", file_type);
-    let storage_tx = &state.storage_server_transmitter.clone();
-    let (tx, rx) = crossbeam_channel::bounded(1);
-    if storage_tx
-        .send(Message::GetFilesOfType(file_type, tx))
-        .is_err()
-    {
-        error!("failed to send get files of tags to storage server");
-        return actix_web::HttpResponse::InternalServerError().finish();
+/// Store the file of one part, leaving nothing behind if it fails.
+async fn upload_part(
+    field: &mut actix_multipart::Field,
+    file_name: String,
+    options: &UploadOptions,
+    state: &State,
+) -> Result<FileMetadata, ErrorData> {
+    let options = options.clone();
+    let upload = storage_reply(state, |tx| Message::StartUpload(file_name, options, tx)).await?;
+    // removes the temporary file if streaming fails or the client disconnects
+    let mut temp_file = TempFile(Some(upload.temp_path.clone()));
+    let (hash, size) = match stream_upload(field, &upload).await {
+        Ok(received) => received,
+        Err(err) => return Err(ErrorData::new(err.as_response_error().status_code(), err)),
+    };
+    info!("creating {} with size {} bytes", upload.file_name, size);
+    let result = storage_reply(state, |tx| Message::FinishUpload(upload, hash, size, tx)).await;
+    // the storage server moved the temporary file into place or removed it
+    if result.is_ok() {
+        temp_file.0 = None;
     }
-    match rx.recv() {
-        Ok(files) => {
-            let files: Vec<FileData> = files.into_iter().map(|each| each.into()).collect();
-            let body = serde_json::to_string(&files).unwrap();
-            actix_web::HttpResponse::Ok()
-                .content_type("application/json")
-                .body(body)
+    result
+}
+
+/// Temporary file of an upload, removed when dropped unless its path is taken.
+struct TempFile(Option<PathBuf>);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take() {
+            match std::fs::remove_file(&path) {
+                Ok(()) => debug!("removed {path:?} of an unfinished upload"),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => error!("failed to remove {path:?} due to {err:?}"),
+            }
         }
-        Err(_) => {
-            error!("failed to receive files from storage server");
-            actix_web::HttpResponse::InternalServerError().finish()
+    }
+}
+
+/// Write the content of the field to the temporary file of the upload as it arrives, hashing it
+/// on the way so the file doesn't have to be read again to index it.
+async fn stream_upload(
+    field: &mut actix_multipart::Field,
+    upload: &Upload,
+) -> actix_web::Result<(blake3::Hash, u64)> {
+    let mut file = tokio::fs::File::create(&upload.temp_path).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut size: u64 = 0;
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        if let Some(max_size) = upload.max_size.filter(|max_size| size > *max_size) {
+            error!("{} is larger than {max_size} bytes", upload.file_name);
+            return Err(actix_web::error::ErrorPayloadTooLarge(format!(
+                "uploads can't be larger than {max_size} bytes"
+            )));
         }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
     }
+    file.sync_all().await?;
+    Ok((hasher.finalize(), size))
 }
 
-async fn get_content(
-    req: actix_web::HttpRequest,
-    state: State,
-) -> actix_web::Result<actix_files::NamedFile> {
-    let file_name: String = req.match_info().query("file_name").parse().unwrap();
-    let file_id = file_name.trim().parse::<u64>().unwrap();
-    info!("get file request received: {}", file_name);
-    let storage_tx = &state.storage_server_transmitter.clone();
-    let (tx, rx) = crossbeam_channel::bounded(1);
-    if storage_tx.send(Message::GetFilePath(file_id, tx)).is_err() {
-        error!("failed to send get files of tags to storage server");
-        return Err(actix_web::error::ErrorBadRequest(
-            "failed to send get files of tags to storage server",
-        ));
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+
+/// Describes the supported tus protocol, which the `/uploads` endpoints implement for uploads that
+/// can be resumed after the connection drops.
+async fn tus_options() -> actix_web::HttpResponse {
+    tus_response(
+        actix_web::HttpResponse::NoContent()
+            .insert_header(("Tus-Version", TUS_VERSION))
+            .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+            .finish(),
+    )
+}
+
+/// Start a resumable upload. The name of the file is given in the `filename` key of the
+/// `Upload-Metadata` header, and the optional `root`, `directory`, `tags` and `on_conflict` keys
+/// mean the same as for multipart uploads.
+async fn create_upload(req: actix_web::HttpRequest, state: State) -> actix_web::HttpResponse {
+    info!("create upload request received");
+    if let Some(response) = check_tus_version(&req) {
+        return response;
     }
-    match rx.recv() {
-        Ok(result) => match result {
-            Ok(path) => Ok(actix_files::NamedFile::open(path)?),
-            Err(e) => {
-                error!("failed to get file path: {}", e);
-                Err(actix_web::error::ErrorBadRequest("failed to get file path"))
-            }
+    let length = match header_value::<u64>(&req, "Upload-Length") {
+        Some(length) => length,
+        None => return tus_response(bad_request("expected a valid Upload-Length header")),
+    };
+    let metadata = match req
+        .headers()
+        .get("Upload-Metadata")
+        .and_then(|value| value.to_str().ok())
+        .map(parse_upload_metadata)
+    {
+        Some(Some(metadata)) => metadata,
+        Some(None) => return tus_response(bad_request("invalid Upload-Metadata header")),
+        None => HashMap::new(),
+    };
+    let file_name = match metadata.get("filename") {
+        Some(file_name) => file_name.clone(),
+        None => return tus_response(bad_request("expected a filename in Upload-Metadata")),
+    };
+    let on_conflict = match metadata.get("on_conflict") {
+        Some(policy) => match serde_json::from_value(serde_json::Value::String(policy.clone())) {
+            Ok(policy) => policy,
+            Err(_) => return tus_response(bad_request("invalid on_conflict in Upload-Metadata")),
         },
-        Err(_) => {
-            error!("failed find a file with the given id");
-            Err(actix_web::error::ErrorBadRequest(
-                "failed find a file with the given id",
-            ))
-        }
-    }
+        None => ConflictPolicy::default(),
+    };
+    let options = UploadOptions {
+        root: metadata.get("root").cloned(),
+        directory: metadata.get("directory").cloned(),
+        tags: metadata
+            .get("tags")
+            .map(|tags| split_tags(tags).collect())
+            .unwrap_or_default(),
+        on_conflict,
+    };
+    let result = storage_request(&state, |tx| {
+        Message::CreatePendingUpload(file_name, length, options, tx)
+    })
+    .await;
+    let pending = match result {
+        Ok(pending) => pending,
+        Err(err) => return tus_response(err.error_response()),
+    };
+    tus_response(
+        actix_web::HttpResponse::Created()
+            .insert_header((header::LOCATION, format!("/uploads/{}", pending.id)))
+            .insert_header(upload_expires(&pending))
+            .finish(),
+    )
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
-struct FileUpload {
-    name: String,
+fn upload_expires(pending: &PendingUpload) -> (&'static str, String) {
+    let expires = UNIX_EPOCH + Duration::from_secs(pending.expires_at);
+    (
+        "Upload-Expires",
+        header::HttpDate::from(expires).to_string(),
+    )
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
-struct FileData {
-    name: String,
+/// Lets other requests append to the upload again once the request holding it is done, also when
+/// the client disconnects in the middle of it. It is created before the upload is locked, since the
+/// request may be dropped while waiting for the lock.
+struct UploadLock {
     id: String,
-    ty: String,
-    tags: Vec<String>,
+    /// Identifies the request holding the lock.
+    token: String,
+    transmitter: crossbeam_channel::Sender<Message>,
 }
 
-impl From<FileMetadata> for FileData {
-    fn from(value: FileMetadata) -> Self {
-        let tags = match value.tags {
-            Some(tags) => tags,
-            None => Vec::new(),
-        };
-        Self {
-            name: value.name,
-            id: value.id.to_string(),
-            ty: value.ty,
-            tags,
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        let id = std::mem::take(&mut self.id);
+        let token = std::mem::take(&mut self.token);
+        if self
+            .transmitter
+            .send(Message::UnlockPendingUpload(id, token))
+            .is_err()
+        {
+            error!("failed to unlock upload since the storage server stopped");
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        env,
-        fs::File,
-        io::{Read, Write},
-        path::PathBuf,
+async fn get_upload_offset(
+    req: actix_web::HttpRequest,
+    path: actix_web::web::Path<String>,
+    state: State,
+) -> actix_web::HttpResponse {
+    if let Some(response) = check_tus_version(&req) {
+        return response;
+    }
+    let id = path.into_inner();
+    let pending = match storage_request(&state, |tx| Message::GetPendingUpload(id, tx)).await {
+        Ok(pending) => pending,
+        Err(err) => return tus_response(err.error_response()),
     };
+    match upload_offset(&pending).await {
+        Ok(offset) => tus_response(
+            actix_web::HttpResponse::Ok()
+                .insert_header(("Upload-Offset", offset))
+                .insert_header(("Upload-Length", pending.length))
+                .insert_header((header::CACHE_CONTROL, "no-store"))
+                .finish(),
+        ),
+        Err(err) => tus_response(err.error_response()),
+    }
+}
 
-    use crate::{
-        create_and_run_server, get_file_by_type, get_files_by_tags, get_tags, index, post_file,
-        Config, FileData, ServerState, TagQuery, TagQueryData,
+/// Number of bytes received for the upload, read off the async workers since it stats the
+/// temporary file.
+async fn upload_offset(pending: &PendingUpload) -> actix_web::Result<u64> {
+    let pending = pending.clone();
+    actix_web::web::block(move || pending.offset())
+        .await
+        .map_err(json_error)
+}
+
+/// Append the body to the upload at the offset given in the `Upload-Offset` header, which has
+/// to match the content received so far. The file is indexed once all of it is received.
+async fn append_upload(
+    req: actix_web::HttpRequest,
+    path: actix_web::web::Path<String>,
+    mut payload: actix_web::web::Payload,
+    state: State,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    if let Some(response) = check_tus_version(&req) {
+        return Ok(response);
+    }
+    let id = path.into_inner();
+    info!("append upload request received for {id}");
+    let content_type = req.headers().get(header::CONTENT_TYPE);
+    if content_type.is_none_or(|value| value != "application/offset+octet-stream") {
+        let status = actix_web::http::StatusCode::UNSUPPORTED_MEDIA_TYPE;
+        let message = "expected application/offset+octet-stream content";
+        return Ok(tus_response(ErrorData::new(status, message).response()));
+    }
+    let mut offset = match header_value::<u64>(&req, "Upload-Offset") {
+        Some(offset) => offset,
+        None => {
+            return Ok(tus_response(bad_request(
+                "expected a valid Upload-Offset header",
+            )))
+        }
     };
-    use actix_web::{
-        http::header::{self, ContentType, HeaderMap},
-        test,
-        web::{self, Bytes},
-        App,
+    // created before locking so the upload is unlocked however the request ends, even if it
+    // stops waiting for the lock or the client disconnects
+    let lock = UploadLock {
+        id: id.clone(),
+        token: uuid::Uuid::new_v4().to_string(),
+        transmitter: state.storage_server_transmitter.clone(),
     };
-    use pea_server::utils::storage::{clean_up_dir, FileIndex, FileMetadata, StorageServer};
-    use std::sync::Once;
-
-    static INIT: Once = Once::new();
-    const TEST_INDEX: &str = "test_index.json";
+    let token = lock.token.clone();
+    let pending = match storage_request(&state, |tx| {
+        Message::LockPendingUpload(id.clone(), token, tx)
+    })
+    .await
+    {
+        Ok(pending) => pending,
+        Err(err) => return Ok(tus_response(err.error_response())),
+    };
+    let current = upload_offset(&pending).await?;
+    if offset != current {
+        error!("upload {id} is at {current} but received {offset}");
+        let status = actix_web::http::StatusCode::CONFLICT;
+        let message = format!("upload is at offset {current}");
+        return Ok(tus_response(ErrorData::new(status, message).response()));
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(&pending.upload.temp_path)
+        .await?;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if offset + chunk.len() as u64 > pending.length {
+            file.sync_data().await?;
+            return Ok(tus_response(bad_request(
+                "received more content than the Upload-Length",
+            )));
+        }
+        file.write_all(&chunk).await?;
+        offset += chunk.len() as u64;
+    }
+    file.sync_data().await?;
+    if offset == pending.length {
+        info!("received all {offset} bytes of upload {id}");
+        if let Err(err) = storage_request(&state, |tx| Message::FinishPendingUpload(id, tx)).await {
+            return Ok(tus_response(err.error_response()));
+        }
+    }
+    Ok(tus_response(
+        actix_web::HttpResponse::NoContent()
+            .insert_header(("Upload-Offset", offset))
+            .insert_header(upload_expires(&pending))
+            .finish(),
+    ))
+}
 
-    #[tokio::test]
-    async fn can_start_server() {
-        initialize();
-        tokio::spawn(async move {
+async fn terminate_upload(
+    req: actix_web::HttpRequest,
+    path: actix_web::web::Path<String>,
+    state: State,
+) -> actix_web::HttpResponse {
+    if let Some(response) = check_tus_version(&req) {
+        return response;
+    }
+    let id = path.into_inner();
+    info!("terminate upload request received for {id}");
+    match storage_request(&state, |tx| Message::TerminatePendingUpload(id, tx)).await {
+        Ok(_) => tus_response(actix_web::HttpResponse::NoContent().finish()),
+        Err(err) => tus_response(err.error_response()),
+    }
+}
+
+fn tus_response(mut response: actix_web::HttpResponse) -> actix_web::HttpResponse {
+    response.headers_mut().insert(
+        HeaderName::from_static("tus-resumable"),
+        HeaderValue::from_static(TUS_VERSION),
+    );
+    response
+}
+
+/// Requests to the resumable upload endpoints have to use the supported tus version.
+fn check_tus_version(req: &actix_web::HttpRequest) -> Option<actix_web::HttpResponse> {
+    let version = req.headers().get("Tus-Resumable");
+    if version.is_some_and(|version| version == TUS_VERSION) {
+        return None;
+    }
+    let status = actix_web::http::StatusCode::PRECONDITION_FAILED;
+    let message = format!("expected Tus-Resumable {TUS_VERSION}");
+    Some(tus_response(
+        actix_web::HttpResponse::PreconditionFailed()
+            .insert_header(("Tus-Version", TUS_VERSION))
+            .json(ErrorData::new(status, message)),
+    ))
+}
+
+fn header_value<T: std::str::FromStr>(req: &actix_web::HttpRequest, name: &str) -> Option<T> {
+    req.headers().get(name)?.to_str().ok()?.parse().ok()
+}
+
+fn bad_request(message: &str) -> actix_web::HttpResponse {
+    ErrorData::new(actix_web::http::StatusCode::BAD_REQUEST, message).response()
+}
+
+/// Parse the comma separated key value pairs of the `Upload-Metadata` header, whose values are
+/// base64 encoded and may be left out.
+fn parse_upload_metadata(header: &str) -> Option<HashMap<String, String>> {
+    let mut metadata = HashMap::new();
+    for pair in header
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+        let value = base64::engine::general_purpose::STANDARD
+            .decode(value.trim())
+            .ok()?;
+        metadata.insert(key.to_string(), String::from_utf8(value).ok()?);
+    }
+    Some(metadata)
+}
+
+/// Send the request to the storage server and wait for its reply without blocking the worker.
+/// The error holds the response to send back, which is 503 if the storage server didn't reply in
+/// time. A request that timed out still runs to completion, only its reply is dropped.
+async fn storage_request<T>(
+    state: &ServerState,
+    message: impl FnOnce(ReplyTransmitter<T>) -> Message,
+) -> actix_web::Result<T> {
+    storage_reply(state, message).await.map_err(error_response)
+}
+
+fn error_response(error: ErrorData) -> actix_web::Error {
+    let response = error.response();
+    actix_web::error::InternalError::from_response(error.message, response).into()
+}
+
+/// Respond to the error, e.g. of a malformed request, with error data like to the errors of the
+/// storage server.
+fn json_error(err: impl Into<actix_web::Error>) -> actix_web::Error {
+    let err = err.into();
+    error_response(ErrorData::new(err.as_response_error().status_code(), err))
+}
+
+/// Answer requests that can't be extracted, e.g. with a malformed body or query, with error data.
+fn request_errors(config: &mut actix_web::web::ServiceConfig) {
+    config
+        .app_data(actix_web::web::JsonConfig::default().error_handler(|err, _| json_error(err)))
+        .app_data(actix_web::web::QueryConfig::default().error_handler(|err, _| json_error(err)))
+        .app_data(actix_web::web::PathConfig::default().error_handler(|err, _| json_error(err)));
+}
+
+/// Same as `storage_request` but keeps the error data, e.g. to report it among other results.
+async fn storage_reply<T>(
+    state: &ServerState,
+    message: impl FnOnce(ReplyTransmitter<T>) -> Message,
+) -> Result<T, ErrorData> {
+    let unavailable = |message: &str| {
+        error!("{message}");
+        ErrorData::new(actix_web::http::StatusCode::SERVICE_UNAVAILABLE, message)
+    };
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    if state.storage_server_transmitter.send(message(tx)).is_err() {
+        return Err(unavailable("failed to send request to storage server"));
+    }
+    match tokio::time::timeout(state.storage_timeout, rx).await {
+        Ok(Ok(Ok(result))) => Ok(result),
+        Ok(Ok(Err(err))) => {
+            error!("storage request failed due to {err}");
+            Err(ErrorData::of(&err, &state.snapshot.load().roots()))
+        }
+        Ok(Err(_)) => Err(unavailable("storage server dropped the request")),
+        Err(_) => Err(unavailable("storage server didn't reply in time")),
+    }
+}
+
+async fn get_file_by_type(
+    path: actix_web::web::Path<String>,
+    params: actix_web::web::Query<ListParams>,
+    state: State,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    let file_type = path.into_inner();
+    info!("get file by type request received: {}", file_type);
+    let files = state.snapshot.load().files_of_type(&file_type);
+    Ok(list_response(files, &params, FileData::from))
+}
+
+/// Cache lifetime of content served under its content derived id, which never changes.
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Serve the content of the file, supporting range requests for seeking. Since ids are derived
+/// from the content, the content hash is used as a strong ETag and the response can be cached
+/// forever, unless the file changed on disk since it was indexed.
+async fn get_content(
+    req: actix_web::HttpRequest,
+    state: State,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    let file_name = req.match_info().query("file_name");
+    info!("get file request received: {}", file_name);
+    let file_id = file_name.trim().parse::<u64>().map_err(|_| {
+        let status = actix_web::http::StatusCode::BAD_REQUEST;
+        error_response(ErrorData::new(status, "expected a numeric file id"))
+    })?;
+    let snapshot = state.snapshot.load();
+    let file = snapshot.get_file(file_id).map_err(|err| {
+        error!("failed to get file {file_id} due to {err}");
+        error_response(ErrorData::of(&err, &snapshot.roots()))
+    })?;
+    let path = file.path.clone();
+    let named_file = actix_web::web::block(move || actix_files::NamedFile::open(path))
+        .await
+        .map_err(json_error)??;
+    let etag = match content_etag(&file, named_file.metadata()) {
+        Some(etag) => etag,
+        None => {
+            debug!("{:?} changed since it was indexed", file.path);
+            let mut response = named_file.into_response(&req);
+            response
+                .headers_mut()
+                .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+            return Ok(response);
+        }
+    };
+    let not_modified = match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(items)) => items.iter().any(|item| item.weak_eq(&etag)),
+        None => false,
+    };
+    let mut response = if not_modified {
+        actix_web::HttpResponse::NotModified().finish()
+    } else {
+        // If-Modified-Since and ranges are still handled by the named file
+        named_file.use_etag(false).into_response(&req)
+    };
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&etag.to_string()) {
+        headers.insert(header::ETAG, value);
+    }
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(IMMUTABLE_CACHE_CONTROL),
+    );
+    Ok(response)
+}
+
+/// Strong ETag of the indexed content, if the file on disk still has the indexed size and
+/// modification time, so the hash still describes what's served.
+fn content_etag(file: &FileMetadata, metadata: &std::fs::Metadata) -> Option<header::EntityTag> {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_secs());
+    let is_fresh = !file.hash.is_empty()
+        && file.link.is_none()
+        && file.size == metadata.len()
+        && file.modified.is_some()
+        && file.modified == modified;
+    is_fresh.then(|| header::EntityTag::new_strong(file.hash.clone()))
+}
+
+async fn delete_file(
+    path: actix_web::web::Path<u64>,
+    state: State,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    let id = path.into_inner();
+    info!("delete file request received: {}", id);
+    let entry = storage_request(&state, |tx| Message::DeleteFile(id, tx)).await?;
+    let roots = state.snapshot.load().roots();
+    Ok(actix_web::HttpResponse::Ok().json(TrashData::new(entry, &roots)))
+}
+
+async fn update_file(
+    path: actix_web::web::Path<u64>,
+    update: actix_web::web::Json<FileUpdate>,
+    state: State,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    let id = path.into_inner();
+    info!("update file request received: {} {:?}", id, &update);
+    let update = update.into_inner();
+    let file = storage_request(&state, |tx| Message::UpdateFile(id, update, tx)).await?;
+    Ok(actix_web::HttpResponse::Ok().json(FileData::from(file)))
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct TagList {
+    tags: Vec<String>,
+}
+
+async fn add_tags(
+    path: actix_web::web::Path<u64>,
+    body: actix_web::web::Json<TagList>,
+    state: State,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    let update = TagUpdate {
+        add: body.into_inner().tags,
+        remove: Vec::new(),
+    };
+    update_tags(path.into_inner(), update, state).await
+}
+
+async fn remove_tags(
+    path: actix_web::web::Path<u64>,
+    body: actix_web::web::Json<TagList>,
+    state: State,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    let update = TagUpdate {
+        add: Vec::new(),
+        remove: body.into_inner().tags,
+    };
+    update_tags(path.into_inner(), update, state).await
+}
+
+async fn update_tags(
+    id: u64,
+    update: TagUpdate,
+    state: State,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    info!("update tags request received: {} {:?}", id, &update);
+    let file = storage_request(&state, |tx| Message::UpdateTags(id, update, tx)).await?;
+    Ok(actix_web::HttpResponse::Ok().json(FileData::from(file)))
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct TagQueryUpdate {
+    data: TagQueryData,
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+async fn update_tags_of_query(
+    query: actix_web::web::Json<TagQueryUpdate>,
+    state: State,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    let query = query.into_inner();
+    info!("update tags of query request received: {:?}", &query);
+    let update = TagUpdate {
+        add: query.add,
+        remove: query.remove,
+    };
+    let files = storage_request(&state, |tx| {
+        Message::UpdateTagsOfFiles(query.data.tags, query.data.ty, update, tx)
+    })
+    .await?;
+    let files: Vec<FileData> = files.into_iter().map(|each| each.into()).collect();
+    Ok(actix_web::HttpResponse::Ok().json(files))
+}
+
+async fn get_trash(state: State) -> actix_web::Result<actix_web::HttpResponse> {
+    info!("get trash request received");
+    let snapshot = state.snapshot.load();
+    let roots = snapshot.roots();
+    let entries: Vec<TrashData> = snapshot
+        .trash()
+        .into_iter()
+        .map(|each| TrashData::new(each, &roots))
+        .collect();
+    Ok(actix_web::HttpResponse::Ok().json(entries))
+}
+
+async fn restore_file(
+    path: actix_web::web::Path<String>,
+    state: State,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    let id = path.into_inner();
+    info!("restore file request received: {}", id);
+    let file = storage_request(&state, |tx| Message::RestoreFile(id, tx)).await?;
+    Ok(actix_web::HttpResponse::Ok().json(FileData::from(file)))
+}
+
+async fn purge_file(
+    path: actix_web::web::Path<String>,
+    state: State,
+) -> actix_web::Result<actix_web::HttpResponse> {
+    let id = path.into_inner();
+    info!("purge file request received: {}", id);
+    storage_request(&state, |tx| Message::PurgeFile(id, tx)).await?;
+    Ok(actix_web::HttpResponse::NoContent().finish())
+}
+
+/// Body of error responses.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct ErrorData {
+    status: u16,
+    /// Kebab case name of the error that clients can match on, e.g. `file-already-exists`.
+    code: String,
+    message: String,
+    /// Name of the content root of the file the error is about, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    root: Option<String>,
+    /// Path of the file the error is about relative to its content root, if known.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "lossless_path::option"
+    )]
+    path: Option<PathBuf>,
+}
+
+impl ErrorData {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::from_u16(self.status)
+            .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn response(&self) -> actix_web::HttpResponse {
+        actix_web::HttpResponse::build(self.status_code()).json(self)
+    }
+
+    /// An error that isn't caused by the storage server, named after the status.
+    fn new(status: actix_web::http::StatusCode, message: impl ToString) -> Self {
+        let code = status
+            .canonical_reason()
+            .unwrap_or("unknown error")
+            .to_lowercase()
+            .replace(' ', "-");
+        Self {
+            status: status.as_u16(),
+            code,
+            message: message.to_string(),
+            root: None,
+            path: None,
+        }
+    }
+
+    /// The error of the storage server. Clients only get to see paths inside the content roots,
+    /// relative to their root, since the rest of the server's file system is none of their
+    /// business. The absolute path is only logged.
+    fn of(err: &FileErr, roots: &[ContentRoot]) -> Self {
+        let message = match err {
+            FileErr::Io { kind, .. } => kind.to_string(),
+            _ => err.to_string(),
+        };
+        let (root, path) = match err.path().and_then(|path| relative_to_root(path, roots)) {
+            Some((root, path)) => (Some(root), Some(path)),
+            None => (None, None),
+        };
+        Self {
+            status: file_error_status(err).as_u16(),
+            code: file_error_code(err).to_string(),
+            message,
+            root,
+            path,
+        }
+    }
+}
+
+/// Name of the root the path is in and the path relative to it.
+fn relative_to_root(path: &Path, roots: &[ContentRoot]) -> Option<(String, PathBuf)> {
+    roots.iter().find_map(|root| {
+        // registered roots are already canonical, so this doesn't have to touch the filesystem
+        let relative = path.strip_prefix(&root.path).ok()?;
+        Some((root.name.clone(), relative.to_path_buf()))
+    })
+}
+
+fn file_error_status(err: &FileErr) -> actix_web::http::StatusCode {
+    use actix_web::http::StatusCode;
+    use std::io::ErrorKind;
+    match err {
+        FileErr::IdInvalid | FileErr::UnknownRoot | FileErr::PathDoesNotExist => {
+            StatusCode::NOT_FOUND
+        }
+        FileErr::InvalidName => StatusCode::BAD_REQUEST,
+        FileErr::Ignored => StatusCode::UNPROCESSABLE_ENTITY,
+        FileErr::ReadOnly => StatusCode::FORBIDDEN,
+        FileErr::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        FileErr::FileAlreadyExists | FileErr::DuplicateFile => StatusCode::CONFLICT,
+        FileErr::UploadLocked => StatusCode::LOCKED,
+        FileErr::Io { kind, .. } => match kind {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            ErrorKind::AlreadyExists => StatusCode::CONFLICT,
+            ErrorKind::StorageFull => StatusCode::INSUFFICIENT_STORAGE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+        FileErr::IndexDoesNotExist
+        | FileErr::IndexInvalid
+        | FileErr::DBError
+        | FileErr::FailedToCreateFile
+        | FileErr::InvalidConfig => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn file_error_code(err: &FileErr) -> &'static str {
+    match err {
+        FileErr::PathDoesNotExist => "path-does-not-exist",
+        FileErr::IndexDoesNotExist => "index-does-not-exist",
+        FileErr::IndexInvalid => "index-invalid",
+        FileErr::IdInvalid => "id-invalid",
+        FileErr::DBError => "db-error",
+        FileErr::FailedToCreateFile => "failed-to-create-file",
+        FileErr::DuplicateFile => "duplicate-file",
+        FileErr::FileAlreadyExists => "file-already-exists",
+        FileErr::InvalidName => "invalid-name",
+        FileErr::InvalidConfig => "invalid-config",
+        FileErr::Ignored => "ignored",
+        FileErr::UnknownRoot => "unknown-root",
+        FileErr::ReadOnly => "read-only",
+        FileErr::TooLarge => "too-large",
+        FileErr::UploadLocked => "upload-locked",
+        FileErr::Io { .. } => "io-error",
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct TrashData {
+    /// Id of the trash entry, which restoring and purging take.
+    id: String,
+    file_id: String,
+    name: String,
+    ty: String,
+    tags: Vec<String>,
+    /// Name of the content root the file was deleted from, empty if it wasn't in a registered
+    /// root.
+    #[serde(default)]
+    root: String,
+    /// Where the file is restored to relative to its root, like the paths of errors. Only the
+    /// name of the file if it wasn't in a registered root.
+    #[serde(with = "lossless_path")]
+    original_path: PathBuf,
+    deleted_at: u64,
+}
+
+impl TrashData {
+    fn new(entry: TrashEntry, roots: &[ContentRoot]) -> Self {
+        let (root, original_path) =
+            relative_to_root(&entry.file.path, roots).unwrap_or_else(|| {
+                let name = entry.file.path.file_name().unwrap_or_default();
+                (String::new(), PathBuf::from(name))
+            });
+        Self {
+            tags: entry.file.all_tags(),
+            id: entry.id,
+            file_id: entry.file.id.to_string(),
+            name: entry.file.name,
+            ty: entry.file.ty,
+            root,
+            original_path,
+            deleted_at: entry.deleted_at,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Default)]
+struct FileData {
+    name: String,
+    id: String,
+    ty: String,
+    /// Name of the content root of the file, empty if it isn't in a registered root.
+    #[serde(default)]
+    root: String,
+    /// Both path derived and user assigned tags.
+    tags: Vec<String>,
+    #[serde(default)]
+    user_tags: Vec<String>,
+    #[serde(default)]
+    mime: String,
+    #[serde(default)]
+    size: u64,
+    /// Timestamps in seconds since the unix epoch.
+    #[serde(default)]
+    created: Option<u64>,
+    #[serde(default)]
+    modified: Option<u64>,
+    #[serde(default)]
+    indexed_at: Option<u64>,
+}
+
+impl From<FileMetadata> for FileData {
+    fn from(value: FileMetadata) -> Self {
+        Self {
+            tags: value.all_tags(),
+            name: value.name,
+            id: value.id.to_string(),
+            ty: value.ty,
+            root: value.root,
+            user_tags: value.user_tags,
+            mime: value.mime,
+            size: value.size,
+            created: value.created,
+            modified: value.modified,
+            indexed_at: value.indexed_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::File,
+        io::{Read, Write},
+        path::PathBuf,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use crate::{
+        add_tags, append_upload, create_and_run_server, create_upload, delete_file, get_content,
+        get_file_by_type, get_files, get_files_by_tags, get_tags, get_trash, get_upload_offset,
+        index, is_storage_responsive, list_response, positional_args, post_file, purge_file,
+        reload_config, remove_tags, request_errors, restore_file, shutdown, terminate_upload,
+        update_file, update_tags_of_query, Config, Cursor, ErrorData, ExpressionQuery, FileData,
+        ListParams, QueryError, ServerState, SortKey, SortOrder, TagList, TagQuery, TagQueryData,
+        TagQueryUpdate, TrashData, UploadResult, IMMUTABLE_CACHE_CONTROL,
+    };
+    use actix_web::{
+        http::{
+            header::{self, ContentType, HeaderMap},
+            StatusCode,
+        },
+        test,
+        web::{self, Bytes},
+        App,
+    };
+    use pea_server::utils::{
+        backend::backup_path,
+        config::{ContentRoot, ServerConfig, UploadConfig},
+        snapshot::SharedSnapshot,
+        storage::{
+            clean_up_dir, FileErr, FileIndex, FileMetadata, FileUpdate, Message, StorageServer,
+        },
+        trash::TrashEntry,
+    };
+    use std::sync::Once;
+
+    static INIT: Once = Once::new();
+    const TEST_INDEX: &str = "test_index.json";
+
+    #[tokio::test]
+    async fn can_start_server() {
+        initialize();
+        tokio::spawn(async move {
             let config = Config {
                 id: uuid::Uuid::new_v4(),
                 address: Box::new(format!("localhost:{}", 5000)),
                 index_path: PathBuf::from(TEST_INDEX),
             };
-            let tx = StorageServer::initialize(&config.index_path);
+            let tx = StorageServer::initialize(&config.index_path)
+                .expect("expect loading index to succeed");
             create_and_run_server(config, tx)
                 .expect("expect server startup to succeed")
                 .await
@@ -438,6 +1669,33 @@ mod tests {
         })
         .abort()
     }
+    #[actix_web::test]
+    async fn can_shut_down_gracefully() {
+        initialize();
+        let test_index_path = PathBuf::from("./shutdown_test.json");
+        let storage =
+            StorageServer::initialize(&test_index_path).expect("expect loading index to succeed");
+        let config = Config {
+            id: uuid::Uuid::new_v4(),
+            address: Box::new(format!("localhost:{}", 5001)),
+            index_path: test_index_path,
+        };
+        let server = create_and_run_server(config, storage.clone())
+            .expect("expect server startup to succeed");
+        let handle = server.handle();
+        let server_task = tokio::spawn(server);
+        assert!(shutdown(handle, &storage, None).await);
+        server_task
+            .await
+            .unwrap()
+            .expect("expect server to stop cleanly");
+        let (tx, _rx) = tokio::sync::oneshot::channel();
+        assert!(storage
+            .transmitter
+            .send(Message::PurgeFile("1".to_string(), tx))
+            .is_err());
+    }
+
     #[actix_web::test]
     async fn can_get_the_index_page() {
         initialize();
@@ -450,61 +1708,631 @@ mod tests {
     }
 
     #[actix_web::test]
-    async fn can_upload_files() {
-        initialize();
+    async fn can_upload_files() {
+        initialize();
+        let server = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(ServerState::new(
+                    StorageServer::initialize(&PathBuf::from(TEST_INDEX))
+                        .expect("expect loading index to succeed"),
+                )))
+                .route("/file", web::post().to(post_file)),
+        )
+        .await;
+        let bytes = Bytes::from(
+            "testasdadsad\r\n\
+             --abbc761f78ff4d7cb7573b5a23f96ef0\r\n\
+             Content-Disposition: form-data; name=\"file\"; filename=\"f1.txt\"\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nContent-Length: 4\r\n\r\n\
+             test\r\n\
+             --abbc761f78ff4d7cb7573b5a23f96ef0\r\n\
+             Content-Disposition: form-data; name=\"file\"; filename=\"f2.txt\"\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nContent-Length: 4\r\n\r\n\
+             data\r\n\
+             --abbc761f78ff4d7cb7573b5a23f96ef0--\r\n",
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static(
+                "multipart/mixed; boundary=\"abbc761f78ff4d7cb7573b5a23f96ef0\"",
+            ),
+        );
+        let mut request = test::TestRequest::post().uri("/file").set_payload(bytes);
+        for (k, v) in headers {
+            request = request.insert_header((k, v));
+        }
+        let request = request.to_request();
+        let resp = test::call_service(&server, request).await;
+        assert!(resp.status().is_success());
+        let stored: Vec<UploadResult> = test::read_body_json(resp).await;
+        let stored: Vec<&str> = stored
+            .iter()
+            .filter_map(|each| each.file.as_ref())
+            .map(|each| each.name.as_str())
+            .collect();
+        assert_eq!(stored, vec!["f1.txt", "f2.txt"]);
+        let expected = [("f1.txt", "test"), ("f2.txt", "data")];
+        let index =
+            FileIndex::new(&PathBuf::from(TEST_INDEX)).expect("expect loading index to succeed");
+        let indexed_files: Vec<String> = index
+            .files()
+            .unwrap()
+            .into_iter()
+            .map(|each| each.name)
+            .collect();
+        for (file_name, content) in expected {
+            let file_path =
+                PathBuf::from(env::var("PEA_RECEIVED_FILES_DIR").unwrap()).join(file_name);
+            let mut file = std::fs::File::open(&file_path).expect("expect file to exist");
+            let mut actual = Vec::new();
+            file.read_to_end(&mut actual)
+                .expect("expect file reading to succeed");
+            assert_eq!(actual, content.as_bytes());
+            std::fs::remove_file(file_path).expect("expect deleting file to succeed");
+            assert!(indexed_files
+                .iter()
+                .any(|name| { name == &file_name.to_string() }));
+        }
+        std::fs::remove_file(TEST_INDEX).expect("expect deleting index to succeed");
+        std::fs::remove_file(backup_path(&PathBuf::from(TEST_INDEX)))
+            .expect("expect deleting index backup to succeed");
+    }
+
+    #[actix_web::test]
+    async fn can_limit_upload_size() {
+        initialize();
+        let test_index_path = PathBuf::from("./upload_limit_test.json");
+        let test_storage = PathBuf::from("./upload_limit_test");
+        std::fs::create_dir_all(&test_storage).expect("expect creating test storage to succeed");
+        let config = ServerConfig {
+            roots: vec![ContentRoot {
+                name: "uploads".to_string(),
+                path: test_storage.clone(),
+                ..Default::default()
+            }],
+            uploads: UploadConfig {
+                max_size: Some(4),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let server = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(ServerState::new(
+                    StorageServer::initialize_with_config(&test_index_path, config)
+                        .expect("expect loading index to succeed"),
+                )))
+                .route("/file", web::post().to(post_file)),
+        )
+        .await;
+        let upload = |file_name: &str, content: &str| {
+            test::TestRequest::post()
+                .uri("/file?root=uploads")
+                .insert_header((
+                    header::CONTENT_TYPE,
+                    "multipart/form-data; boundary=\"abbc761f78ff4d7cb7573b5a23f96ef0\"",
+                ))
+                .set_payload(format!(
+                    "--abbc761f78ff4d7cb7573b5a23f96ef0\r\n\
+                     Content-Disposition: form-data; name=\"file\"; filename=\"{file_name}\"\r\n\
+                     Content-Type: text/plain\r\n\r\n\
+                     {content}\r\n\
+                     --abbc761f78ff4d7cb7573b5a23f96ef0--\r\n"
+                ))
+                .to_request()
+        };
+        let resp = test::call_service(&server, upload("small.txt", "test")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&server, upload("large.txt", "larger")).await;
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        let results: Vec<UploadResult> = test::read_body_json(resp).await;
+        let status = results[0].error.as_ref().map(|error| error.status);
+        assert_eq!(status, Some(StatusCode::PAYLOAD_TOO_LARGE.as_u16()));
+        let mut stored: Vec<String> = std::fs::read_dir(&test_storage)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        stored.sort();
+        assert_eq!(stored, vec!["small.txt".to_string()]);
+        std::fs::remove_dir_all(&test_storage).expect("expect deleting test storage to succeed");
+        std::fs::remove_file(&test_index_path).expect("expect deleting index to succeed");
+        let _ = std::fs::remove_file(backup_path(&test_index_path));
+    }
+
+    #[actix_web::test]
+    async fn can_report_each_uploaded_part() {
+        initialize();
+        let test_index_path = PathBuf::from("./upload_parts_test.json");
+        let test_storage = PathBuf::from("./upload_parts_test");
+        std::fs::create_dir_all(&test_storage).expect("expect creating test storage to succeed");
+        let config = ServerConfig {
+            roots: vec![ContentRoot {
+                name: "uploads".to_string(),
+                path: test_storage.clone(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let server = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(ServerState::new(
+                    StorageServer::initialize_with_config(&test_index_path, config)
+                        .expect("expect loading index to succeed"),
+                )))
+                .route("/file", web::post().to(post_file)),
+        )
+        .await;
+        let part = |disposition: &str, content: &str| {
+            format!(
+                "--abbc761f78ff4d7cb7573b5a23f96ef0\r\n\
+                 Content-Disposition: form-data; {disposition}\r\n\r\n\
+                 {content}\r\n"
+            )
+        };
+        let body = [
+            part("name=\"directory\"", "docs/notes"),
+            part("name=\"tags\"", "work, draft"),
+            part("name=\"file\"; filename=\"a.txt\"", "first"),
+            part("name=\"file\"; filename=\"a.txt\"", "second"),
+            part("name=\"file\"; filename=\"../b.txt\"", "third"),
+        ]
+        .concat()
+            + "--abbc761f78ff4d7cb7573b5a23f96ef0--\r\n";
+        let request = test::TestRequest::post()
+            .uri("/file?root=uploads")
+            .insert_header((
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=\"abbc761f78ff4d7cb7573b5a23f96ef0\"",
+            ))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&server, request).await;
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        let results: Vec<UploadResult> = test::read_body_json(resp).await;
+        assert_eq!(results.len(), 3);
+        let file = results[0]
+            .file
+            .as_ref()
+            .expect("expect first part to be stored");
+        assert_eq!(file.name, "a.txt");
+        assert_eq!(file.user_tags, vec!["work", "draft"]);
+        let status = results[1].error.as_ref().map(|error| error.status);
+        assert_eq!(status, Some(StatusCode::CONFLICT.as_u16()));
+        let file = results[2]
+            .file
+            .as_ref()
+            .expect("expect third part to be stored");
+        assert_eq!(file.name, "b.txt");
+        let stored = test_storage.join("docs/notes");
+        assert_eq!(
+            std::fs::read_to_string(stored.join("a.txt")).unwrap(),
+            "first"
+        );
+        assert_eq!(
+            std::fs::read_to_string(stored.join("b.txt")).unwrap(),
+            "third"
+        );
+
+        let mut body = part("name=\"tags\"", "").into_bytes();
+        body.splice(body.len() - 2..body.len() - 2, [0xff, 0xfe]);
+        body.extend_from_slice(b"--abbc761f78ff4d7cb7573b5a23f96ef0--\r\n");
+        let request = test::TestRequest::post()
+            .uri("/file?root=uploads")
+            .insert_header((
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=\"abbc761f78ff4d7cb7573b5a23f96ef0\"",
+            ))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&server, request).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let error: ErrorData = test::read_body_json(resp).await;
+        assert_eq!(error.message, "form fields must be valid utf-8");
+        std::fs::remove_dir_all(&test_storage).expect("expect deleting test storage to succeed");
+        std::fs::remove_file(&test_index_path).expect("expect deleting index to succeed");
+        let _ = std::fs::remove_file(backup_path(&test_index_path));
+    }
+
+    #[actix_web::test]
+    async fn can_resume_uploads() {
+        initialize();
+        let test_storage = PathBuf::from("./resumable_upload_test");
+        let test_index_path = test_storage.join("index.json");
+        std::fs::create_dir_all(test_storage.join("files"))
+            .expect("expect creating test storage to succeed");
+        let config = ServerConfig {
+            roots: vec![ContentRoot {
+                name: "uploads".to_string(),
+                path: test_storage.join("files"),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let app = || {
+            App::new()
+                .app_data(actix_web::web::Data::new(ServerState::new(
+                    StorageServer::initialize_with_config(&test_index_path, config.clone())
+                        .expect("expect loading index to succeed"),
+                )))
+                .route("/uploads", web::post().to(create_upload))
+                .route("/uploads/{id}", web::head().to(get_upload_offset))
+                .route("/uploads/{id}", web::patch().to(append_upload))
+                .route("/uploads/{id}", web::delete().to(terminate_upload))
+        };
+        let server = test::init_service(app()).await;
+        // the file name is hello.txt and the root is uploads
+        let create = |metadata: &str| {
+            test::TestRequest::post()
+                .uri("/uploads")
+                .insert_header(("Tus-Resumable", "1.0.0"))
+                .insert_header(("Upload-Length", "10"))
+                .insert_header((
+                    "Upload-Metadata",
+                    format!("filename aGVsbG8udHh0,root dXBsb2Fkcw==,{metadata}"),
+                ))
+                .to_request()
+        };
+        let append = |location: &str, offset: u64, content: &'static str| {
+            test::TestRequest::patch()
+                .uri(location)
+                .insert_header(("Tus-Resumable", "1.0.0"))
+                .insert_header(("Upload-Offset", offset))
+                .insert_header((header::CONTENT_TYPE, "application/offset+octet-stream"))
+                .set_payload(content)
+                .to_request()
+        };
+        let offset_of = |location: &str| {
+            test::TestRequest::default()
+                .method(actix_web::http::Method::HEAD)
+                .uri(location)
+                .insert_header(("Tus-Resumable", "1.0.0"))
+                .to_request()
+        };
+        let header_of = |resp: &actix_web::dev::ServiceResponse, name: &str| {
+            resp.headers()
+                .get(name)
+                .map(|value| value.to_str().unwrap().to_string())
+        };
+
+        let request = test::TestRequest::post().uri("/uploads").to_request();
+        let resp = test::call_service(&server, request).await;
+        assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+        let error: ErrorData = test::read_body_json(resp).await;
+        assert_eq!(error.code, "precondition-failed");
+        let resp = test::call_service(&server, create("")).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let location = header_of(&resp, "Location").expect("expect upload location");
+        let expires = header_of(&resp, "Upload-Expires").expect("expect upload expiry");
+        let resp = test::call_service(&server, append(&location, 0, "hello")).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(header_of(&resp, "Upload-Offset").as_deref(), Some("5"));
+        assert_eq!(header_of(&resp, "Upload-Expires"), Some(expires));
+
+        // the upload continues where it stopped after a restart
+        let server = test::init_service(app()).await;
+        let resp = test::call_service(&server, offset_of(&location)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(header_of(&resp, "Upload-Offset").as_deref(), Some("5"));
+        let resp = test::call_service(&server, append(&location, 3, "lo")).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let error: ErrorData = test::read_body_json(resp).await;
+        assert_eq!(error.message, "upload is at offset 5");
+        let resp = test::call_service(&server, append(&location, 5, "world")).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(header_of(&resp, "Upload-Offset").as_deref(), Some("10"));
+        let resp = test::call_service(&server, offset_of(&location)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let content = std::fs::read_to_string(test_storage.join("files/hello.txt"))
+            .expect("expect uploaded file to exist");
+        assert_eq!(content, "helloworld");
+        let index = FileIndex::new(&test_index_path).expect("expect loading index to succeed");
+        let files: Vec<(String, String)> = index
+            .files()
+            .unwrap()
+            .into_iter()
+            .map(|each| (each.name, each.root))
+            .collect();
+        assert_eq!(
+            files,
+            vec![("hello.txt".to_string(), "uploads".to_string())]
+        );
+
+        let resp = test::call_service(&server, create("")).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        // on_conflict is rename
+        let resp = test::call_service(&server, create("on_conflict cmVuYW1l")).await;
+        let location = header_of(&resp, "Location").expect("expect upload location");
+        let request = test::TestRequest::delete()
+            .uri(&location)
+            .insert_header(("Tus-Resumable", "1.0.0"))
+            .to_request();
+        let resp = test::call_service(&server, request).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(&server, offset_of(&location)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            std::fs::read_dir(test_storage.join("files"))
+                .unwrap()
+                .count(),
+            1
+        );
+        std::fs::remove_dir_all(&test_storage).expect("expect deleting test storage to succeed");
+    }
+
+    #[actix_web::test]
+    async fn unlocks_uploads_of_dropped_requests() {
+        initialize();
+        let test_storage = PathBuf::from("./dropped_upload_test");
+        let test_index_path = test_storage.join("index.json");
+        std::fs::create_dir_all(test_storage.join("files"))
+            .expect("expect creating test storage to succeed");
+        let config = ServerConfig {
+            roots: vec![ContentRoot {
+                name: "uploads".to_string(),
+                path: test_storage.join("files"),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let storage = StorageServer::initialize_with_config(&test_index_path, config)
+            .expect("expect loading index to succeed");
+        // a slow storage server, so requests can be dropped while waiting for it
+        let (proxy_tx, proxy_rx) = crossbeam_channel::unbounded::<Message>();
+        let storage_tx = storage.transmitter.clone();
+        std::thread::spawn(move || {
+            for message in proxy_rx {
+                std::thread::sleep(Duration::from_millis(100));
+                if storage_tx.send(message).is_err() {
+                    return;
+                }
+            }
+        });
+        let state = ServerState {
+            storage_server_transmitter: proxy_tx,
+            snapshot: storage.snapshot.clone(),
+            storage_timeout: Duration::from_secs(30),
+        };
+        let server = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(state))
+                .route("/uploads", web::post().to(create_upload))
+                .route("/uploads/{id}", web::patch().to(append_upload)),
+        )
+        .await;
+        // the file name is hello.txt and the root is uploads
+        let request = test::TestRequest::post()
+            .uri("/uploads")
+            .insert_header(("Tus-Resumable", "1.0.0"))
+            .insert_header(("Upload-Length", "10"))
+            .insert_header(("Upload-Metadata", "filename aGVsbG8udHh0,root dXBsb2Fkcw=="))
+            .to_request();
+        let resp = test::call_service(&server, request).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let location = resp
+            .headers()
+            .get(header::LOCATION)
+            .expect("expect created upload to have a location")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let append = |offset: u64| {
+            test::TestRequest::patch()
+                .uri(&location)
+                .insert_header(("Tus-Resumable", "1.0.0"))
+                .insert_header(("Upload-Offset", offset))
+                .insert_header((header::CONTENT_TYPE, "application/offset+octet-stream"))
+        };
+
+        // dropped while waiting for the lock
+        let request = append(0).set_payload("hello").to_request();
+        let call = test::call_service(&server, request);
+        assert!(tokio::time::timeout(Duration::from_millis(20), call)
+            .await
+            .is_err());
+        // dropped while receiving the content, which never ends
+        let content: std::pin::Pin<Box<dyn futures_util::Stream<Item = _>>> =
+            Box::pin(futures_util::StreamExt::chain(
+                futures_util::stream::iter([Ok::<_, actix_web::error::PayloadError>(
+                    Bytes::from_static(b"hello"),
+                )]),
+                futures_util::stream::pending(),
+            ));
+        let (request, _) = append(0)
+            .to_request()
+            .replace_payload(actix_web::dev::Payload::Stream { payload: content });
+        let call = test::call_service(&server, request);
+        assert!(tokio::time::timeout(Duration::from_millis(500), call)
+            .await
+            .is_err());
+
+        let request = append(5).set_payload("world").to_request();
+        let resp = test::call_service(&server, request).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let content = std::fs::read_to_string(test_storage.join("files/hello.txt"))
+            .expect("expect uploaded file to exist");
+        assert_eq!(content, "helloworld");
+        std::fs::remove_dir_all(&test_storage).expect("expect deleting test storage to succeed");
+    }
+
+    #[actix_web::test]
+    async fn hides_server_paths() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let roots = [ContentRoot {
+            name: "photos".to_string(),
+            path: PathBuf::from("/srv/content/photos"),
+            ..Default::default()
+        }];
+        let err = FileErr::Io {
+            path: PathBuf::from("/srv/content/photos/2022/beach.jpg"),
+            kind: std::io::ErrorKind::PermissionDenied,
+        };
+        let error = ErrorData::of(&err, &roots);
+        assert_eq!(error.status, 403);
+        assert_eq!(error.code, "io-error");
+        assert_eq!(error.root.as_deref(), Some("photos"));
+        assert_eq!(error.path, Some(PathBuf::from("2022/beach.jpg")));
+        assert!(!error.message.contains("/srv"));
+
+        // paths outside of the content roots are only logged
+        let err = FileErr::Io {
+            path: PathBuf::from("/srv/index.json"),
+            kind: std::io::ErrorKind::PermissionDenied,
+        };
+        let error = ErrorData::of(&err, &roots);
+        assert_eq!(error.root, None);
+        assert_eq!(error.path, None);
+        assert!(!error.message.contains("/srv"));
+
+        // deleted files with names that aren't valid UTF-8 can be listed as well
+        let path = PathBuf::from("/srv/content/photos/2022")
+            .join(std::ffi::OsStr::from_bytes(b"caf\xe9.jpg"));
+        let entry = TrashEntry {
+            id: "1".to_string(),
+            file: FileMetadata {
+                name: "caf\u{FFFD}.jpg".to_string(),
+                path,
+                root: "photos".to_string(),
+                ..Default::default()
+            },
+            trash_path: PathBuf::from("/srv/trash/1-caf\u{FFFD}.jpg"),
+            deleted_at: 0,
+        };
+        let body = serde_json::to_string(&TrashData::new(entry, &roots))
+            .expect("expect serializing trash entry to succeed");
+        assert!(!body.contains("/srv"));
+        let trash: TrashData = serde_json::from_str(&body).unwrap();
+        assert_eq!(trash.root, "photos");
+        assert_eq!(
+            trash.original_path.as_os_str().as_bytes(),
+            b"2022/caf\xe9.jpg"
+        );
+    }
+
+    #[actix_web::test]
+    async fn can_reject_malformed_requests() {
+        let server = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(ServerState {
+                    storage_server_transmitter: crossbeam_channel::unbounded().0,
+                    snapshot: SharedSnapshot::default(),
+                    storage_timeout: Duration::from_millis(50),
+                }))
+                .configure(request_errors)
+                .route("/files", web::get().to(get_files))
+                .route("/file/{id}", web::delete().to(delete_file))
+                .route("/file/{id}/tags", web::post().to(add_tags)),
+        )
+        .await;
+        for request in [
+            test::TestRequest::get().uri("/files?limit=many"),
+            test::TestRequest::delete().uri("/file/abc"),
+            test::TestRequest::post()
+                .uri("/file/1/tags")
+                .insert_header(ContentType::json())
+                .set_payload("{"),
+        ] {
+            let response = test::call_service(&server, request.to_request()).await;
+            assert!(response.status().is_client_error());
+            let error: ErrorData = test::read_body_json(response).await;
+            assert!(!error.message.is_empty());
+        }
+    }
+
+    #[actix_web::test]
+    async fn keeps_config_when_reload_fails() {
+        initialize();
+        let test_storage = PathBuf::from("./reload_config_test");
+        let test_index_path = test_storage.join("index.json");
+        std::fs::create_dir_all(test_storage.join("files"))
+            .expect("expect creating test storage to succeed");
+        let config = ServerConfig {
+            roots: vec![ContentRoot {
+                name: "media".to_string(),
+                path: test_storage.join("files"),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let storage = StorageServer::initialize_with_config(&test_index_path, config)
+            .expect("expect loading index to succeed");
+
+        let missing = ServerConfig::from_file(&test_storage.join("missing.json"));
+        assert!(matches!(
+            missing,
+            Err(FileErr::Io {
+                kind: std::io::ErrorKind::NotFound,
+                ..
+            })
+        ));
+        assert!(!reload_config(&storage, missing).await);
+        let roots = storage.snapshot.load().roots();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].name, "media");
+
+        assert!(reload_config(&storage, Ok(ServerConfig::default())).await);
+        assert!(storage.snapshot.load().roots().is_empty());
+        std::fs::remove_dir_all(&test_storage).expect("expect deleting test storage to succeed");
+    }
+
+    #[actix_web::test]
+    async fn can_time_out_storage_requests() {
+        // a storage server that never replies
+        let (storage_tx, storage_rx) = crossbeam_channel::unbounded();
+        let state = ServerState {
+            storage_server_transmitter: storage_tx,
+            snapshot: SharedSnapshot::default(),
+            storage_timeout: Duration::from_millis(50),
+        };
         let server = test::init_service(
             App::new()
-                .app_data(actix_web::web::Data::new(ServerState {
-                    storage_server_transmitter: StorageServer::initialize(&PathBuf::from(
-                        TEST_INDEX,
-                    )),
-                }))
-                .route("/file", web::post().to(post_file)),
+                .app_data(actix_web::web::Data::new(state))
+                .route("/files", web::get().to(get_files))
+                .route("/file/{id}", web::delete().to(delete_file)),
         )
         .await;
-        let bytes = Bytes::from(
-            "testasdadsad\r\n\
-             --abbc761f78ff4d7cb7573b5a23f96ef0\r\n\
-             Content-Disposition: form-data; name=\"file\"; filename=\"f1.txt\"\r\n\
-             Content-Type: text/plain; charset=utf-8\r\nContent-Length: 4\r\n\r\n\
-             test\r\n\
-             --abbc761f78ff4d7cb7573b5a23f96ef0\r\n\
-             Content-Disposition: form-data; name=\"file\"; filename=\"f2.txt\"\r\n\
-             Content-Type: text/plain; charset=utf-8\r\nContent-Length: 4\r\n\r\n\
-             data\r\n\
-             --abbc761f78ff4d7cb7573b5a23f96ef0--\r\n",
-        );
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            header::CONTENT_TYPE,
-            header::HeaderValue::from_static(
-                "multipart/mixed; boundary=\"abbc761f78ff4d7cb7573b5a23f96ef0\"",
-            ),
-        );
-        let mut request = test::TestRequest::post().uri("/file").set_payload(bytes);
-        for (k, v) in headers {
-            request = request.insert_header((k, v));
-        }
-        let request = request.to_request();
-        let resp = test::call_service(&server, request).await;
-        assert!(resp.status().is_success());
-        let expected = [("f1.txt", "test"), ("f2.txt", "data")];
-        let index = FileIndex::new(&PathBuf::from(TEST_INDEX));
-        let indexed_files: Vec<String> = index.files().into_iter().map(|each| each.name).collect();
-        for (file_name, content) in expected {
-            let file_path =
-                PathBuf::from(env::var("PEA_RECEIVED_FILES_DIR").unwrap()).join(file_name);
-            let mut file = std::fs::File::open(&file_path).expect("expect file to exist");
-            let mut actual = Vec::new();
-            file.read_to_end(&mut actual)
-                .expect("expect file reading to succeed");
-            assert_eq!(actual, content.as_bytes());
-            std::fs::remove_file(file_path).expect("expect deleting file to succeed");
-            assert!(indexed_files
-                .iter()
-                .any(|name| { name == &file_name.to_string() }));
-        }
-        std::fs::remove_file(TEST_INDEX).expect("expect deleting index to succeed");
+        let request = test::TestRequest::delete().uri("/file/1").to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let error: ErrorData = test::read_body_json(response).await;
+        assert_eq!(error.code, "service-unavailable");
+        assert_eq!(storage_rx.len(), 1);
+
+        // queries are answered from the snapshot without waiting for the storage server
+        let request = test::TestRequest::get().uri("/files").to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // a storage server that stopped
+        drop(storage_rx);
+        let request = test::TestRequest::delete().uri("/file/1").to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_web::test]
+    async fn checks_storage_for_watchdog() {
+        let test_index_path = PathBuf::from("./watchdog_test.json");
+        let storage = StorageServer::initialize_with_config(&test_index_path, Default::default())
+            .expect("expect loading index to succeed");
+        assert!(is_storage_responsive(&ServerState::new(storage.clone())).await);
+
+        // a storage server that hangs
+        let (storage_tx, _storage_rx) = crossbeam_channel::unbounded();
+        let state = ServerState {
+            storage_server_transmitter: storage_tx,
+            snapshot: SharedSnapshot::default(),
+            storage_timeout: Duration::from_millis(50),
+        };
+        assert!(!is_storage_responsive(&state).await);
+        // a storage server that stopped
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        storage.transmitter.send(Message::ShutDown(tx)).unwrap();
+        rx.await.unwrap().expect("expect shutting down to succeed");
+        assert!(!is_storage_responsive(&ServerState::new(storage)).await);
+        let _ = std::fs::remove_file(&test_index_path);
+        let _ = std::fs::remove_file(backup_path(&test_index_path));
     }
 
     #[actix_web::test]
@@ -518,6 +2346,7 @@ mod tests {
                 ty: "txt".to_string(),
                 path: PathBuf::from("./dummy-file/1.txt"),
                 tags: None,
+                ..Default::default()
             },
             FileMetadata {
                 name: "2.mp4".to_string(),
@@ -525,15 +2354,17 @@ mod tests {
                 ty: "mp4".to_string(),
                 path: PathBuf::from("./dummy-file/2.mp4"),
                 tags: None,
+                ..Default::default()
             },
         ];
         let body = serde_json::to_string_pretty(&files).unwrap();
         std::fs::write(&test_index_path, body).expect("expect creating index file to succeed");
         let server = test::init_service(
             App::new()
-                .app_data(actix_web::web::Data::new(ServerState {
-                    storage_server_transmitter: StorageServer::initialize(&test_index_path),
-                }))
+                .app_data(actix_web::web::Data::new(ServerState::new(
+                    StorageServer::initialize(&test_index_path)
+                        .expect("expect loading index to succeed"),
+                )))
                 .route("/files/{type}", web::get().to(get_file_by_type)),
         )
         .await;
@@ -541,15 +2372,23 @@ mod tests {
         let response = test::call_service(&server, request).await;
         assert!(response.status().is_success());
         let response_body: Vec<FileData> = test::read_body_json(response).await;
+        // the dummy files don't exist so the migration can only guess the MIME type
+        assert!(response_body[0].indexed_at.is_some());
         assert_eq!(
             response_body,
             vec![FileData {
                 name: "1.txt".to_string(),
                 id: 1.to_string(),
                 ty: "txt".to_string(),
-                tags: vec![]
+                tags: vec![],
+                user_tags: vec![],
+                mime: "text/plain".to_string(),
+                indexed_at: response_body[0].indexed_at,
+                ..Default::default()
             },]
         );
+        std::fs::remove_file(backup_path(&test_index_path))
+            .expect("expect cleaning test index backup to succeed");
         std::fs::remove_file(test_index_path).expect("expect cleaning test index file to succeed");
     }
 
@@ -564,6 +2403,7 @@ mod tests {
                 ty: "txt".to_string(),
                 path: PathBuf::from("./dummy-file/1.txt"),
                 tags: Some(vec!["tag1".to_string(), "tag2".to_string()]),
+                ..Default::default()
             },
             FileMetadata {
                 name: "2.mp4".to_string(),
@@ -575,15 +2415,17 @@ mod tests {
                     "tag4".to_string(),
                     "tag3".to_string(),
                 ]),
+                ..Default::default()
             },
         ];
         let body = serde_json::to_string_pretty(&files).unwrap();
         std::fs::write(&test_index_path, body).expect("expect creating index file to succeed");
         let server = test::init_service(
             App::new()
-                .app_data(actix_web::web::Data::new(ServerState {
-                    storage_server_transmitter: StorageServer::initialize(&test_index_path),
-                }))
+                .app_data(actix_web::web::Data::new(ServerState::new(
+                    StorageServer::initialize(&test_index_path)
+                        .expect("expect loading index to succeed"),
+                )))
                 .route("/tags", web::get().to(get_tags)),
         )
         .await;
@@ -599,6 +2441,8 @@ mod tests {
             "tag4".to_string(),
         ];
         assert_eq!(response_body, expected);
+        std::fs::remove_file(backup_path(&test_index_path))
+            .expect("expect cleaning test index backup to succeed");
         std::fs::remove_file(test_index_path).expect("expect cleaning test index file to succeed");
     }
 
@@ -613,6 +2457,7 @@ mod tests {
                 ty: "txt".to_string(),
                 path: PathBuf::from("./dummy-file/1.txt"),
                 tags: Some(vec!["tag1".to_string(), "tag2".to_string()]),
+                ..Default::default()
             },
             FileMetadata {
                 name: "2.mp4".to_string(),
@@ -625,6 +2470,7 @@ mod tests {
                     "tag3".to_string(),
                     "tag4".to_string(),
                 ]),
+                ..Default::default()
             },
             FileMetadata {
                 name: "3.mp4".to_string(),
@@ -632,6 +2478,7 @@ mod tests {
                 ty: "mp4".to_string(),
                 path: PathBuf::from("./dummy-file/2.mp4"),
                 tags: Some(vec!["tag1".to_string()]),
+                ..Default::default()
             },
         ];
         let body = serde_json::to_string_pretty(&files).unwrap();
@@ -639,9 +2486,10 @@ mod tests {
 
         let server = test::init_service(
             App::new()
-                .app_data(actix_web::web::Data::new(ServerState {
-                    storage_server_transmitter: StorageServer::initialize(&test_index_path),
-                }))
+                .app_data(actix_web::web::Data::new(ServerState::new(
+                    StorageServer::initialize(&test_index_path)
+                        .expect("expect loading index to succeed"),
+                )))
                 .route("/query", web::post().to(get_files_by_tags)),
         )
         .await;
@@ -688,14 +2536,566 @@ mod tests {
         let expected = vec!["1.txt".to_string(), "2.mp4".to_string()];
         assert_eq!(res_files, expected);
 
+        let query = ExpressionQuery {
+            query: "(tag3 OR type:txt,pdf) AND NOT tag4".to_string(),
+        };
+        let request = test::TestRequest::post()
+            .uri("/query")
+            .set_json(query)
+            .to_request();
+        let response_body: Vec<FileData> = test::call_and_read_body_json(&server, request).await;
+        let res_files = response_body
+            .into_iter()
+            .map(|each| each.name)
+            .collect::<Vec<String>>();
+        assert_eq!(res_files, vec!["1.txt".to_string()]);
+
+        let query = ExpressionQuery {
+            query: "tag1 AND (tag2 OR )".to_string(),
+        };
+        let request = test::TestRequest::post()
+            .uri("/query")
+            .set_json(query)
+            .to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: QueryError = test::read_body_json(response).await;
+        assert_eq!(error.error.code, "bad-request");
+        assert_eq!(error.position, 18);
+        assert_eq!(error.token, ")");
+
+        std::fs::remove_file(backup_path(&test_index_path))
+            .expect("expect cleaning test index backup to succeed");
+        std::fs::remove_file(test_index_path).expect("expect cleaning test index file to succeed");
+    }
+    #[actix_web::test]
+    async fn can_read_positional_args_between_flags() {
+        let args = [
+            "--daemon",
+            "index.json",
+            "--pid-file=pea.pid",
+            "0.0.0.0:8080",
+            "--watch",
+        ];
+        let positional: Vec<String> = positional_args(args.map(String::from).into_iter()).collect();
+        assert_eq!(positional, vec!["index.json", "0.0.0.0:8080"]);
+    }
+
+    #[actix_web::test]
+    async fn can_page_and_sort_files() {
+        initialize();
+        let test_index_path = PathBuf::from("./page_files_test.json");
+        let files: Vec<FileMetadata> = ["b.mp4", "a.mp4", "c.txt", "a.txt"]
+            .iter()
+            .zip([30, 10, 20, 10])
+            .enumerate()
+            .map(|(id, (name, size))| FileMetadata {
+                name: name.to_string(),
+                id: id as u64,
+                ty: name.split('.').next_back().unwrap().to_string(),
+                path: PathBuf::from("./dummy-file").join(name),
+                size,
+                ..Default::default()
+            })
+            .collect();
+        let body = serde_json::to_string_pretty(&files).unwrap();
+        std::fs::write(&test_index_path, body).expect("expect creating index file to succeed");
+        let server = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(ServerState::new(
+                    StorageServer::initialize(&test_index_path)
+                        .expect("expect loading index to succeed"),
+                )))
+                .route("/files", web::get().to(get_files))
+                .route("/files/{type}", web::get().to(get_file_by_type)),
+        )
+        .await;
+
+        let mut names = Vec::new();
+        let mut uri = "/files?limit=3".to_string();
+        loop {
+            let request = test::TestRequest::get().uri(&uri).to_request();
+            let response = test::call_service(&server, request).await;
+            assert_eq!(response.headers().get("X-Total-Count").unwrap(), "4");
+            let cursor = response
+                .headers()
+                .get("X-Next-Cursor")
+                .map(|cursor| cursor.to_str().unwrap().to_string());
+            let page: Vec<FileData> = test::read_body_json(response).await;
+            names.extend(page.into_iter().map(|each| each.name));
+            match cursor {
+                Some(cursor) => uri = format!("/files?limit=3&cursor={cursor}"),
+                None => break,
+            }
+        }
+        assert_eq!(names, vec!["a.mp4", "a.txt", "b.mp4", "c.txt"]);
+
+        // ties are broken by the id
+        let request = test::TestRequest::get()
+            .uri("/files?sort=size&order=desc&fields=name,size")
+            .to_request();
+        let page: Vec<serde_json::Value> = test::call_and_read_body_json(&server, request).await;
+        assert_eq!(
+            page,
+            vec![
+                serde_json::json!({"name": "b.mp4", "size": 30}),
+                serde_json::json!({"name": "c.txt", "size": 20}),
+                serde_json::json!({"name": "a.txt", "size": 10}),
+                serde_json::json!({"name": "a.mp4", "size": 10}),
+            ]
+        );
+
+        let request = test::TestRequest::get()
+            .uri("/files/mp4?sort=name&order=desc&limit=1")
+            .to_request();
+        let response = test::call_service(&server, request).await;
+        let cursor = response.headers().get("X-Next-Cursor").unwrap();
+        let uri = format!(
+            "/files/mp4?sort=name&order=desc&limit=1&cursor={}",
+            cursor.to_str().unwrap()
+        );
+        let request = test::TestRequest::get().uri(&uri).to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.headers().get("X-Total-Count").unwrap(), "2");
+        assert!(response.headers().get("X-Next-Cursor").is_none());
+        let page: Vec<FileData> = test::read_body_json(response).await;
+        assert_eq!(page[0].name, "a.mp4");
+
+        let request = test::TestRequest::get()
+            .uri("/files?cursor=nope")
+            .to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: ErrorData = test::read_body_json(response).await;
+        assert_eq!(error.code, "bad-request");
+        assert_eq!(error.message, "invalid cursor");
+        // a cursor only resumes the listing it was created for
+        let cursor = Cursor::after(&files[0], SortKey::Size, SortOrder::Asc).encode();
+        let request = test::TestRequest::get()
+            .uri(&format!("/files?cursor={cursor}"))
+            .to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // removing a file of a previous page doesn't shift the next page
+        let params = ListParams {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let response = list_response(files.clone(), &params, FileData::from);
+        let cursor = response.headers().get("X-Next-Cursor").unwrap();
+        let params = ListParams {
+            cursor: Some(cursor.to_str().unwrap().to_string()),
+            ..params
+        };
+        let remaining = files.iter().filter(|each| each.name != "a.mp4").cloned();
+        let response = list_response(remaining.collect(), &params, FileData::from);
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let page: Vec<FileData> = serde_json::from_slice(&body).unwrap();
+        let names: Vec<_> = page.into_iter().map(|each| each.name).collect();
+        assert_eq!(names, vec!["b.mp4", "c.txt"]);
+        let request = test::TestRequest::get()
+            .uri("/files?sort=color")
+            .to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        std::fs::remove_file(backup_path(&test_index_path))
+            .expect("expect cleaning test index backup to succeed");
+        std::fs::remove_file(test_index_path).expect("expect cleaning test index file to succeed");
+    }
+
+    #[actix_web::test]
+    async fn can_serve_cacheable_content() {
+        initialize();
+        let test_dir = PathBuf::from("./content_test");
+        let test_index_path = PathBuf::from("./content_test.json");
+        std::fs::create_dir_all(&test_dir).expect("expect creating test dir to succeed");
+        std::fs::write(test_dir.join("video.mp4"), "0123456789")
+            .expect("expect creating test file to succeed");
+        let mut index = FileIndex::new(&test_index_path).expect("expect loading index to succeed");
+        index
+            .add_dir(&test_dir)
+            .expect("expect indexing directory to succeed");
+        let file = index.files().unwrap()[0].clone();
+        drop(index);
+        let server = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(ServerState::new(
+                    StorageServer::initialize(&test_index_path)
+                        .expect("expect loading index to succeed"),
+                )))
+                .route("/content/{file_name}", web::get().to(get_content)),
+        )
+        .await;
+        let uri = format!("/content/{}", file.id);
+        let etag = format!("\"{}\"", file.hash);
+
+        let request = test::TestRequest::get().uri(&uri).to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(header::ETAG).unwrap(), etag.as_str());
+        assert_eq!(
+            response.headers().get(header::CACHE_CONTROL).unwrap(),
+            IMMUTABLE_CACHE_CONTROL
+        );
+        assert_eq!(test::read_body(response).await, "0123456789");
+
+        let request = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((header::IF_NONE_MATCH, etag.as_str()))
+            .to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers().get(header::ETAG).unwrap(), etag.as_str());
+        let request = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((header::IF_NONE_MATCH, "\"other\""))
+            .to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let last_modified =
+            header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(u32::MAX.into()));
+        let request = test::TestRequest::get()
+            .uri(&uri)
+            .insert_header((header::IF_MODIFIED_SINCE, last_modified.to_string()))
+            .to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // seeking
+        for (range, status, content_range, body) in [
+            (
+                "bytes=2-5",
+                StatusCode::PARTIAL_CONTENT,
+                "bytes 2-5/10",
+                "2345",
+            ),
+            (
+                "bytes=7-",
+                StatusCode::PARTIAL_CONTENT,
+                "bytes 7-9/10",
+                "789",
+            ),
+            (
+                "bytes=-3",
+                StatusCode::PARTIAL_CONTENT,
+                "bytes 7-9/10",
+                "789",
+            ),
+            (
+                "bytes=20-30",
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                "bytes */10",
+                "",
+            ),
+        ] {
+            let request = test::TestRequest::get()
+                .uri(&uri)
+                .insert_header((header::RANGE, range))
+                .to_request();
+            let response = test::call_service(&server, request).await;
+            assert_eq!(response.status(), status, "{range}");
+            let headers = response.headers();
+            assert_eq!(headers.get(header::CONTENT_RANGE).unwrap(), content_range);
+            assert_eq!(headers.get(header::ACCEPT_RANGES).unwrap(), "bytes");
+            assert_eq!(test::read_body(response).await, body, "{range}");
+        }
+
+        // changed since indexed
+        std::fs::write(test_dir.join("video.mp4"), "changed").unwrap();
+        let request = test::TestRequest::get().uri(&uri).to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers().get(header::ETAG).unwrap(), etag.as_str());
+        assert_eq!(
+            response.headers().get(header::CACHE_CONTROL).unwrap(),
+            "no-cache"
+        );
+
+        let request = test::TestRequest::get().uri("/content/123").to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let request = test::TestRequest::get().uri("/content/abc").to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: ErrorData = test::read_body_json(response).await;
+        assert_eq!(error.code, "bad-request");
+        std::fs::remove_dir_all(&test_dir).expect("expect deleting test dir to succeed");
+        std::fs::remove_file(&test_index_path).expect("expect deleting index to succeed");
+        let _ = std::fs::remove_file(backup_path(&test_index_path));
+    }
+
+    #[actix_web::test]
+    async fn can_delete_and_restore_files() {
+        initialize();
+        let test_dir = PathBuf::from("./delete_files_test");
+        let test_index_path = PathBuf::from("./delete_files_test.json");
+        std::fs::create_dir_all(&test_dir).expect("expect creating test dir to succeed");
+        std::fs::write(test_dir.join("1.txt"), "delete me")
+            .expect("expect creating test file to succeed");
+        let mut index = FileIndex::new(&test_index_path).expect("expect loading index to succeed");
+        index
+            .add_dir(&test_dir)
+            .expect("expect indexing directory to succeed");
+        let id = index.files().unwrap()[0].id;
+        drop(index);
+        let server = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(ServerState::new(
+                    StorageServer::initialize(&test_index_path)
+                        .expect("expect loading index to succeed"),
+                )))
+                .route("/file/{id}", web::delete().to(delete_file))
+                .route("/trash", web::get().to(get_trash))
+                .route("/trash/{id}/restore", web::post().to(restore_file))
+                .route("/trash/{id}", web::delete().to(purge_file)),
+        )
+        .await;
+        let file_uri = format!("/file/{id}");
+
+        let request = test::TestRequest::delete().uri(&file_uri).to_request();
+        let entry: TrashData = test::call_and_read_body_json(&server, request).await;
+        assert_eq!(entry.file_id, id.to_string());
+        assert_eq!(entry.root, "");
+        assert_eq!(entry.original_path, PathBuf::from("1.txt"));
+        assert!(!test_dir.join("1.txt").exists());
+        let request = test::TestRequest::get().uri("/trash").to_request();
+        let trash: Vec<TrashData> = test::call_and_read_body_json(&server, request).await;
+        assert!(trash.contains(&entry));
+
+        let request = test::TestRequest::post()
+            .uri(&format!("/trash/{}/restore", entry.id))
+            .to_request();
+        let response = test::call_service(&server, request).await;
+        assert!(response.status().is_success());
+        let content =
+            std::fs::read_to_string(test_dir.join("1.txt")).expect("expect restored file to exist");
+        assert_eq!(content, "delete me");
+
+        // deleting the file again gets a new entry instead of replacing the restored one
+        let request = test::TestRequest::delete().uri(&file_uri).to_request();
+        let second_entry: TrashData = test::call_and_read_body_json(&server, request).await;
+        assert_ne!(second_entry.id, entry.id);
+        let request = test::TestRequest::post()
+            .uri(&format!("/trash/{}/restore", entry.id))
+            .to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let request = test::TestRequest::delete()
+            .uri(&format!("/trash/{}", second_entry.id))
+            .to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let request = test::TestRequest::get().uri("/trash").to_request();
+        let trash: Vec<TrashData> = test::call_and_read_body_json(&server, request).await;
+        assert!(!trash.iter().any(|each| each.file_id == id.to_string()));
+        let request = test::TestRequest::delete().uri(&file_uri).to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let error: ErrorData = test::read_body_json(response).await;
+        assert_eq!(error.status, 404);
+        assert_eq!(error.code, "id-invalid");
+
+        std::fs::remove_dir_all(test_dir).expect("expect cleaning test dir to succeed");
+        std::fs::remove_dir_all(env::var("PEA_TRASH_DIR").unwrap())
+            .expect("expect cleaning trash dir to succeed");
+        std::fs::remove_file(backup_path(&test_index_path))
+            .expect("expect cleaning test index backup to succeed");
+        std::fs::remove_file(test_index_path).expect("expect cleaning test index file to succeed");
+    }
+
+    #[actix_web::test]
+    async fn can_rename_and_move_files() {
+        initialize();
+        let test_dir = PathBuf::from("./update_files_test");
+        let test_index_path = PathBuf::from("./update_files_test.json");
+        for (file, content) in [("a/1.txt", "first"), ("b/2.txt", "second")] {
+            let path = test_dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).expect("expect creating test file to succeed");
+        }
+        let mut index = FileIndex::new(&test_index_path).expect("expect loading index to succeed");
+        index
+            .add_dir(&test_dir)
+            .expect("expect indexing directory to succeed");
+        let id = index.files_of_tags(&["a".to_string()]).unwrap()[0].id;
+        drop(index);
+        let server = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(ServerState::new(
+                    StorageServer::initialize(&test_index_path)
+                        .expect("expect loading index to succeed"),
+                )))
+                .route("/file/{id}", web::patch().to(update_file)),
+        )
+        .await;
+        let file_uri = format!("/file/{id}");
+
+        let request = test::TestRequest::patch()
+            .uri(&file_uri)
+            .set_json(FileUpdate {
+                name: Some("renamed.txt".to_string()),
+                directory: None,
+            })
+            .to_request();
+        let file: FileData = test::call_and_read_body_json(&server, request).await;
+        assert_eq!(file.name, "renamed.txt");
+        assert_eq!(file.tags, vec!["a"]);
+        assert!(test_dir.join("a/renamed.txt").exists());
+
+        let request = test::TestRequest::patch()
+            .uri(&file_uri)
+            .set_json(FileUpdate {
+                name: None,
+                directory: Some("c/d".to_string()),
+            })
+            .to_request();
+        let file: FileData = test::call_and_read_body_json(&server, request).await;
+        assert_eq!(file.id, id.to_string());
+        assert_eq!(file.tags, vec!["c", "d"]);
+        assert!(!test_dir.join("a/renamed.txt").exists());
+        assert!(test_dir.join("c/d/renamed.txt").exists());
+
+        let request = test::TestRequest::patch()
+            .uri(&file_uri)
+            .set_json(FileUpdate {
+                name: Some("2.txt".to_string()),
+                directory: Some("b".to_string()),
+            })
+            .to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let request = test::TestRequest::patch()
+            .uri(&file_uri)
+            .set_json(FileUpdate {
+                name: Some("../escaped.txt".to_string()),
+                directory: None,
+            })
+            .to_request();
+        let response = test::call_service(&server, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(test_dir.join("c/d/renamed.txt").exists());
+
+        std::fs::remove_dir_all(test_dir).expect("expect cleaning test dir to succeed");
+        std::fs::remove_file(backup_path(&test_index_path))
+            .expect("expect cleaning test index backup to succeed");
+        std::fs::remove_file(test_index_path).expect("expect cleaning test index file to succeed");
+    }
+
+    #[actix_web::test]
+    async fn can_tag_files() {
+        initialize();
+        let test_index_path = PathBuf::from("./tag_files_test.json");
+        let files = vec![
+            FileMetadata {
+                name: "1.txt".to_string(),
+                id: 1,
+                ty: "txt".to_string(),
+                path: PathBuf::from("./dummy-file/1.txt"),
+                tags: Some(vec!["tag1".to_string()]),
+                ..Default::default()
+            },
+            FileMetadata {
+                name: "2.mp4".to_string(),
+                id: 2,
+                ty: "mp4".to_string(),
+                path: PathBuf::from("./dummy-file/2.mp4"),
+                tags: Some(vec!["tag1".to_string()]),
+                ..Default::default()
+            },
+            FileMetadata {
+                name: "3.mp4".to_string(),
+                id: 3,
+                ty: "mp4".to_string(),
+                path: PathBuf::from("./dummy-file/3.mp4"),
+                tags: None,
+                ..Default::default()
+            },
+        ];
+        let body = serde_json::to_string_pretty(&files).unwrap();
+        std::fs::write(&test_index_path, body).expect("expect creating index file to succeed");
+        let server = test::init_service(
+            App::new()
+                .app_data(actix_web::web::Data::new(ServerState::new(
+                    StorageServer::initialize(&test_index_path)
+                        .expect("expect loading index to succeed"),
+                )))
+                .route("/tags", web::get().to(get_tags))
+                .route("/query", web::post().to(get_files_by_tags))
+                .route("/file/{id}/tags", web::post().to(add_tags))
+                .route("/file/{id}/tags", web::delete().to(remove_tags))
+                .route("/query/tags", web::post().to(update_tags_of_query)),
+        )
+        .await;
+
+        let request = test::TestRequest::post()
+            .uri("/file/3/tags")
+            .set_json(TagList {
+                tags: vec!["holiday".to_string(), "family".to_string()],
+            })
+            .to_request();
+        let file: FileData = test::call_and_read_body_json(&server, request).await;
+        assert_eq!(file.tags, vec!["holiday", "family"]);
+        let request = test::TestRequest::delete()
+            .uri("/file/3/tags")
+            .set_json(TagList {
+                tags: vec!["family".to_string()],
+            })
+            .to_request();
+        let file: FileData = test::call_and_read_body_json(&server, request).await;
+        assert_eq!(file.user_tags, vec!["holiday"]);
+
+        let request = test::TestRequest::post()
+            .uri("/query/tags")
+            .set_json(TagQueryUpdate {
+                data: TagQueryData {
+                    ty: "mp4".to_string(),
+                    tags: vec!["tag1".to_string()],
+                },
+                add: vec!["video".to_string()],
+                remove: vec![],
+            })
+            .to_request();
+        let files: Vec<FileData> = test::call_and_read_body_json(&server, request).await;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].tags, vec!["tag1", "video"]);
+
+        let request = test::TestRequest::get().uri("/tags").to_request();
+        let mut tags: Vec<String> = test::call_and_read_body_json(&server, request).await;
+        tags.sort();
+        assert_eq!(tags, vec!["holiday", "tag1", "video"]);
+        let request = test::TestRequest::post()
+            .uri("/query")
+            .set_json(TagQuery {
+                data: TagQueryData {
+                    ty: "".to_string(),
+                    tags: vec!["holiday".to_string()],
+                },
+            })
+            .to_request();
+        let files: Vec<FileData> = test::call_and_read_body_json(&server, request).await;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "3.mp4");
+
+        std::fs::remove_file(backup_path(&test_index_path))
+            .expect("expect cleaning test index backup to succeed");
         std::fs::remove_file(test_index_path).expect("expect cleaning test index file to succeed");
     }
+
     // every test properly creating the content dir
+    /// Serve a placeholder client from a temporary directory instead of the repository's client
+    /// content, so that running the tests doesn't leave files in the working tree.
     fn initialize() {
         INIT.call_once(|| {
-            let content_path = PathBuf::from(env::var("PEA_CLIENT_CONTENT_DIR").unwrap());
+            let content_path =
+                env::temp_dir().join(format!("pea-client-content-{}", std::process::id()));
             let path = &content_path;
             clean_up_dir(path).expect("expect creating content dir to succeed");
+            env::set_var("PEA_CLIENT_CONTENT_DIR", path);
             let mut index_file = File::create(path.join("./index.html"))
                 .expect("expect creating index.html to succeed");
             index_file
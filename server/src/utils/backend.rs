@@ -0,0 +1,599 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, error, warn};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+
+use super::{
+    config::ContentRoot,
+    storage::{FileErr, FileMetadata},
+};
+
+/// Persistent storage used by `FileIndex` to keep file metadata.
+pub trait IndexBackend: Send {
+    fn files(&self) -> Result<Vec<FileMetadata>, FileErr>;
+    fn get(&self, id: u64) -> Result<Option<FileMetadata>, FileErr>;
+    fn file_at(&self, path: &Path) -> Result<Option<FileMetadata>, FileErr>;
+    /// The file at the path and the files below it if it is a directory.
+    fn files_under(&self, path: &Path) -> Result<Vec<FileMetadata>, FileErr>;
+    fn files_of_type(&self, ty: &str) -> Result<Vec<FileMetadata>, FileErr>;
+    fn files_of_tags(&self, tags: &[String]) -> Result<Vec<FileMetadata>, FileErr>;
+    fn tags(&self) -> Result<Vec<String>, FileErr>;
+    fn insert(&mut self, file: FileMetadata) -> Result<(), FileErr>;
+    /// Insert or replace `upserts` and delete the files with ids in `removals` as a single change.
+    /// Either every change is stored or none of them are.
+    fn update_batch(&mut self, upserts: Vec<FileMetadata>, removals: &[u64])
+        -> Result<(), FileErr>;
+
+    fn insert_batch(&mut self, files: Vec<FileMetadata>) -> Result<(), FileErr> {
+        self.update_batch(files, &[])
+    }
+
+    fn roots(&self) -> Result<Vec<ContentRoot>, FileErr>;
+    /// Insert the root or replace the root with the same name.
+    fn set_root(&mut self, root: ContentRoot) -> Result<(), FileErr>;
+    fn remove_root(&mut self, name: &str) -> Result<(), FileErr>;
+
+    /// Flush outstanding writes and release the index. Changes are stored as they are made, so
+    /// there is nothing left to do by default.
+    fn close(self: Box<Self>) -> Result<(), FileErr> {
+        Ok(())
+    }
+}
+
+/// Pick the backend based on the extension of the index file. `.db`, `.sqlite` and `.sqlite3`
+/// files are opened as SQLite databases, everything else is treated as a JSON index.
+pub fn open_backend(index_file: &Path) -> Result<Box<dyn IndexBackend>, FileErr> {
+    if is_sqlite_file(index_file) {
+        Ok(Box::new(SqliteBackend::open(index_file)?))
+    } else {
+        Ok(Box::new(JsonBackend::open(index_file)?))
+    }
+}
+
+fn is_sqlite_file(path: &Path) -> bool {
+    match path.extension() {
+        Some(extension) => matches!(
+            extension.to_string_lossy().as_ref(),
+            "db" | "sqlite" | "sqlite3"
+        ),
+        None => false,
+    }
+}
+
+type FileDB = HashMap<u64, FileMetadata>;
+
+const JSON_INDEX_VERSION: u32 = 1;
+
+/// Content of a JSON index file. Indexes written before content roots were added only contain
+/// the array of files.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(untagged)]
+enum IndexFile {
+    Versioned {
+        version: u32,
+        #[serde(default)]
+        roots: Vec<ContentRoot>,
+        files: Vec<FileMetadata>,
+    },
+    Legacy(Vec<FileMetadata>),
+}
+
+/// Keeps the whole index in memory and rewrites the JSON index file on every change. See
+/// `write_atomically` for how writes are kept crash safe.
+pub struct JsonBackend {
+    index_file: PathBuf,
+    db: FileDB,
+    roots: Vec<ContentRoot>,
+}
+
+impl JsonBackend {
+    pub fn open(index_file: &Path) -> Result<Self, FileErr> {
+        let (roots, files) = match load_index(index_file)? {
+            IndexFile::Versioned { roots, files, .. } => (roots, files),
+            IndexFile::Legacy(files) => (Vec::new(), files),
+        };
+        Ok(Self {
+            db: files.into_iter().map(|each| (each.id, each)).collect(),
+            roots,
+            index_file: index_file.to_path_buf(),
+        })
+    }
+
+    /// Apply the change to a copy of the index and only keep it once it is stored, so the index
+    /// in memory always matches the index file.
+    fn change(
+        &mut self,
+        change: impl FnOnce(&mut FileDB, &mut Vec<ContentRoot>),
+    ) -> Result<(), FileErr> {
+        let mut db = self.db.clone();
+        let mut roots = self.roots.clone();
+        change(&mut db, &mut roots);
+        let index = IndexFile::Versioned {
+            version: JSON_INDEX_VERSION,
+            roots: roots.clone(),
+            files: db.values().cloned().collect(),
+        };
+        serialize_index(&self.index_file, &index)?;
+        self.db = db;
+        self.roots = roots;
+        Ok(())
+    }
+}
+
+impl IndexBackend for JsonBackend {
+    fn files(&self) -> Result<Vec<FileMetadata>, FileErr> {
+        Ok(self.db.values().cloned().collect())
+    }
+
+    fn get(&self, id: u64) -> Result<Option<FileMetadata>, FileErr> {
+        Ok(self.db.get(&id).cloned())
+    }
+
+    fn file_at(&self, path: &Path) -> Result<Option<FileMetadata>, FileErr> {
+        Ok(self.db.values().find(|each| each.path == path).cloned())
+    }
+
+    fn files_under(&self, path: &Path) -> Result<Vec<FileMetadata>, FileErr> {
+        Ok(self
+            .db
+            .values()
+            .filter(|each| each.path.starts_with(path))
+            .cloned()
+            .collect())
+    }
+
+    fn files_of_type(&self, ty: &str) -> Result<Vec<FileMetadata>, FileErr> {
+        Ok(self
+            .db
+            .values()
+            .filter(|each| each.ty == ty)
+            .cloned()
+            .collect())
+    }
+
+    fn files_of_tags(&self, tags: &[String]) -> Result<Vec<FileMetadata>, FileErr> {
+        Ok(self
+            .db
+            .values()
+            .filter(|each| has_tags(each, tags))
+            .cloned()
+            .collect())
+    }
+
+    fn tags(&self) -> Result<Vec<String>, FileErr> {
+        Ok(distinct_tags(self.db.values()))
+    }
+
+    fn insert(&mut self, file: FileMetadata) -> Result<(), FileErr> {
+        self.change(|db, _| {
+            db.insert(file.id, file);
+        })
+    }
+
+    fn update_batch(
+        &mut self,
+        upserts: Vec<FileMetadata>,
+        removals: &[u64],
+    ) -> Result<(), FileErr> {
+        self.change(|db, _| {
+            for id in removals {
+                db.remove(id);
+            }
+            for each in upserts {
+                db.insert(each.id, each);
+            }
+        })
+    }
+
+    fn roots(&self) -> Result<Vec<ContentRoot>, FileErr> {
+        Ok(self.roots.clone())
+    }
+
+    fn set_root(&mut self, root: ContentRoot) -> Result<(), FileErr> {
+        self.change(|_, roots| {
+            roots.retain(|each| each.name != root.name);
+            roots.push(root);
+        })
+    }
+
+    fn remove_root(&mut self, name: &str) -> Result<(), FileErr> {
+        self.change(|_, roots| roots.retain(|each| each.name != name))
+    }
+}
+
+/// Whether the file has every one of the tags. Files without any tags never match.
+pub(crate) fn has_tags(file: &FileMetadata, tags: &[String]) -> bool {
+    let file_tags = file.all_tags();
+    !file_tags.is_empty() && tags.iter().all(|tag| file_tags.contains(tag))
+}
+
+pub(crate) fn distinct_tags<'a>(files: impl Iterator<Item = &'a FileMetadata>) -> Vec<String> {
+    let tags: HashSet<String> = files.flat_map(|each| each.all_tags()).collect();
+    tags.into_iter().collect()
+}
+
+/// Load the index at `path`, recovering from an interrupted or corrupted write when possible.
+///
+/// Index writes first go to a journal file which is renamed over the index once it is fully
+/// written, and the previous index is kept as a backup. If the index itself can't be read we fall
+/// back to the journal (a write that finished but wasn't renamed yet) and then to the backup.
+fn load_index(path: &Path) -> Result<IndexFile, FileErr> {
+    let err = match read_index_file(path) {
+        Ok(index) => {
+            remove_stale_journal(path);
+            return Ok(index);
+        }
+        // an index that exists but can't be read must not be replaced by a recovered one
+        Err(err @ FileErr::Io { .. }) => {
+            error!("failed to read index file {path:?} due to {err}");
+            return Err(err);
+        }
+        Err(err) => err,
+    };
+    let journal = journal_path(path);
+    let backup = backup_path(path);
+    if matches!(err, FileErr::IndexDoesNotExist) && !journal.exists() && !backup.exists() {
+        debug!("empty index");
+        return Ok(IndexFile::Legacy(Vec::new()));
+    }
+    warn!("index file {path:?} is not readable ({err}), trying to recover");
+    for candidate in [journal, backup] {
+        if let Ok(index) = read_index_file(&candidate) {
+            warn!("restoring index {path:?} from {candidate:?}");
+            serialize_index(path, &index)?;
+            return Ok(index);
+        }
+    }
+    error!("failed to recover index file {path:?}");
+    Err(FileErr::IndexInvalid)
+}
+
+fn read_index_file(path: &Path) -> Result<IndexFile, FileErr> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => match serde_json::from_str::<IndexFile>(&content) {
+            Ok(res) => Ok(res),
+            Err(err) => {
+                error!("failed to parse index file {path:?} due to {err:?}");
+                Err(FileErr::IndexInvalid)
+            }
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Err(FileErr::IndexDoesNotExist),
+        Err(err) => Err(FileErr::io(path, &err)),
+    }
+}
+
+fn remove_stale_journal(path: &Path) {
+    let journal = journal_path(path);
+    if journal.exists() {
+        debug!("removing stale index journal {journal:?}");
+        if let Err(err) = std::fs::remove_file(&journal) {
+            warn!("failed to remove stale index journal {journal:?} due to {err:?}");
+        }
+    }
+}
+
+fn serialize_index(path: &Path, index: &IndexFile) -> Result<(), FileErr> {
+    match serde_json::to_string_pretty(index) {
+        Ok(body) => write_atomically(path, body.as_bytes()),
+        Err(err) => {
+            error!("db serialization failed due to {err:?}");
+            Err(FileErr::DBError)
+        }
+    }
+}
+
+/// Replace the content of `path` such that a crash at any point leaves either the old or the new
+/// content on disk. The previous content of `path` is kept as a backup next to it.
+pub fn write_atomically(path: &Path, content: &[u8]) -> Result<(), FileErr> {
+    let parent_dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    if let Err(err) = std::fs::create_dir_all(&parent_dir) {
+        error!("failed to create dir: {parent_dir:?} to store index file due to {err:?}");
+        return Err(FileErr::io(&parent_dir, &err));
+    }
+    let journal = journal_path(path);
+    let result = File::create(&journal)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            file.sync_all()
+        })
+        .and_then(|_| {
+            if path.exists() {
+                backup(path)?;
+            }
+            std::fs::rename(&journal, path)
+        })
+        .and_then(|_| File::open(&parent_dir)?.sync_all());
+    result.map_err(|err| {
+        error!("failed to write index file {path:?} due to {err:?}");
+        FileErr::io(path, &err)
+    })
+}
+
+fn backup(path: &Path) -> std::io::Result<()> {
+    let backup = backup_path(path);
+    if backup.exists() {
+        std::fs::remove_file(&backup)?;
+    }
+    if std::fs::hard_link(path, &backup).is_err() {
+        std::fs::copy(path, &backup)?;
+    }
+    Ok(())
+}
+
+fn journal_path(path: &Path) -> PathBuf {
+    path_with_suffix(path, "journal")
+}
+
+pub fn backup_path(path: &Path) -> PathBuf {
+    path_with_suffix(path, "bak")
+}
+
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Stores the index in an embedded SQLite database. Each file is a row keyed by its id, with the
+/// type and tags kept in indexed columns so lookups don't need to scan the whole index. The full
+/// metadata is kept as JSON in the `data` column.
+pub struct SqliteBackend {
+    connection: Connection,
+}
+
+const SQLITE_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS files (
+        id   INTEGER PRIMARY KEY,
+        ty   TEXT NOT NULL,
+        path TEXT NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS files_ty ON files(ty);
+    CREATE INDEX IF NOT EXISTS files_path ON files(path);
+    CREATE TABLE IF NOT EXISTS file_tags (
+        file_id INTEGER NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+        tag     TEXT NOT NULL,
+        PRIMARY KEY (file_id, tag)
+    );
+    CREATE INDEX IF NOT EXISTS file_tags_tag ON file_tags(tag);
+    CREATE TABLE IF NOT EXISTS roots (
+        name TEXT PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+";
+
+impl SqliteBackend {
+    pub fn open(index_file: &Path) -> Result<Self, FileErr> {
+        if let Some(parent_dir) = index_file.parent() {
+            if let Err(err) = std::fs::create_dir_all(parent_dir) {
+                error!("failed to create dir: {parent_dir:?} to store index file due to {err:?}");
+                return Err(FileErr::DBError);
+            }
+        }
+        let connection = Connection::open(index_file).map_err(sqlite_error)?;
+        connection
+            .execute_batch("PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;")
+            .map_err(sqlite_error)?;
+        connection
+            .execute_batch(SQLITE_SCHEMA)
+            .map_err(sqlite_error)?;
+        Ok(Self { connection })
+    }
+
+    fn query_files(
+        &self,
+        sql: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<FileMetadata>, FileErr> {
+        let mut statement = self.connection.prepare(sql).map_err(sqlite_error)?;
+        let rows = statement
+            .query_map(params, |row| row.get::<_, String>(0))
+            .map_err(sqlite_error)?;
+        let mut files = Vec::new();
+        for row in rows {
+            files.push(parse_row(&row.map_err(sqlite_error)?)?);
+        }
+        Ok(files)
+    }
+}
+
+impl IndexBackend for SqliteBackend {
+    fn files(&self) -> Result<Vec<FileMetadata>, FileErr> {
+        self.query_files("SELECT data FROM files", [])
+    }
+
+    fn get(&self, id: u64) -> Result<Option<FileMetadata>, FileErr> {
+        let data = self
+            .connection
+            .query_row("SELECT data FROM files WHERE id = ?1", [id as i64], |row| {
+                row.get::<_, String>(0)
+            })
+            .optional()
+            .map_err(sqlite_error)?;
+        data.map(|data| parse_row(&data)).transpose()
+    }
+
+    fn file_at(&self, path: &Path) -> Result<Option<FileMetadata>, FileErr> {
+        // the path column is lossy, so files with similar non UTF-8 paths may match too
+        let files = self.query_files(
+            "SELECT data FROM files WHERE path = ?1",
+            [path.to_string_lossy()],
+        )?;
+        Ok(files.into_iter().find(|each| each.path == path))
+    }
+
+    fn files_under(&self, path: &Path) -> Result<Vec<FileMetadata>, FileErr> {
+        // paths below the directory sort between `{path}/` and `{path}0`, so the range can be
+        // looked up in the path index
+        let path_lossy = path.to_string_lossy();
+        let dir = path_lossy.trim_end_matches('/');
+        let files = self.query_files(
+            "SELECT data FROM files WHERE path = ?1 OR (path >= ?2 AND path < ?3)",
+            params![path_lossy, format!("{dir}/"), format!("{dir}0")],
+        )?;
+        Ok(files
+            .into_iter()
+            .filter(|each| each.path.starts_with(path))
+            .collect())
+    }
+
+    fn files_of_type(&self, ty: &str) -> Result<Vec<FileMetadata>, FileErr> {
+        self.query_files("SELECT data FROM files WHERE ty = ?1", [ty])
+    }
+
+    fn files_of_tags(&self, tags: &[String]) -> Result<Vec<FileMetadata>, FileErr> {
+        if tags.is_empty() {
+            return self.query_files(
+                "SELECT data FROM files WHERE id IN (SELECT file_id FROM file_tags)",
+                [],
+            );
+        }
+        let placeholders = vec!["?"; tags.len()].join(", ");
+        let sql = format!(
+            "SELECT data FROM files WHERE id IN (
+                SELECT file_id FROM file_tags WHERE tag IN ({placeholders})
+                GROUP BY file_id HAVING COUNT(DISTINCT tag) = {}
+            )",
+            tags.len()
+        );
+        self.query_files(&sql, params_from_iter(tags.iter()))
+    }
+
+    fn tags(&self) -> Result<Vec<String>, FileErr> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT DISTINCT tag FROM file_tags")
+            .map_err(sqlite_error)?;
+        let rows = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(sqlite_error)?;
+        rows.collect::<Result<Vec<String>, _>>()
+            .map_err(sqlite_error)
+    }
+
+    fn insert(&mut self, file: FileMetadata) -> Result<(), FileErr> {
+        let transaction = self.connection.transaction().map_err(sqlite_error)?;
+        insert_row(&transaction, &file)?;
+        transaction.commit().map_err(sqlite_error)
+    }
+
+    fn update_batch(
+        &mut self,
+        upserts: Vec<FileMetadata>,
+        removals: &[u64],
+    ) -> Result<(), FileErr> {
+        let transaction = self.connection.transaction().map_err(sqlite_error)?;
+        for id in removals {
+            transaction
+                .execute("DELETE FROM files WHERE id = ?1", [*id as i64])
+                .map_err(sqlite_error)?;
+        }
+        for each in &upserts {
+            insert_row(&transaction, each)?;
+        }
+        transaction.commit().map_err(sqlite_error)
+    }
+
+    fn roots(&self) -> Result<Vec<ContentRoot>, FileErr> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT data FROM roots")
+            .map_err(sqlite_error)?;
+        let rows = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(sqlite_error)?;
+        let mut roots = Vec::new();
+        for row in rows {
+            let data = row.map_err(sqlite_error)?;
+            match serde_json::from_str(&data) {
+                Ok(root) => roots.push(root),
+                Err(err) => {
+                    error!("failed to parse content root {data} due to {err:?}");
+                    return Err(FileErr::IndexInvalid);
+                }
+            }
+        }
+        Ok(roots)
+    }
+
+    fn set_root(&mut self, root: ContentRoot) -> Result<(), FileErr> {
+        let data = match serde_json::to_string(&root) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("failed to serialize {root:?} due to {err:?}");
+                return Err(FileErr::DBError);
+            }
+        };
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO roots (name, data) VALUES (?1, ?2)",
+                params![root.name, data],
+            )
+            .map_err(sqlite_error)?;
+        Ok(())
+    }
+
+    fn remove_root(&mut self, name: &str) -> Result<(), FileErr> {
+        self.connection
+            .execute("DELETE FROM roots WHERE name = ?1", [name])
+            .map_err(sqlite_error)?;
+        Ok(())
+    }
+
+    /// Closing checkpoints the write ahead log into the database file.
+    fn close(self: Box<Self>) -> Result<(), FileErr> {
+        self.connection
+            .close()
+            .map_err(|(_, err)| sqlite_error(err))
+    }
+}
+
+fn insert_row(connection: &Connection, file: &FileMetadata) -> Result<(), FileErr> {
+    let data = match serde_json::to_string(file) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("failed to serialize {file:?} due to {err:?}");
+            return Err(FileErr::DBError);
+        }
+    };
+    let id = file.id as i64;
+    connection
+        .execute(
+            "INSERT OR REPLACE INTO files (id, ty, path, data) VALUES (?1, ?2, ?3, ?4)",
+            params![id, file.ty, file.path.to_string_lossy(), data],
+        )
+        .map_err(sqlite_error)?;
+    connection
+        .execute("DELETE FROM file_tags WHERE file_id = ?1", [id])
+        .map_err(sqlite_error)?;
+    for tag in file.all_tags() {
+        connection
+            .execute(
+                "INSERT OR IGNORE INTO file_tags (file_id, tag) VALUES (?1, ?2)",
+                params![id, tag],
+            )
+            .map_err(sqlite_error)?;
+    }
+    Ok(())
+}
+
+fn parse_row(data: &str) -> Result<FileMetadata, FileErr> {
+    serde_json::from_str(data).map_err(|err| {
+        error!("failed to parse index row due to {err:?}");
+        FileErr::IndexInvalid
+    })
+}
+
+fn sqlite_error(err: rusqlite::Error) -> FileErr {
+    error!("sqlite index operation failed due to {err:?}");
+    FileErr::DBError
+}
@@ -0,0 +1,142 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use log::error;
+
+use super::storage::FileErr;
+
+/// Optional server configuration, read from the JSON file at `PEA_CONFIG_FILE`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct ServerConfig {
+    #[serde(default)]
+    pub indexing: IndexingConfig,
+    /// Registered in the index when the server starts, replacing roots with the same name. Roots
+    /// removed from the configuration while the server runs are unregistered when it is reloaded.
+    #[serde(default)]
+    pub roots: Vec<ContentRoot>,
+    #[serde(default)]
+    pub uploads: UploadConfig,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone)]
+pub struct UploadConfig {
+    /// Uploads larger than this many bytes are rejected.
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// Seconds after which resumable uploads that weren't completed are discarded.
+    #[serde(default = "default_upload_expiry")]
+    pub expire_after: u64,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            expire_after: default_upload_expiry(),
+        }
+    }
+}
+
+fn default_upload_expiry() -> u64 {
+    24 * 60 * 60
+}
+
+/// A named directory whose files are indexed.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct ContentRoot {
+    pub name: String,
+    pub path: PathBuf,
+    /// Files in read only roots can't be uploaded, renamed, moved or deleted.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Tag given to every file in the root, in front of the tags derived from its directories.
+    #[serde(default)]
+    pub tag_prefix: Option<String>,
+    /// Replaces the global indexing rules for the files in this root.
+    #[serde(default)]
+    pub indexing: Option<IndexingConfig>,
+}
+
+/// Which files under the content roots get indexed, on top of the `.peaignore` files.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone)]
+pub struct IndexingConfig {
+    /// Glob patterns of the files to index, all files if empty.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Glob patterns of the files and directories to skip, on top of encrypted files and the
+    /// metadata files created by macOS, which are never indexed.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Files larger than this many bytes are skipped.
+    #[serde(default)]
+    pub max_file_size: Option<u64>,
+    /// Index files and directories whose names start with a dot.
+    #[serde(default = "default_index_hidden")]
+    pub index_hidden: bool,
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    /// Directories nested deeper than this below the root are skipped.
+    #[serde(default = "default_max_depth")]
+    pub max_depth: usize,
+}
+
+/// How symbolic links found while indexing are handled.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Skip the links.
+    Ignore,
+    /// Index the files the links point to, unless they are already indexed through another path.
+    #[default]
+    Follow,
+    /// Index the links themselves without following them.
+    Record,
+}
+
+impl Default for IndexingConfig {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            max_file_size: None,
+            index_hidden: default_index_hidden(),
+            symlinks: SymlinkPolicy::default(),
+            max_depth: default_max_depth(),
+        }
+    }
+}
+
+fn default_index_hidden() -> bool {
+    true
+}
+
+fn default_max_depth() -> usize {
+    64
+}
+
+impl ServerConfig {
+    pub fn load() -> Result<Self, FileErr> {
+        match env::var("PEA_CONFIG_FILE") {
+            Ok(path) => Self::from_file(Path::new(&path)),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// The configuration in the file, which has to exist since falling back to the default would
+    /// e.g. unregister every content root when reloading.
+    pub fn from_file(path: &Path) -> Result<Self, FileErr> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) => {
+                error!("failed to read config file {path:?} due to {err}");
+                return Err(FileErr::io(path, &err));
+            }
+        };
+        serde_json::from_str(&content).map_err(|err| {
+            error!("failed to parse config file {path:?} due to {err:?}");
+            FileErr::InvalidConfig
+        })
+    }
+}
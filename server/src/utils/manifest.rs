@@ -0,0 +1,54 @@
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{backend::write_atomically, storage::FileErr};
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Read the entries of the manifest in the server managed directory, e.g. the trash. A directory
+/// without a manifest has no entries yet.
+pub fn load<T: DeserializeOwned>(dir: &Path) -> Result<Vec<T>, FileErr> {
+    let manifest = dir.join(MANIFEST_FILE);
+    match std::fs::read_to_string(&manifest) {
+        Ok(content) => serde_json::from_str(&content).map_err(|err| {
+            error!("failed to parse manifest {manifest:?} due to {err:?}");
+            FileErr::IndexInvalid
+        }),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            debug!("no manifest in {dir:?}");
+            Ok(Vec::new())
+        }
+        Err(err) => {
+            error!("failed to read manifest {manifest:?} due to {err}");
+            Err(FileErr::io(&manifest, &err))
+        }
+    }
+}
+
+/// Replace the manifest in the directory with the entries, see `write_atomically`.
+pub fn save<T: Serialize>(dir: &Path, entries: &[&T]) -> Result<(), FileErr> {
+    match serde_json::to_string_pretty(entries) {
+        Ok(body) => write_atomically(&dir.join(MANIFEST_FILE), body.as_bytes()),
+        Err(err) => {
+            error!("manifest serialization for {dir:?} failed due to {err:?}");
+            Err(FileErr::DBError)
+        }
+    }
+}
+
+/// Seconds since the unix epoch, or `None` for earlier times.
+pub fn unix_seconds(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|duration| duration.as_secs())
+}
+
+/// The current time in seconds since the unix epoch, which manifest entries are timed with.
+pub fn now() -> u64 {
+    unix_seconds(SystemTime::now()).unwrap_or_default()
+}
@@ -1,5 +1,15 @@
+pub mod backend;
+pub mod config;
+pub mod manifest;
+pub mod query;
 pub mod registry;
+pub mod rules;
+pub mod snapshot;
 pub mod storage;
+pub mod systemd;
+pub mod trash;
+pub mod uploads;
+pub mod watcher;
 
 pub fn get_local_ip_address() -> std::net::IpAddr {
     match local_ip_address::local_ip() {
@@ -21,20 +31,42 @@ pub fn get_local_ip_address() -> std::net::IpAddr {
 #[cfg(test)]
 mod tests {
     use std::{
-        collections::{hash_map::DefaultHasher, HashMap},
-        fs::{self, remove_dir_all, File},
-        hash::{Hash, Hasher},
+        collections::HashMap,
+        env,
+        fs::{self, remove_dir_all},
+        os::unix::net::UnixDatagram,
         path::{Path, PathBuf},
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
     };
+    #[cfg(target_os = "linux")]
+    use std::{
+        ffi::OsStr,
+        os::{linux::net::SocketAddrExt, unix::net::SocketAddr},
+    };
+
+    use tokio::sync::oneshot;
 
     use crate::utils::{
         get_local_ip_address,
-        storage::{clean_up_dir, FileMetadata},
+        storage::{clean_up_dir, create_file, id_of_hash, FileMetadata},
     };
 
     use super::{
+        backend::{backup_path, open_backend},
+        config::{ContentRoot, IndexingConfig, ServerConfig, SymlinkPolicy},
+        query::{parse, Query},
         registry::{register_server, unregister_server, RegistryData},
-        storage::FileIndex,
+        rules::IndexRules,
+        snapshot::IndexSnapshot,
+        storage::{
+            delete_file, finish_upload, restore_file, start_upload, ConflictPolicy, FileErr,
+            FileIndex, FileUpdate, Message, RescanReport, StorageHandle, StorageServer, TagUpdate,
+            Upload, UploadOptions,
+        },
+        systemd::notify_socket,
+        trash::Trash,
+        uploads::PendingUploads,
+        watcher::watch_roots,
     };
     #[test]
     fn test_get_local_ip() {
@@ -62,26 +94,54 @@ mod tests {
         let test_storage = &PathBuf::from("./libtest_2");
         let index_path = &PathBuf::from("./libtest_2.index");
         initialize_storage(test_storage);
+        let indexing_started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
         let index = index_for_dir(index_path, test_storage);
-        let mut metadata = index.files();
-        let files = ["1.mp4", "2.jpg", "3.mkv", "6.txt"];
+        let mut metadata = index.files().unwrap();
+        // the type of files without an extension is inferred from their content
+        let files = [
+            ("1.mp4", "mp4", "video/mp4"),
+            ("2.jpg", "jpg", "image/jpeg"),
+            ("3.mkv", "mkv", "video/x-matroska"),
+            ("6.txt", "txt", "text/plain"),
+            (".no_name", "txt", "text/plain"),
+            ("no_ext", "txt", "text/plain"),
+        ];
+        for file in &mut metadata {
+            assert!(file.indexed_at.unwrap() >= indexing_started);
+            file.indexed_at = None;
+        }
         let mut expected: Vec<FileMetadata> = files
             .into_iter()
-            .map(|name| {
+            .map(|(name, ty, mime)| {
                 let path = std::fs::canonicalize(test_storage.join(name)).unwrap();
-                let mut hasher = DefaultHasher::new();
-                path.hash(&mut hasher);
-                let id = hasher.finish();
-                let ty = path
-                    .extension()
-                    .expect("expect valid file extension")
-                    .to_string_lossy();
+                let hash = blake3::hash(format!("./{name}").as_bytes());
+                let id = u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap());
                 FileMetadata {
                     name: name.to_string(),
                     id,
                     ty: ty.to_string(),
                     path,
                     tags: None,
+                    user_tags: Vec::new(),
+                    hash: hash.to_hex().to_string(),
+                    size: format!("./{name}").len() as u64,
+                    modified: fs::metadata(test_storage.join(name))
+                        .and_then(|metadata| metadata.modified())
+                        .ok()
+                        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                        .map(|duration| duration.as_secs()),
+                    created: fs::metadata(test_storage.join(name))
+                        .and_then(|metadata| metadata.created())
+                        .ok()
+                        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                        .map(|duration| duration.as_secs()),
+                    indexed_at: None,
+                    mime: mime.to_string(),
+                    root: String::new(),
+                    link: None,
                 }
             })
             .collect();
@@ -98,7 +158,7 @@ mod tests {
         initialize_storage(test_storage);
         clean_up_dir(test_storage).expect("expect cleanup up directory to succeed");
         let index = index_for_dir(index_path, test_storage);
-        let metadata = index.files();
+        let metadata = index.files().unwrap();
         assert_eq!(metadata, []);
         cleanup_storage(index_path, test_storage);
     }
@@ -107,7 +167,7 @@ mod tests {
     fn test_file_tagging() {
         let test_storage = &PathBuf::from("./libtest_4");
         let index_path = &PathBuf::from("./libtest_4.index");
-        let file_tags = vec![
+        let file_tags = [
             ("./libtest_4/1.mp4", None),
             ("./libtest_4/a/2.mp4", Some(vec!["a".to_string()])),
             (
@@ -130,12 +190,12 @@ mod tests {
             })
             .collect();
         let file_index = index_for_dir(index_path, test_storage);
-        file_index.files().iter().for_each(|file| {
+        file_index.files().unwrap().iter().for_each(|file| {
             let tags = &file.tags;
             let expected = expected_tags.get(&file.path).unwrap();
             assert_eq!(tags, expected);
         });
-        assert!(file_index.files().len() == expected_tags.len());
+        assert!(file_index.files().unwrap().len() == expected_tags.len());
         cleanup_storage(index_path, test_storage);
     }
 
@@ -153,7 +213,7 @@ mod tests {
             create_nested_file(Path::new(each));
         }
         let file_index = index_for_dir(index_path, test_storage);
-        let mut sorted_tags = file_index.tags();
+        let mut sorted_tags = file_index.tags().unwrap();
         sorted_tags.sort_unstable();
         let expected = vec!["a".to_string(), "b".to_string(), "c".to_string()];
         assert_eq!(sorted_tags, expected);
@@ -176,7 +236,8 @@ mod tests {
         let file_index = index_for_dir(index_path, test_storage);
 
         let mut res_1_names = file_index
-            .files_of_tags(&vec!["a".to_string(), "b".to_string()])
+            .files_of_tags(&["a".to_string(), "b".to_string()])
+            .unwrap()
             .iter()
             .map(|file| file.name.clone())
             .collect::<Vec<String>>();
@@ -184,7 +245,8 @@ mod tests {
         assert_eq!(res_1_names, vec![String::from("3.mp4")]);
 
         let mut res_2_names = file_index
-            .files_of_tags(&vec!["b".to_string()])
+            .files_of_tags(&["b".to_string()])
+            .unwrap()
             .iter()
             .map(|file| file.name.clone())
             .collect::<Vec<String>>();
@@ -197,6 +259,1440 @@ mod tests {
         cleanup_storage(index_path, test_storage);
     }
 
+    #[test]
+    fn test_sqlite_index() {
+        let test_storage = &PathBuf::from("./libtest_7");
+        let index_path = &PathBuf::from("./libtest_7.db");
+        let files = vec![
+            "./libtest_7/1.mp4",
+            "./libtest_7/a/2.jpg",
+            "./libtest_7/a/b/3.mp4",
+            "./libtest_7/c/b/4.mp4",
+        ];
+        for file in files {
+            create_nested_file(Path::new(file));
+        }
+        let file_index = index_for_dir(index_path, test_storage);
+        drop(file_index);
+
+        let file_index = FileIndex::new(index_path).expect("expect loading index to succeed");
+        assert_eq!(file_index.files().unwrap().len(), 4);
+        let mut sorted_tags = file_index.tags().unwrap();
+        sorted_tags.sort_unstable();
+        assert_eq!(sorted_tags, vec!["a", "b", "c"]);
+
+        let mut res_names = file_index
+            .files_of_tags(&["b".to_string()])
+            .unwrap()
+            .iter()
+            .map(|file| file.name.clone())
+            .collect::<Vec<String>>();
+        res_names.sort_unstable();
+        assert_eq!(res_names, vec!["3.mp4", "4.mp4"]);
+
+        let res_names = file_index
+            .files_of_tags(&["a".to_string(), "b".to_string()])
+            .unwrap()
+            .iter()
+            .map(|file| file.name.clone())
+            .collect::<Vec<String>>();
+        assert_eq!(res_names, vec!["3.mp4"]);
+
+        let jpg_files = file_index.files_of_type("jpg".to_string()).unwrap();
+        assert_eq!(jpg_files.len(), 1);
+        let path = file_index
+            .get_file_path(jpg_files[0].id)
+            .expect("expect file to be in the index");
+        assert_eq!(path, std::fs::canonicalize("./libtest_7/a/2.jpg").unwrap());
+        drop(file_index);
+        for suffix in ["-wal", "-shm"] {
+            let _ = fs::remove_file(format!("./libtest_7.db{suffix}"));
+        }
+        cleanup_storage(index_path, test_storage);
+    }
+
+    #[test]
+    fn test_files_under() {
+        for index_path in ["./libtest_33.index", "./libtest_33.db"] {
+            let index_path = Path::new(index_path);
+            let mut backend = open_backend(index_path).expect("expect opening index to succeed");
+            let paths = [
+                "/a/b",
+                "/a/b/1.txt",
+                "/a/b/c/2.txt",
+                "/a/b.txt",
+                "/a/b2/3.txt",
+            ];
+            let files = paths
+                .iter()
+                .enumerate()
+                .map(|(id, path)| FileMetadata {
+                    id: id as u64,
+                    path: PathBuf::from(path),
+                    ..Default::default()
+                })
+                .collect();
+            backend
+                .insert_batch(files)
+                .expect("expect inserting files to succeed");
+            let mut found: Vec<String> = backend
+                .files_under(Path::new("/a/b"))
+                .unwrap()
+                .into_iter()
+                .map(|each| each.path.to_string_lossy().to_string())
+                .collect();
+            found.sort();
+            assert_eq!(found, vec!["/a/b", "/a/b/1.txt", "/a/b/c/2.txt"]);
+            assert_eq!(backend.files_under(Path::new("/")).unwrap().len(), 5);
+            backend.close().expect("expect closing index to succeed");
+            for suffix in ["", ".bak", "-wal", "-shm"] {
+                let _ = fs::remove_file(format!("{}{suffix}", index_path.display()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_ids_survive_moving_files() {
+        let test_storage = &PathBuf::from("./libtest_9");
+        let index_path = &PathBuf::from("./libtest_9.index");
+        create_nested_file(Path::new("./libtest_9/a/1.mp4"));
+        let original = index_for_dir(index_path, test_storage).files().unwrap();
+        fs::remove_file(index_path).expect("expect deleting index to succeed");
+
+        std::fs::create_dir_all("./libtest_9/b").unwrap();
+        fs::rename("./libtest_9/a/1.mp4", "./libtest_9/b/2.mp4")
+            .expect("expect moving file to succeed");
+        fs::copy("./libtest_9/b/2.mp4", "./libtest_9/b/3.mp4")
+            .expect("expect copying file to succeed");
+        let mut index = FileIndex::new(index_path).expect("expect loading index to succeed");
+        let duplicates = index
+            .add_dir(test_storage)
+            .expect("expect indexing directory with duplicates to succeed");
+        let files = index.files().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(duplicates.len(), 1);
+        let (copy, moved): (Vec<_>, Vec<_>) = files
+            .into_iter()
+            .partition(|file| duplicates.contains(&file.path));
+        assert_eq!(moved[0].id, original[0].id);
+        assert_eq!(moved[0].hash, original[0].hash);
+        assert_ne!(moved[0].path, original[0].path);
+        // copies are indexed under their own id, which is kept when they are moved
+        assert_ne!(copy[0].id, moved[0].id);
+        assert_eq!(copy[0].hash, moved[0].hash);
+        fs::rename(&copy[0].path, "./libtest_9/a/3.mp4").expect("expect moving file to succeed");
+        let report = index
+            .rescan(test_storage)
+            .expect("expect rescanning directory to succeed");
+        assert_eq!((report.added, report.updated, report.removed), (0, 1, 0));
+        let files = index.files().unwrap();
+        let moved_copy = files.iter().find(|file| file.name == "3.mp4").unwrap();
+        assert_eq!(moved_copy.id, copy[0].id);
+        assert!(moved_copy.path.ends_with("a/3.mp4"));
+
+        // unrelated files whose hashes share the first 8 bytes don't replace each other
+        create_nested_file(Path::new("./libtest_9/c/4.mp4"));
+        let content_id = id_of_hash(&blake3::hash(b"./libtest_9/c/4.mp4"));
+        index
+            .insert(FileMetadata {
+                id: content_id,
+                hash: "other".to_string(),
+                path: PathBuf::from("/elsewhere/4.mp4"),
+                ..Default::default()
+            })
+            .unwrap();
+        index
+            .add_dir(&test_storage.join("c"))
+            .expect("expect indexing directory to succeed");
+        let files = index.files().unwrap();
+        assert_eq!(files.len(), 4);
+        let indexed = files.iter().find(|file| file.name == "4.mp4").unwrap();
+        assert_ne!(indexed.id, content_id);
+        assert_eq!(index.get_file(content_id).unwrap().hash, "other");
+        cleanup_storage(index_path, test_storage);
+    }
+
+    #[test]
+    fn test_rescan() {
+        let test_storage = &PathBuf::from("./libtest_10");
+        let index_path = &PathBuf::from("./libtest_10.index");
+        for file in [
+            "./libtest_10/1.mp4",
+            "./libtest_10/2.mp4",
+            "./libtest_10/a/3.mp4",
+        ] {
+            create_nested_file(Path::new(file));
+        }
+        let mut index = FileIndex::new(index_path).expect("expect loading index to succeed");
+        let report = index
+            .rescan(test_storage)
+            .expect("expect rescanning directory to succeed");
+        assert_eq!((report.added, report.updated, report.removed), (3, 0, 0));
+        let moved_id = index.files_of_tags(&["a".to_string()]).unwrap()[0].id;
+        let update = TagUpdate {
+            add: vec!["keep".to_string()],
+            remove: Vec::new(),
+        };
+        index
+            .update_tags(moved_id, &update)
+            .expect("expect tagging file to succeed");
+
+        fs::write("./libtest_10/1.mp4", "new content").expect("expect updating file to succeed");
+        fs::remove_file("./libtest_10/2.mp4").expect("expect deleting file to succeed");
+        create_nested_file(Path::new("./libtest_10/4.mp4"));
+        std::fs::create_dir_all("./libtest_10/b").unwrap();
+        fs::rename("./libtest_10/a/3.mp4", "./libtest_10/b/3.mp4")
+            .expect("expect moving file to succeed");
+        let report = index
+            .rescan(test_storage)
+            .expect("expect rescanning directory to succeed");
+        assert_eq!((report.added, report.updated, report.removed), (1, 2, 1));
+
+        let mut names = index
+            .files()
+            .unwrap()
+            .into_iter()
+            .map(|file| file.name)
+            .collect::<Vec<String>>();
+        names.sort_unstable();
+        assert_eq!(names, vec!["1.mp4", "3.mp4", "4.mp4"]);
+        let moved = index.files_of_tags(&["b".to_string()]).unwrap();
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[0].id, moved_id);
+        assert_eq!(moved[0].user_tags, vec!["keep"]);
+        assert!(index.files_of_tags(&["a".to_string()]).unwrap().is_empty());
+
+        let report = index
+            .rescan(test_storage)
+            .expect("expect rescanning directory to succeed");
+        assert_eq!(report, RescanReport::default());
+        cleanup_storage(index_path, test_storage);
+    }
+
+    #[test]
+    fn test_watching_content_root() {
+        let test_storage = &PathBuf::from("./libtest_11");
+        let index_path = &PathBuf::from("./libtest_11.index");
+        create_nested_file(Path::new("./libtest_11/1.mp4"));
+        let storage =
+            StorageServer::initialize(index_path).expect("expect loading index to succeed");
+        let watcher = watch_roots(
+            std::slice::from_ref(test_storage),
+            storage.transmitter.clone(),
+            Duration::from_millis(50),
+        )
+        .expect("expect watching directory to succeed");
+        assert_eq!(wait_for_files(&storage, &["1.mp4"]), vec!["1.mp4"]);
+
+        create_nested_file(Path::new("./libtest_11/a/2.mp4"));
+        fs::remove_file("./libtest_11/1.mp4").expect("expect deleting file to succeed");
+        assert_eq!(wait_for_files(&storage, &["2.mp4"]), vec!["2.mp4"]);
+
+        // changes aren't picked up once the watcher is dropped
+        drop(watcher);
+        create_nested_file(Path::new("./libtest_11/3.mp4"));
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(all_file_names(&storage), vec!["2.mp4"]);
+        shut_down(&storage);
+        cleanup_storage(index_path, test_storage);
+    }
+
+    fn all_file_names(storage: &StorageHandle) -> Vec<String> {
+        let mut names: Vec<String> = storage
+            .snapshot
+            .load()
+            .files()
+            .into_iter()
+            .map(|f| f.name)
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    fn shut_down(storage: &StorageHandle) {
+        let (tx, rx) = oneshot::channel();
+        storage
+            .transmitter
+            .send(Message::ShutDown(tx))
+            .expect("expect shutting down storage server to succeed");
+        rx.blocking_recv()
+            .unwrap()
+            .expect("expect closing index to succeed");
+    }
+
+    fn wait_for_files(storage: &StorageHandle, expected: &[&str]) -> Vec<String> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let names = all_file_names(storage);
+            if names == expected || Instant::now() > deadline {
+                return names;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn test_pending_uploads() {
+        let test_storage = &PathBuf::from("./libtest_19");
+        let uploads_dir = &test_storage.join("uploads");
+        fs::create_dir_all(test_storage).unwrap();
+        let upload = |name: &str| Upload {
+            file_name: name.to_string(),
+            options: UploadOptions::default(),
+            temp_path: test_storage.join(format!(".{name}.pea-upload")),
+            max_size: None,
+        };
+        let mut uploads = PendingUploads::open(uploads_dir).expect("expect opening to succeed");
+        let kept = uploads
+            .add(upload("kept"), 10, Duration::from_secs(60))
+            .expect("expect adding upload to succeed");
+        let expired = uploads
+            .add(upload("expired"), 10, Duration::ZERO)
+            .expect("expect adding upload to succeed");
+        fs::write(&kept.upload.temp_path, "12345").unwrap();
+        assert!(matches!(uploads.get(&expired.id), Err(FileErr::IdInvalid)));
+
+        let mut uploads = PendingUploads::open(uploads_dir).expect("expect opening to succeed");
+        assert_eq!(uploads.get(&kept.id).unwrap().offset(), 5);
+        let appending = uploads
+            .add(upload("appending"), 10, Duration::from_secs(1))
+            .expect("expect adding upload to succeed");
+        uploads
+            .lock(&appending.id, "first")
+            .expect("expect locking to succeed");
+        assert!(matches!(
+            uploads.lock(&appending.id, "second"),
+            Err(FileErr::UploadLocked)
+        ));
+        // requests that failed to lock the upload can't unlock it
+        uploads.unlock(&appending.id, "second");
+        assert!(matches!(
+            uploads.terminate(&appending.id),
+            Err(FileErr::UploadLocked)
+        ));
+        // uploads being appended to are only purged once they are unlocked
+        std::thread::sleep(Duration::from_secs(1));
+        assert_eq!(uploads.purge_expired().unwrap(), 1);
+        assert!(!expired.upload.temp_path.exists());
+        assert!(kept.upload.temp_path.exists());
+        assert!(appending.upload.temp_path.exists());
+        uploads.unlock(&appending.id, "first");
+        assert_eq!(uploads.purge_expired().unwrap(), 1);
+        assert!(!appending.upload.temp_path.exists());
+        remove_dir_all(test_storage).expect("expect deleting test storage to succeed");
+    }
+
+    #[test]
+    fn test_upload_conflicts() {
+        let test_storage = &PathBuf::from("./libtest_20");
+        let index_path = &PathBuf::from("./libtest_20.index");
+        fs::create_dir_all(test_storage).unwrap();
+        let mut index = FileIndex::new(index_path).expect("expect loading index to succeed");
+        index
+            .register_root(ContentRoot {
+                name: "files".to_string(),
+                path: test_storage.clone(),
+                ..Default::default()
+            })
+            .expect("expect registering root to succeed");
+        let upload = |index: &mut FileIndex, name: &str, content: &str, on_conflict| {
+            let options = UploadOptions {
+                root: Some("files".to_string()),
+                on_conflict,
+                ..Default::default()
+            };
+            let upload = start_upload(index, name.to_string(), options, None)?;
+            fs::write(&upload.temp_path, content).unwrap();
+            let hash = blake3::hash(content.as_bytes());
+            finish_upload(index, &upload, hash, content.len() as u64)
+        };
+        let reject = ConflictPolicy::Reject;
+
+        let first = upload(&mut index, "../../escape.txt", "a", reject).unwrap();
+        assert_eq!(
+            first.path,
+            fs::canonicalize(test_storage).unwrap().join("escape.txt")
+        );
+        assert!(matches!(
+            upload(&mut index, "..", "a", reject),
+            Err(FileErr::InvalidName)
+        ));
+        assert!(matches!(
+            upload(&mut index, "escape.txt", "b", reject),
+            Err(FileErr::FileAlreadyExists)
+        ));
+        let renamed = upload(&mut index, "escape.txt", "b", ConflictPolicy::Rename).unwrap();
+        assert_eq!(renamed.name, "escape (1).txt");
+        let renamed = upload(&mut index, "escape.txt", "c", ConflictPolicy::Rename).unwrap();
+        assert_eq!(renamed.name, "escape (2).txt");
+        let skipped = upload(
+            &mut index,
+            "escape.txt",
+            "a",
+            ConflictPolicy::SkipIfIdentical,
+        )
+        .unwrap();
+        assert_eq!(skipped, first);
+        assert!(matches!(
+            upload(
+                &mut index,
+                "escape.txt",
+                "d",
+                ConflictPolicy::SkipIfIdentical
+            ),
+            Err(FileErr::FileAlreadyExists)
+        ));
+        let overwritten = upload(&mut index, "escape.txt", "e", ConflictPolicy::Overwrite).unwrap();
+        assert_eq!(overwritten.path, first.path);
+        assert!(index.get_file_path(first.id).is_err());
+        assert_eq!(fs::read_to_string(&first.path).unwrap(), "e");
+        let mut stored: Vec<String> = fs::read_dir(test_storage)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        stored.sort();
+        assert_eq!(
+            stored,
+            vec!["escape (1).txt", "escape (2).txt", "escape.txt"]
+        );
+        assert_eq!(index.files().unwrap().len(), 3);
+        cleanup_storage(index_path, test_storage);
+    }
+
+    #[test]
+    fn test_upload_rules() {
+        let index_path = &PathBuf::from("./libtest_32.index");
+        // uploads without a root go to the relative received files dir
+        let test_storage =
+            &PathBuf::from(env::var("PEA_RECEIVED_FILES_DIR").unwrap()).join("libtest_32");
+        fs::create_dir_all(test_storage).unwrap();
+        fs::write(test_storage.join(".peaignore"), "*.log\n").unwrap();
+        let mut index = FileIndex::new(index_path).expect("expect loading index to succeed");
+        index.set_rules(
+            IndexRules::new(&IndexingConfig {
+                exclude: vec!["libtest_32/drafts/**".to_string()],
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let upload = |index: &mut FileIndex, name: &str, directory: &str| {
+            let options = UploadOptions {
+                directory: Some(directory.to_string()),
+                ..Default::default()
+            };
+            start_upload(index, name.to_string(), options, None)
+        };
+
+        assert!(matches!(
+            upload(&mut index, "debug.log", "libtest_32"),
+            Err(FileErr::Ignored)
+        ));
+        assert!(matches!(
+            upload(&mut index, "1.txt", "libtest_32/drafts"),
+            Err(FileErr::Ignored)
+        ));
+        let upload = upload(&mut index, "1.txt", "libtest_32").unwrap();
+        assert_eq!(
+            upload.temp_path.parent(),
+            Some(fs::canonicalize(test_storage).unwrap().as_path())
+        );
+        remove_dir_all(test_storage).expect("expect deleting test storage to succeed");
+        assert!(!index_path.exists());
+    }
+
+    #[test]
+    fn test_storage_server_errors() {
+        let test_storage = &PathBuf::from("./libtest_21");
+        let index_path = &PathBuf::from("./libtest_21.index");
+        create_nested_file(Path::new("./libtest_21/1.mp4"));
+        let mut index = index_for_dir(index_path, test_storage);
+        let missing = test_storage.join("missing");
+        let err = index
+            .register_root(ContentRoot {
+                name: "missing".to_string(),
+                path: missing.clone(),
+                ..Default::default()
+            })
+            .expect_err("expect registering a missing root to fail");
+        assert!(matches!(
+            &err,
+            FileErr::Io { path, kind: std::io::ErrorKind::NotFound } if path == &missing
+        ));
+        assert_eq!(err.path(), Some(missing.as_path()));
+        drop(index);
+
+        let storage =
+            StorageServer::initialize(index_path).expect("expect loading index to succeed");
+        // the storage server has to outlive requesters that stopped waiting for the reply
+        let (tx, rx) = oneshot::channel();
+        drop(rx);
+        storage
+            .transmitter
+            .send(Message::UpdateTags(1, TagUpdate::default(), tx))
+            .unwrap();
+        assert_eq!(all_file_names(&storage), vec!["1.mp4"]);
+        assert!(matches!(
+            storage.snapshot.load().get_file(1),
+            Err(FileErr::IdInvalid)
+        ));
+        shut_down(&storage);
+        cleanup_storage(index_path, test_storage);
+    }
+
+    #[test]
+    fn test_snapshot_reads() {
+        let test_storage = &PathBuf::from("./libtest_22");
+        let index_path = &PathBuf::from("./libtest_22.index");
+        create_nested_file(Path::new("./libtest_22/1.mp4"));
+        let id = index_for_dir(index_path, test_storage).files().unwrap()[0].id;
+        let storage =
+            StorageServer::initialize(index_path).expect("expect loading index to succeed");
+        let before = storage.snapshot.load();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let snapshot = storage.snapshot.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        assert_eq!(snapshot.load().files().len(), 1);
+                    }
+                })
+            })
+            .collect();
+        let (tx, rx) = oneshot::channel();
+        let update = TagUpdate {
+            add: vec!["x".to_string()],
+            ..Default::default()
+        };
+        storage
+            .transmitter
+            .send(Message::UpdateTags(id, update, tx))
+            .unwrap();
+        rx.blocking_recv().unwrap().unwrap();
+        for reader in readers {
+            reader.join().expect("expect reading snapshots to succeed");
+        }
+
+        // changes are visible once they are replied to, earlier snapshots stay unchanged
+        let after = storage.snapshot.load();
+        assert_eq!(after.files_of_tags(&["x".to_string()]).len(), 1);
+        assert_eq!(after.tags(), vec!["x"]);
+        assert!(before.files_of_tags(&["x".to_string()]).is_empty());
+        shut_down(&storage);
+        cleanup_storage(index_path, test_storage);
+    }
+
+    #[test]
+    fn test_snapshot_updates() {
+        let test_storage = &PathBuf::from("./libtest_26");
+        let index_path = &PathBuf::from("./libtest_26.index");
+        create_nested_file(Path::new("./libtest_26/1.mp4"));
+        create_nested_file(Path::new("./libtest_26/2.mp4"));
+        let mut index = index_for_dir(index_path, test_storage);
+        index.clear_changes();
+        let before = IndexSnapshot::new(&index, Vec::new()).unwrap();
+        let ids: Vec<u64> = index.files().unwrap().iter().map(|file| file.id).collect();
+        let update = TagUpdate {
+            add: vec!["x".to_string()],
+            ..Default::default()
+        };
+        index.update_tags(ids[0], &update).unwrap();
+        index.remove(ids[1]).unwrap();
+        index
+            .register_root(ContentRoot {
+                name: "root".to_string(),
+                path: test_storage.clone(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(index.changes().files, ids.iter().copied().collect());
+        assert!(index.changes().roots);
+
+        // only the changes are applied, the rest is shared with the previous snapshot
+        let after = before.updated(&index, index.changes(), None).unwrap();
+        let rebuilt = IndexSnapshot::new(&index, Vec::new()).unwrap();
+        assert_eq!(after.files(), rebuilt.files());
+        assert_eq!(after.roots(), rebuilt.roots());
+        assert_eq!(after.tags(), vec!["x"]);
+        assert_eq!(before.files().len(), 2);
+        assert!(before.roots().is_empty());
+        cleanup_storage(index_path, test_storage);
+    }
+
+    #[test]
+    fn test_reload_config() {
+        let test_storage = &PathBuf::from("./libtest_23");
+        let index_path = &PathBuf::from("./libtest_23.index");
+        create_nested_file(Path::new("./libtest_23/1.mp4"));
+        let storage = StorageServer::initialize_with_config(index_path, ServerConfig::default())
+            .expect("expect loading index to succeed");
+        assert!(storage.snapshot.load().roots().is_empty());
+        let reload = |config: ServerConfig| {
+            let (tx, rx) = oneshot::channel();
+            storage
+                .transmitter
+                .send(Message::ReloadConfig(config, tx))
+                .unwrap();
+            rx.blocking_recv().unwrap()
+        };
+
+        let mut config = ServerConfig {
+            roots: vec![ContentRoot {
+                name: "media".to_string(),
+                path: test_storage.clone(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        reload(config.clone()).expect("expect reloading config to succeed");
+        let roots = storage.snapshot.load().roots();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].name, "media");
+
+        let root = fs::canonicalize(test_storage).unwrap();
+        storage
+            .transmitter
+            .send(Message::SyncPaths(root.clone(), vec![root.join("1.mp4")]))
+            .unwrap();
+
+        // invalid rules and roots are rejected before anything changes
+        let mut invalid = config.clone();
+        invalid.roots[0].tag_prefix = Some("other".to_string());
+        invalid.roots.push(ContentRoot {
+            name: "missing".to_string(),
+            path: test_storage.join("missing"),
+            ..Default::default()
+        });
+        assert!(matches!(reload(invalid), Err(FileErr::Io { .. })));
+        assert_eq!(storage.snapshot.load().roots(), roots);
+        assert_eq!(storage.snapshot.load().files().len(), 1);
+        config.indexing.include = vec!["[".to_string()];
+        config.roots[0].name = "other".to_string();
+        assert!(matches!(reload(config), Err(FileErr::InvalidConfig)));
+        assert_eq!(storage.snapshot.load().roots(), roots);
+
+        // roots removed from the configuration are unregistered together with their files
+        reload(ServerConfig::default()).expect("expect reloading config to succeed");
+        assert!(storage.snapshot.load().roots().is_empty());
+        assert!(storage.snapshot.load().files().is_empty());
+        assert!(test_storage.join("1.mp4").exists());
+        shut_down(&storage);
+        cleanup_storage(index_path, test_storage);
+    }
+
+    #[test]
+    fn test_partial_uploads_removed() {
+        let test_storage = &PathBuf::from("./libtest_30");
+        let index_path = &test_storage.join("data/index.json");
+        let stale = &test_storage.join("root/a/.stale.pea-upload");
+        let running = &test_storage.join("root/.running.pea-upload");
+        create_nested_file(stale);
+        create_nested_file(running);
+        fs::File::options()
+            .write(true)
+            .open(stale)
+            .and_then(|file| {
+                file.set_modified(SystemTime::now() - Duration::from_secs(2 * 60 * 60))
+            })
+            .expect("expect setting modification time to succeed");
+        let config = ServerConfig {
+            roots: vec![ContentRoot {
+                name: "media".to_string(),
+                path: test_storage.join("root"),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let storage = StorageServer::initialize_with_config(index_path, config)
+            .expect("expect loading index to succeed");
+        let (tx, rx) = oneshot::channel();
+        let options = UploadOptions {
+            root: Some("media".to_string()),
+            ..Default::default()
+        };
+        storage
+            .transmitter
+            .send(Message::CreatePendingUpload(
+                "1.jpg".to_string(),
+                10,
+                options,
+                tx,
+            ))
+            .unwrap();
+        let pending = rx
+            .blocking_recv()
+            .unwrap()
+            .expect("expect creating upload to succeed");
+        // housekeeping runs when the server starts, before the message is handled
+        assert!(!stale.exists());
+        assert!(running.exists());
+        assert!(pending.upload.temp_path.exists());
+        shut_down(&storage);
+        remove_dir_all(test_storage).expect("expect deleting test storage to succeed");
+    }
+
+    #[test]
+    fn test_notify_socket() {
+        let test_storage = &PathBuf::from("./libtest_24");
+        fs::create_dir(test_storage).expect("expect creating test storage to succeed");
+        let socket_path = test_storage.join("notify.sock");
+        let socket = UnixDatagram::bind(&socket_path).expect("expect binding socket to succeed");
+        notify_socket(socket_path.as_os_str(), "READY=1").expect("expect notifying to succeed");
+        let mut buffer = [0; 64];
+        let length = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"READY=1");
+        remove_dir_all(test_storage).expect("expect deleting test storage to succeed");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_notify_abstract_socket() {
+        let name = format!("pea-libtest-{}", std::process::id());
+        let address = SocketAddr::from_abstract_name(&name).unwrap();
+        let socket = UnixDatagram::bind_addr(&address).expect("expect binding socket to succeed");
+        notify_socket(OsStr::new(&format!("@{name}")), "WATCHDOG=1")
+            .expect("expect notifying abstract socket to succeed");
+        let mut buffer = [0; 64];
+        let length = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"WATCHDOG=1");
+    }
+
+    #[test]
+    fn test_trash_retention() {
+        let test_storage = &PathBuf::from("./libtest_12");
+        let trash_dir = &test_storage.join("trash");
+        create_nested_file(Path::new("./libtest_12/1.mp4"));
+        let file = FileMetadata {
+            name: "1.mp4".to_string(),
+            id: 1,
+            ty: "mp4".to_string(),
+            path: test_storage.join("1.mp4"),
+            ..Default::default()
+        };
+        let mut trash = Trash::open(trash_dir).expect("expect opening trash to succeed");
+        let entry = trash
+            .add(file)
+            .expect("expect moving file to trash to succeed");
+        assert!(!test_storage.join("1.mp4").exists());
+        assert!(entry.trash_path.exists());
+
+        let trash = Trash::open(trash_dir).expect("expect opening trash to succeed");
+        assert_eq!(trash.entries(), vec![entry.clone()]);
+        let mut trash = trash;
+        assert_eq!(trash.purge_expired(Duration::from_secs(60 * 60)).purged, 0);
+
+        // an entry that can't be purged doesn't keep the others from being purged
+        create_nested_file(Path::new("./libtest_12/2.mp4"));
+        let stuck = trash
+            .add(FileMetadata {
+                name: "2.mp4".to_string(),
+                id: 2,
+                ty: "mp4".to_string(),
+                path: test_storage.join("2.mp4"),
+                ..Default::default()
+            })
+            .expect("expect moving file to trash to succeed");
+        fs::remove_file(&stuck.trash_path).unwrap();
+        create_nested_file(&stuck.trash_path.join("3.mp4"));
+        let report = trash.purge_expired(Duration::ZERO);
+        assert_eq!(report.purged, 1);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, stuck.id);
+        assert_eq!(trash.entries(), vec![stuck]);
+        assert!(!entry.trash_path.exists());
+        remove_dir_all(test_storage).expect("expect deleting test storage to succeed");
+    }
+
+    #[test]
+    fn test_failed_restore() {
+        let test_storage = &PathBuf::from("./libtest_34");
+        let index_path = &PathBuf::from("./libtest_34.index");
+        create_nested_file(&test_storage.join("files/1.mp4"));
+        let mut index = index_for_dir(index_path, &test_storage.join("files"));
+        let mut trash =
+            Trash::open(&test_storage.join("trash")).expect("expect opening trash to succeed");
+        let id = index.files().unwrap()[0].id;
+        let entry = delete_file(&mut index, &mut trash, id).expect("expect deleting to succeed");
+
+        // the index can't be written while a directory is in the way of its journal
+        let journal = PathBuf::from("./libtest_34.index.journal");
+        fs::create_dir_all(&journal).expect("expect creating journal dir to succeed");
+        assert!(restore_file(&mut index, &mut trash, &entry.id).is_err());
+        assert!(!entry.file.path.exists());
+        assert!(entry.trash_path.exists());
+        assert_eq!(trash.entries(), vec![entry.clone()]);
+
+        fs::remove_dir(&journal).expect("expect deleting journal dir to succeed");
+        let file = restore_file(&mut index, &mut trash, &entry.id)
+            .expect("expect restoring file to succeed");
+        assert!(file.path.exists());
+        assert!(trash.entries().is_empty());
+        assert_eq!(index.get_file_path(id).unwrap(), file.path);
+        cleanup_storage(index_path, test_storage);
+    }
+
+    #[test]
+    fn test_maintenance_while_busy() {
+        let test_storage = &PathBuf::from("./libtest_28");
+        let index_path = &PathBuf::from("./libtest_28.index");
+        create_nested_file(Path::new("./libtest_28/1.mp4"));
+        let id = index_for_dir(index_path, test_storage).files().unwrap()[0].id;
+        let storage = StorageServer::initialize_with_maintenance(
+            index_path,
+            ServerConfig::default(),
+            Duration::from_millis(200),
+            Duration::ZERO,
+        )
+        .expect("expect loading index to succeed");
+        let (tx, rx) = oneshot::channel();
+        storage
+            .transmitter
+            .send(Message::DeleteFile(id, tx))
+            .unwrap();
+        let entry = rx
+            .blocking_recv()
+            .unwrap()
+            .expect("expect deleting file to succeed");
+
+        // messages arriving more often than the maintenance interval don't hold off the purge
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while storage
+            .snapshot
+            .load()
+            .trash()
+            .iter()
+            .any(|each| each.id == entry.id)
+        {
+            assert!(
+                Instant::now() < deadline,
+                "expect expired files to be purged"
+            );
+            let (tx, rx) = oneshot::channel();
+            storage
+                .transmitter
+                .send(Message::GetPendingUpload("missing".to_string(), tx))
+                .unwrap();
+            assert!(rx.blocking_recv().unwrap().is_err());
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(!entry.trash_path.exists());
+        shut_down(&storage);
+        cleanup_storage(index_path, test_storage);
+        remove_dir_all(env::var("PEA_TRASH_DIR").unwrap())
+            .expect("expect deleting trash dir to succeed");
+    }
+
+    #[test]
+    fn test_trash_keeps_files_with_same_id() {
+        let test_storage = &PathBuf::from("./libtest_27");
+        let trash_dir = &test_storage.join("trash");
+        let mut trash = Trash::open(trash_dir).expect("expect opening trash to succeed");
+        let mut entries = Vec::new();
+        for name in ["a/1.mp4", "b/1.mp4"] {
+            create_nested_file(&test_storage.join(name));
+            let file = FileMetadata {
+                name: "1.mp4".to_string(),
+                id: 1,
+                ty: "mp4".to_string(),
+                path: test_storage.join(name),
+                ..Default::default()
+            };
+            entries.push(
+                trash
+                    .add(file)
+                    .expect("expect moving file to trash to succeed"),
+            );
+        }
+        assert_ne!(entries[0].id, entries[1].id);
+        assert!(entries.iter().all(|each| each.trash_path.exists()));
+
+        let mut trash = Trash::open(trash_dir).expect("expect opening trash to succeed");
+        assert_eq!(trash.entries().len(), 2);
+        let file = trash
+            .restore(&entries[0].id)
+            .expect("expect restoring file to succeed");
+        assert_eq!(file.path, test_storage.join("a/1.mp4"));
+        assert!(test_storage.join("a/1.mp4").exists());
+        assert_eq!(trash.entries(), vec![entries[1].clone()]);
+        remove_dir_all(test_storage).expect("expect deleting test storage to succeed");
+    }
+
+    #[test]
+    fn test_index_recovery() {
+        let test_storage = &PathBuf::from("./libtest_8");
+        let index_path = &PathBuf::from("./libtest_8.index");
+        for file in ["./libtest_8/a/1.mp4", "./libtest_8/b/2.mp4"] {
+            create_nested_file(Path::new(file));
+        }
+        let mut index = FileIndex::new(index_path).expect("expect loading index to succeed");
+        index
+            .add_dir(&test_storage.join("a"))
+            .expect("expect indexing directory to succeed");
+        index
+            .add_dir(&test_storage.join("b"))
+            .expect("expect indexing directory to succeed");
+        drop(index);
+
+        fs::write(index_path, "[{\"name\": \"1.mp4\",")
+            .expect("expect corrupting the index to succeed");
+        let index = FileIndex::new(index_path).expect("expect recovering index to succeed");
+        let names = index
+            .files()
+            .unwrap()
+            .into_iter()
+            .map(|file| file.name)
+            .collect::<Vec<String>>();
+        assert_eq!(names, vec!["1.mp4"]);
+        drop(index);
+
+        fs::write(index_path, "garbage").expect("expect corrupting the index to succeed");
+        fs::write(backup_path(index_path), "garbage")
+            .expect("expect corrupting the index backup to succeed");
+        assert!(matches!(
+            FileIndex::new(index_path),
+            Err(FileErr::IndexInvalid)
+        ));
+        cleanup_storage(index_path, test_storage);
+    }
+
+    #[test]
+    fn test_unreadable_index() {
+        let test_storage = &PathBuf::from("./libtest_25");
+        // directories can't be read as files, like files without read permission
+        let index_path = &test_storage.join("index.json");
+        fs::create_dir_all(index_path).expect("expect creating test storage to succeed");
+        fs::write(backup_path(index_path), "[]").expect("expect creating backup to succeed");
+        assert!(matches!(
+            FileIndex::new(index_path),
+            Err(FileErr::Io { path, .. }) if &path == index_path
+        ));
+        assert!(index_path.is_dir());
+
+        fs::create_dir_all(test_storage.join("trash/manifest.json"))
+            .expect("expect creating test storage to succeed");
+        assert!(matches!(
+            Trash::open(&test_storage.join("trash")),
+            Err(FileErr::Io { .. })
+        ));
+        fs::create_dir_all(test_storage.join("uploads/manifest.json"))
+            .expect("expect creating test storage to succeed");
+        assert!(matches!(
+            PendingUploads::open(&test_storage.join("uploads")),
+            Err(FileErr::Io { .. })
+        ));
+        remove_dir_all(test_storage).expect("expect deleting test storage to succeed");
+    }
+
+    #[test]
+    fn test_failed_index_writes() {
+        let test_storage = &PathBuf::from("./libtest_31");
+        let index_path = &PathBuf::from("./libtest_31.index");
+        create_nested_file(&test_storage.join("a/1.mp4"));
+        let mut index = FileIndex::new(index_path).expect("expect loading index to succeed");
+        let root = index
+            .register_root(ContentRoot {
+                name: "media".to_string(),
+                path: test_storage.clone(),
+                ..Default::default()
+            })
+            .expect("expect registering root to succeed");
+        index
+            .rescan(&root.path)
+            .expect("expect rescanning root to succeed");
+        let files = index.files().unwrap();
+
+        // the index file can't be written while a directory is in the way of its journal
+        let journal = PathBuf::from("./libtest_31.index.journal");
+        fs::create_dir_all(&journal).expect("expect creating journal dir to succeed");
+        let update = FileUpdate {
+            name: None,
+            directory: Some("b".to_string()),
+        };
+        assert!(matches!(
+            index.update_file(files[0].id, &update),
+            Err(FileErr::Io { .. })
+        ));
+        assert!(files[0].path.exists());
+        assert_eq!(index.files().unwrap(), files);
+        assert!(index
+            .register_root(ContentRoot {
+                name: "other".to_string(),
+                path: test_storage.join("a"),
+                ..Default::default()
+            })
+            .is_err());
+        assert_eq!(index.roots().unwrap(), vec![root]);
+
+        fs::remove_dir(&journal).expect("expect deleting journal dir to succeed");
+        drop(index);
+        let index = FileIndex::new(index_path).expect("expect loading index to succeed");
+        assert_eq!(index.files().unwrap(), files);
+        cleanup_storage(index_path, test_storage);
+    }
+
+    #[test]
+    fn test_metadata_migration() {
+        let test_storage = &PathBuf::from("./libtest_13");
+        let index_path = &PathBuf::from("./libtest_13.index");
+        initialize_storage(test_storage);
+        // PNG signature, so the type is sniffed from the content instead of the extension
+        let png = [
+            0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 0x0D,
+        ];
+        fs::write(test_storage.join("image.txt"), png).unwrap();
+        let index = index_for_dir(index_path, test_storage);
+        let mut files = index.files().unwrap();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        let mimes: Vec<&str> = files.iter().map(|each| each.mime.as_str()).collect();
+        assert_eq!(
+            mimes,
+            vec![
+                "text/plain",
+                "video/mp4",
+                "image/jpeg",
+                "video/x-matroska",
+                "text/plain",
+                "image/png",
+                "text/plain"
+            ]
+        );
+        drop(index);
+
+        // indexes written before the metadata was added lack these fields
+        let legacy: Vec<serde_json::Value> = files
+            .iter()
+            .map(|file| {
+                serde_json::json!({
+                    "name": file.name,
+                    "id": file.id,
+                    "ty": file.ty,
+                    "path": file.path,
+                    "tags": file.tags,
+                })
+            })
+            .collect();
+        fs::write(index_path, serde_json::to_string(&legacy).unwrap()).unwrap();
+        let index = FileIndex::new(index_path).expect("expect loading legacy index to succeed");
+        for file in index.files().unwrap() {
+            let expected = files.iter().find(|each| each.id == file.id).unwrap();
+            assert_eq!(file.mime, expected.mime);
+            assert_eq!(file.created, expected.created);
+            assert!(file.indexed_at.is_some());
+        }
+        let migrated: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(index_path).unwrap()).unwrap();
+        let migrated: Vec<FileMetadata> =
+            serde_json::from_value(migrated["files"].clone()).unwrap();
+        assert!(migrated.iter().all(|file| !file.mime.is_empty()));
+        cleanup_storage(index_path, test_storage);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unusual_file_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let test_storage = &PathBuf::from("./libtest_14");
+        let index_path = &PathBuf::from("./libtest_14.index");
+        fs::create_dir(test_storage).unwrap();
+        // latin-1 encoded name as found on old network shares
+        let latin1_name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+        fs::write(test_storage.join(latin1_name), "menu").unwrap();
+        let png = [
+            0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 0x0D,
+        ];
+        fs::write(test_storage.join("photo"), png).unwrap();
+        std::os::unix::fs::symlink("missing.mp4", test_storage.join("broken.mp4")).unwrap();
+
+        let mut index = FileIndex::new(index_path).expect("expect loading index to succeed");
+        let report = index
+            .rescan(test_storage)
+            .expect("expect a file failing not to fail the rescan");
+        assert_eq!(report.added, 2);
+        assert_eq!(report.failed.len(), 1);
+        assert!(report.failed[0].path.ends_with("broken.mp4"));
+        drop(index);
+
+        let index = FileIndex::new(index_path).expect("expect reloading index to succeed");
+        let mut files = index.files().unwrap();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(files[0].name, "caf\u{FFFD}.txt");
+        assert_eq!(files[0].path.file_name(), Some(latin1_name));
+        assert_eq!(
+            index.get_file_path(files[0].id).unwrap(),
+            fs::canonicalize(test_storage.join(latin1_name)).unwrap()
+        );
+        assert_eq!(
+            (files[1].ty.as_str(), files[1].mime.as_str()),
+            ("png", "image/png")
+        );
+        cleanup_storage(index_path, test_storage);
+    }
+
+    #[test]
+    fn test_index_rules() {
+        let test_storage = &PathBuf::from("./libtest_15");
+        let index_path = &PathBuf::from("./libtest_15.index");
+        for file in [
+            "./libtest_15/keep.txt",
+            "./libtest_15/skip.log",
+            "./libtest_15/.hidden.txt",
+            "./libtest_15/raw/1.jpg",
+            "./libtest_15/a/x.jpg",
+            "./libtest_15/a/keep.jpg",
+            "./libtest_15/a/b/y.jpg",
+            "./libtest_15/a/b/z.jpg",
+        ] {
+            create_nested_file(Path::new(file));
+        }
+        fs::write("./libtest_15/big.txt", [b'a'; 1000]).unwrap();
+        fs::write("./libtest_15/a/.peaignore", "*.jpg\n!keep.jpg\n").unwrap();
+        fs::write("./libtest_15/a/b/.peaignore", "!y.jpg\n").unwrap();
+        let config = IndexingConfig {
+            include: vec!["*.txt".to_string(), "*.jpg".to_string()],
+            exclude: vec!["raw".to_string()],
+            max_file_size: Some(100),
+            index_hidden: false,
+            ..Default::default()
+        };
+        let mut index = FileIndex::new(index_path).expect("expect loading index to succeed");
+        index.set_rules(IndexRules::new(&config).expect("expect rules to be valid"));
+        index
+            .rescan(test_storage)
+            .expect("expect rescanning directory to succeed");
+        let names = |index: &FileIndex| {
+            let mut names: Vec<String> = index
+                .files()
+                .unwrap()
+                .into_iter()
+                .map(|each| each.name)
+                .collect();
+            names.sort_unstable();
+            names
+        };
+        assert_eq!(names(&index), vec!["keep.jpg", "keep.txt", "y.jpg"]);
+
+        let root = fs::canonicalize(test_storage).unwrap();
+        create_nested_file(Path::new("./libtest_15/a/new.jpg"));
+        index
+            .sync_paths(&root, &[root.join("a/new.jpg")])
+            .expect("expect syncing paths to succeed");
+        assert_eq!(names(&index), vec!["keep.jpg", "keep.txt", "y.jpg"]);
+        fs::write("./libtest_15/a/.peaignore", "z.jpg\n").unwrap();
+        index
+            .sync_paths(&root, &[root.join("a/.peaignore")])
+            .expect("expect syncing paths to succeed");
+        assert_eq!(
+            names(&index),
+            vec!["keep.jpg", "keep.txt", "new.jpg", "x.jpg", "y.jpg"]
+        );
+
+        assert!(matches!(
+            create_file(&mut index, "notes.log".to_string(), b"notes", None),
+            Err(FileErr::Ignored)
+        ));
+        cleanup_storage(index_path, test_storage);
+    }
+
+    #[test]
+    fn test_default_indexing_rules() {
+        let test_storage = &PathBuf::from("./libtest_29");
+        let index_path = &PathBuf::from("./libtest_29.index");
+        for file in [
+            "./libtest_29/1.jpg",
+            "./libtest_29/.hidden.jpg",
+            "./libtest_29/.config/2.jpg",
+            "./libtest_29/secret.enc",
+            "./libtest_29/.DS_Store",
+            "./libtest_29/._1.jpg",
+            "./libtest_29/raw/3.jpg",
+        ] {
+            create_nested_file(Path::new(file));
+        }
+        let names = |config: &IndexingConfig| {
+            let mut index = FileIndex::new(index_path).expect("expect loading index to succeed");
+            index.set_rules(IndexRules::new(config).expect("expect rules to be valid"));
+            index
+                .rescan(test_storage)
+                .expect("expect rescanning directory to succeed");
+            let mut names: Vec<String> = index
+                .files()
+                .unwrap()
+                .into_iter()
+                .map(|each| each.name)
+                .collect();
+            names.sort_unstable();
+            drop(index);
+            fs::remove_file(index_path).unwrap();
+            let _ = fs::remove_file(backup_path(index_path));
+            names
+        };
+
+        // hidden files are indexed, encrypted and macOS metadata files aren't
+        assert_eq!(
+            names(&IndexingConfig::default()),
+            vec![".hidden.jpg", "1.jpg", "2.jpg", "3.jpg"]
+        );
+        // excludes are added to the files that are never indexed instead of replacing them
+        let config: ServerConfig =
+            serde_json::from_str(r#"{"indexing": {"exclude": ["raw"]}}"#).unwrap();
+        assert_eq!(
+            names(&config.indexing),
+            vec![".hidden.jpg", "1.jpg", "2.jpg"]
+        );
+        remove_dir_all(test_storage).expect("expect deleting test storage to succeed");
+    }
+
+    #[test]
+    fn test_content_roots() {
+        check_content_roots(Path::new("./libtest_16"), Path::new("./libtest_16.index"));
+        check_content_roots(Path::new("./libtest_17"), Path::new("./libtest_17.db"));
+    }
+
+    fn check_content_roots(test_storage: &Path, index_path: &Path) {
+        create_nested_file(&test_storage.join("photos/a/1.jpg"));
+        create_nested_file(&test_storage.join("photos/a/2.png"));
+        create_nested_file(&test_storage.join("music/3.mp3"));
+        let mut index = FileIndex::new(index_path).expect("expect loading index to succeed");
+        let photos = index
+            .register_root(ContentRoot {
+                name: "photos".to_string(),
+                path: test_storage.join("photos"),
+                tag_prefix: Some("pics".to_string()),
+                indexing: Some(IndexingConfig {
+                    include: vec!["*.jpg".to_string()],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .expect("expect registering root to succeed");
+        let music = index
+            .register_root(ContentRoot {
+                name: "music".to_string(),
+                path: test_storage.join("music"),
+                read_only: true,
+                ..Default::default()
+            })
+            .expect("expect registering root to succeed");
+        for root in [&photos, &music] {
+            index
+                .rescan(&root.path)
+                .expect("expect rescanning root to succeed");
+        }
+        let mut files = index.files().unwrap();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        let summary: Vec<(&str, &str, Option<Vec<String>>)> = files
+            .iter()
+            .map(|each| (each.name.as_str(), each.root.as_str(), each.tags.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "1.jpg",
+                    "photos",
+                    Some(vec!["pics".to_string(), "a".to_string()])
+                ),
+                ("3.mp3", "music", None),
+            ]
+        );
+
+        let move_to = |directory: &str| FileUpdate {
+            name: None,
+            directory: Some(directory.to_string()),
+        };
+        assert!(matches!(
+            index.update_file(files[1].id, &move_to("b")),
+            Err(FileErr::ReadOnly)
+        ));
+        assert!(matches!(
+            create_file(&mut index, "4.mp3".to_string(), b"4", Some("music")),
+            Err(FileErr::ReadOnly)
+        ));
+        assert!(matches!(
+            create_file(&mut index, "4.jpg".to_string(), b"4", Some("videos")),
+            Err(FileErr::UnknownRoot)
+        ));
+        create_file(&mut index, "4.jpg".to_string(), b"4", Some("photos"))
+            .expect("expect uploading to root to succeed");
+        let moved = index
+            .update_file(files[0].id, &move_to("b"))
+            .expect("expect moving file to succeed");
+        assert_eq!(moved.tags, Some(vec!["pics".to_string(), "b".to_string()]));
+        assert!(test_storage.join("photos/b/1.jpg").exists());
+        drop(index);
+
+        let index = FileIndex::new(index_path).expect("expect loading index to succeed");
+        let uploaded = index.files_of_tags(&["pics".to_string()]).unwrap();
+        assert_eq!(uploaded.len(), 2);
+        let mut roots = index.roots().unwrap();
+        roots.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(roots, vec![music, photos]);
+        drop(index);
+        for suffix in ["-wal", "-shm"] {
+            let mut path = index_path.as_os_str().to_owned();
+            path.push(suffix);
+            let _ = fs::remove_file(path);
+        }
+        cleanup_storage(index_path, test_storage);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let test_storage = &PathBuf::from("./libtest_18");
+        let index_path = &PathBuf::from("./libtest_18.index");
+        let root = &test_storage.join("root");
+        create_nested_file(&root.join("a/1.txt"));
+        create_nested_file(&root.join("a/b/c/2.txt"));
+        create_nested_file(&test_storage.join("outside/3.txt"));
+        fs::hard_link(root.join("a/1.txt"), root.join("a/hard.txt")).unwrap();
+        symlink("..", root.join("a/loop")).unwrap();
+        symlink("a", root.join("copy")).unwrap();
+        symlink("../outside", root.join("ext")).unwrap();
+        symlink("missing.txt", root.join("dangling.txt")).unwrap();
+        let scan = |symlinks: SymlinkPolicy, max_depth: usize| {
+            let config = IndexingConfig {
+                symlinks,
+                max_depth,
+                ..Default::default()
+            };
+            let mut index = FileIndex::new(index_path).expect("expect loading index to succeed");
+            index.set_rules(IndexRules::new(&config).expect("expect rules to be valid"));
+            let report = index
+                .rescan(root)
+                .expect("expect rescanning directory to succeed");
+            let rescan = index
+                .rescan(root)
+                .expect("expect rescanning directory to succeed");
+            assert_eq!((rescan.added, rescan.updated, rescan.removed), (0, 0, 0));
+            let mut files: Vec<(String, Option<Vec<String>>, Option<String>)> = index
+                .files()
+                .unwrap()
+                .into_iter()
+                .map(|each| (each.name, each.tags, each.link))
+                .collect();
+            files.sort();
+            drop(index);
+            fs::remove_file(index_path).unwrap();
+            let _ = fs::remove_file(backup_path(index_path));
+            (files, report.failed.len())
+        };
+        let tags = |tags: &[&str]| Some(tags.iter().map(|tag| tag.to_string()).collect());
+        let file = |name: &str, tags: Option<Vec<String>>| (name.to_string(), tags, None);
+        let link = |name: &str, tags: Option<Vec<String>>, target: &str| {
+            (name.to_string(), tags, Some(target.to_string()))
+        };
+
+        // the hard link, the loop and the link to a walked directory are skipped and the dangling
+        // link is reported
+        let (files, failed) = scan(SymlinkPolicy::Follow, 64);
+        assert_eq!(
+            files,
+            vec![
+                file("1.txt", tags(&["a"])),
+                file("2.txt", tags(&["a", "b", "c"])),
+                file("3.txt", tags(&["ext"])),
+            ]
+        );
+        assert_eq!(failed, 1);
+        let (files, failed) = scan(SymlinkPolicy::Follow, 1);
+        assert_eq!(
+            files,
+            vec![file("1.txt", tags(&["a"])), file("3.txt", tags(&["ext"]))]
+        );
+        assert_eq!(failed, 2);
+        let (files, failed) = scan(SymlinkPolicy::Ignore, 64);
+        assert_eq!(
+            files,
+            vec![
+                file("1.txt", tags(&["a"])),
+                file("2.txt", tags(&["a", "b", "c"])),
+            ]
+        );
+        assert_eq!(failed, 0);
+        let (files, failed) = scan(SymlinkPolicy::Record, 64);
+        assert_eq!(
+            files,
+            vec![
+                file("1.txt", tags(&["a"])),
+                file("2.txt", tags(&["a", "b", "c"])),
+                link("copy", None, "a"),
+                link("dangling.txt", None, "missing.txt"),
+                link("ext", None, "../outside"),
+                link("loop", tags(&["a"]), ".."),
+            ]
+        );
+        assert_eq!(failed, 0);
+        remove_dir_all(test_storage).expect("expect deleting test storage to succeed");
+    }
+
+    #[test]
+    fn test_query_language() {
+        let tag = |name: &str| Query::Tag(name.to_string());
+        assert_eq!(
+            parse("a OR b c").unwrap(),
+            Query::Or(vec![tag("a"), Query::And(vec![tag("b"), tag("c")])])
+        );
+        assert_eq!(
+            parse("NOT (a OR \"b c\") AND type:jpg,png").unwrap(),
+            Query::And(vec![
+                Query::Not(Box::new(Query::Or(vec![tag("a"), tag("b c")]))),
+                Query::Type(vec!["jpg".to_string(), "png".to_string()])
+            ])
+        );
+        assert_eq!(
+            parse("a AND b c OR d OR e").unwrap(),
+            Query::Or(vec![
+                Query::And(vec![tag("a"), tag("b"), tag("c")]),
+                tag("d"),
+                tag("e")
+            ])
+        );
+
+        let error = parse("a AND (b OR c").unwrap_err();
+        assert_eq!((error.position, error.token.as_str()), (6, "("));
+        let error = parse("a OR AND b").unwrap_err();
+        assert_eq!((error.position, error.token.as_str()), (5, "AND"));
+        let error = parse("a b)").unwrap_err();
+        assert_eq!((error.position, error.token.as_str()), (3, ")"));
+        let error = parse("type:jpg,").unwrap_err();
+        assert_eq!((error.position, error.token.as_str()), (0, "type:jpg,"));
+        assert!(parse("a NOT").is_err());
+        assert!(parse("").is_err());
+
+        // deeply nested queries are rejected instead of overflowing the stack
+        let nested = format!("{}a{}", "(".repeat(5000), ")".repeat(5000));
+        let error = parse(&nested).unwrap_err();
+        assert_eq!((error.position, error.token.as_str()), (64, "("));
+        let error = parse(&format!("{}a", "NOT ".repeat(5000))).unwrap_err();
+        assert_eq!((error.position, error.token.as_str()), (256, "NOT"));
+        // long chains of the same operator aren't nested
+        match parse(&vec!["a"; 5000].join(" AND ")).unwrap() {
+            Query::And(queries) => assert_eq!(queries.len(), 5000),
+            query => panic!("expected a chain of ANDs but got {query:?}"),
+        }
+        match parse(&vec!["a"; 5000].join(" OR ")).unwrap() {
+            Query::Or(queries) => assert_eq!(queries.len(), 5000),
+            query => panic!("expected a chain of ORs but got {query:?}"),
+        }
+        let nested = format!("{}a{}", "(".repeat(32), ")".repeat(32));
+        assert_eq!(parse(&nested).unwrap(), Query::Tag("a".to_string()));
+
+        let file = FileMetadata {
+            ty: "JPG".to_string(),
+            tags: Some(vec!["holiday".to_string()]),
+            user_tags: vec!["family".to_string()],
+            ..Default::default()
+        };
+        let query = parse("(holiday OR trip) AND NOT screenshots type:jpg,png").unwrap();
+        assert!(query.matches(&file));
+        assert!(parse("family holiday").unwrap().matches(&file));
+        assert!(!parse("holiday NOT family").unwrap().matches(&file));
+        assert!(!parse("type:png").unwrap().matches(&file));
+    }
+
     #[test]
     fn test_registering_service() {
         if !Path::new("config.json").exists() {
@@ -212,14 +1708,15 @@ mod tests {
         unregister_server(server).expect("expect unregistering server to succeed");
     }
 
+    // every file gets its path as the content so that it has a unique id
     fn create_nested_file(path: &Path) {
         let prefix = path.parent().unwrap();
         std::fs::create_dir_all(prefix).unwrap();
-        File::create(path).unwrap();
+        fs::write(path, path.to_string_lossy().as_bytes()).unwrap();
     }
 
     fn index_for_dir(index_path: &Path, dir: &Path) -> FileIndex {
-        let mut index = FileIndex::new(index_path);
+        let mut index = FileIndex::new(index_path).expect("expect loading index to succeed");
         index
             .add_dir(dir)
             .expect("expect indexing directory to succeed");
@@ -233,13 +1730,17 @@ mod tests {
         let files = ["1.mp4", "2.jpg", "3.mkv", "6.txt", ".no_name", "no_ext"];
         std::fs::create_dir(test_storage).expect("expect test storage dir creation to succeed");
         for file in files {
-            File::create(test_storage.join(format!("./{file}")))
+            fs::write(test_storage.join(format!("./{file}")), format!("./{file}"))
                 .expect("expect file creation to succeed");
         }
     }
 
     fn cleanup_storage(index_path: &Path, test_storage: &Path) {
         fs::remove_file(index_path).expect("expect deleting index to succeed");
+        let backup = backup_path(index_path);
+        if backup.exists() {
+            fs::remove_file(backup).expect("expect deleting index backup to succeed");
+        }
         if !test_storage.exists() {
             panic!("test storage is already deleted");
         }
@@ -0,0 +1,290 @@
+use super::storage::FileMetadata;
+
+/// How deeply parentheses and `NOT`s can be nested, so deeply nested queries are rejected instead
+/// of overflowing the stack while parsing or matching them. Chains of `AND`s or `OR`s are parsed
+/// into a single node and don't count towards it.
+const MAX_QUERY_DEPTH: usize = 64;
+
+/// Parsed tag query, e.g. `(holiday OR trip) AND NOT screenshots type:jpg,png`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Query {
+    Tag(String),
+    /// Matches files with any of the types.
+    Type(Vec<String>),
+    /// Matches files matching all of the queries.
+    And(Vec<Query>),
+    /// Matches files matching any of the queries.
+    Or(Vec<Query>),
+    Not(Box<Query>),
+}
+
+impl Query {
+    pub fn matches(&self, file: &FileMetadata) -> bool {
+        match self {
+            Query::Tag(tag) => file.all_tags().contains(tag),
+            Query::Type(types) => types.iter().any(|ty| ty.eq_ignore_ascii_case(&file.ty)),
+            Query::And(queries) => queries.iter().all(|query| query.matches(file)),
+            Query::Or(queries) => queries.iter().any(|query| query.matches(file)),
+            Query::Not(query) => !query.matches(file),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError {
+    /// Byte offset of the offending token in the query.
+    pub position: usize,
+    /// The offending token, empty if the query ended unexpectedly.
+    pub token: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.token.is_empty() {
+            write!(f, "{} at the end of the query", self.message)
+        } else {
+            write!(
+                f,
+                "{} but found `{}` at position {}",
+                self.message, self.token, self.position
+            )
+        }
+    }
+}
+
+/// Parse a query where tags are combined with `AND`, `OR`, `NOT` and parentheses. Tags next to
+/// each other are combined with `AND`, `type:jpg,png` matches files of any of the listed types and
+/// tags containing spaces or named like an operator can be quoted, e.g. `"AND"`.
+pub fn parse(input: &str) -> Result<Query, ParseError> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser {
+        tokens,
+        current: 0,
+        end: input.len(),
+        depth: 0,
+    };
+    let query = parser.or()?;
+    match parser.peek() {
+        None => Ok(query),
+        Some(token) => Err(token.error("expected an operator")),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum TokenKind {
+    LeftParen,
+    RightParen,
+    And,
+    Or,
+    Not,
+    Type(Vec<String>),
+    Tag(String),
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    text: String,
+    position: usize,
+}
+
+impl Token {
+    fn error(&self, message: &str) -> ParseError {
+        ParseError {
+            position: self.position,
+            token: self.text.clone(),
+            message: message.to_string(),
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+    while let Some((position, c)) = chars.next() {
+        let (kind, end) = match c {
+            c if c.is_whitespace() => continue,
+            '(' => (TokenKind::LeftParen, position + 1),
+            ')' => (TokenKind::RightParen, position + 1),
+            '"' => {
+                let mut tag = String::new();
+                let mut end = None;
+                for (i, c) in chars.by_ref() {
+                    if c == '"' {
+                        end = Some(i + 1);
+                        break;
+                    }
+                    tag.push(c);
+                }
+                match end {
+                    Some(end) if !tag.trim().is_empty() => {
+                        (TokenKind::Tag(tag.trim().to_string()), end)
+                    }
+                    Some(end) => {
+                        return Err(ParseError {
+                            position,
+                            token: input[position..end].to_string(),
+                            message: "expected a tag".to_string(),
+                        })
+                    }
+                    None => {
+                        return Err(ParseError {
+                            position,
+                            token: input[position..].to_string(),
+                            message: "expected a closing quote".to_string(),
+                        })
+                    }
+                }
+            }
+            _ => {
+                let mut end = position + c.len_utf8();
+                while let Some((i, c)) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, '(' | ')' | '"') {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                let word = &input[position..end];
+                let kind = match word {
+                    "AND" => TokenKind::And,
+                    "OR" => TokenKind::Or,
+                    "NOT" => TokenKind::Not,
+                    _ => match word.strip_prefix("type:") {
+                        Some(types) => {
+                            let types: Vec<String> =
+                                types.split(',').map(|ty| ty.to_string()).collect();
+                            if types.iter().any(|ty| ty.is_empty()) {
+                                return Err(ParseError {
+                                    position,
+                                    token: word.to_string(),
+                                    message: "expected a comma separated list of types".to_string(),
+                                });
+                            }
+                            TokenKind::Type(types)
+                        }
+                        None => TokenKind::Tag(word.to_string()),
+                    },
+                };
+                (kind, end)
+            }
+        };
+        tokens.push(Token {
+            kind,
+            text: input[position..end].to_string(),
+            position,
+        });
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser where `NOT` binds tighter than `AND`, which binds tighter than `OR`.
+struct Parser {
+    tokens: Vec<Token>,
+    current: usize,
+    /// Position reported when the query ends unexpectedly.
+    end: usize,
+    /// Depth of the query being parsed, see `MAX_QUERY_DEPTH`.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.current)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.current).cloned();
+        self.current += 1;
+        token
+    }
+
+    /// Go one level deeper into the query at the token.
+    fn enter(&mut self, token: &Token) -> Result<(), ParseError> {
+        if self.depth == MAX_QUERY_DEPTH {
+            return Err(token.error(&format!(
+                "expected at most {MAX_QUERY_DEPTH} nested operators"
+            )));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<Query, ParseError> {
+        let mut queries = vec![self.and()?];
+        while self.peek().is_some_and(|token| token.kind == TokenKind::Or) {
+            self.next();
+            queries.push(self.and()?);
+        }
+        Ok(combine(queries, Query::Or))
+    }
+
+    fn and(&mut self) -> Result<Query, ParseError> {
+        let mut queries = vec![self.not()?];
+        while let Some(token) = self.peek() {
+            match token.kind {
+                TokenKind::And => {
+                    self.next();
+                }
+                // tags next to each other are implicitly combined with AND
+                TokenKind::Tag(_) | TokenKind::Type(_) | TokenKind::Not | TokenKind::LeftParen => {}
+                _ => break,
+            }
+            queries.push(self.not()?);
+        }
+        Ok(combine(queries, Query::And))
+    }
+
+    fn not(&mut self) -> Result<Query, ParseError> {
+        if let Some(token) = self
+            .peek()
+            .filter(|token| token.kind == TokenKind::Not)
+            .cloned()
+        {
+            self.enter(&token)?;
+            self.next();
+            let query = Query::Not(Box::new(self.not()?));
+            self.depth -= 1;
+            return Ok(query);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Query, ParseError> {
+        let token = match self.next() {
+            Some(token) => token,
+            None => {
+                return Err(ParseError {
+                    position: self.end,
+                    token: String::new(),
+                    message: "expected a tag".to_string(),
+                })
+            }
+        };
+        match token.kind {
+            TokenKind::Tag(tag) => Ok(Query::Tag(tag)),
+            TokenKind::Type(types) => Ok(Query::Type(types)),
+            TokenKind::LeftParen => {
+                self.enter(&token)?;
+                let query = self.or()?;
+                self.depth -= 1;
+                match self.next() {
+                    Some(closing) if closing.kind == TokenKind::RightParen => Ok(query),
+                    Some(other) => Err(other.error("expected `)`")),
+                    None => Err(token.error("expected a matching `)`")),
+                }
+            }
+            _ => Err(token.error("expected a tag")),
+        }
+    }
+}
+
+/// Combine the operands of a chain of the same operator, or return the only operand.
+fn combine(mut queries: Vec<Query>, operator: fn(Vec<Query>) -> Query) -> Query {
+    if queries.len() == 1 {
+        queries.remove(0)
+    } else {
+        operator(queries)
+    }
+}
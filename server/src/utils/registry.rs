@@ -30,10 +30,7 @@ pub fn register_server(server: &RegistryData) -> RegistryResult {
     if res.status().is_success() {
         Ok(())
     } else {
-        Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "failed to register server",
-        )))
+        Err(Box::new(std::io::Error::other("failed to register server")))
     }
 }
 
@@ -49,9 +46,6 @@ pub fn unregister_server(server: RegistryData) -> RegistryResult {
     if res.status().is_success() {
         Ok(())
     } else {
-        Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "failed to register server",
-        )))
+        Err(Box::new(std::io::Error::other("failed to register server")))
     }
 }
@@ -0,0 +1,203 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use log::{error, warn};
+
+use super::{
+    config::{IndexingConfig, SymlinkPolicy},
+    storage::FileErr,
+};
+
+/// Gitignore style file listing the paths to skip in its directory and below.
+pub const IGNORE_FILE: &str = ".peaignore";
+
+/// Suffix of the temporary files uploads are streamed to, which are never indexed.
+pub const PARTIAL_UPLOAD_SUFFIX: &str = ".pea-upload";
+
+/// Decides which files get indexed, compiled from `IndexingConfig`.
+pub struct IndexRules {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    max_file_size: Option<u64>,
+    index_hidden: bool,
+    symlinks: SymlinkPolicy,
+    max_depth: usize,
+}
+
+impl Default for IndexRules {
+    fn default() -> Self {
+        Self::new(&IndexingConfig::default()).expect("expect default rules to be valid")
+    }
+}
+
+impl IndexRules {
+    pub fn new(config: &IndexingConfig) -> Result<Self, FileErr> {
+        let include = if config.include.is_empty() {
+            None
+        } else {
+            Some(glob_set(&config.include)?)
+        };
+        Ok(Self {
+            include,
+            exclude: glob_set(&config.exclude)?,
+            max_file_size: config.max_file_size,
+            index_hidden: config.index_hidden,
+            symlinks: config.symlinks,
+            max_depth: config.max_depth,
+        })
+    }
+
+    pub fn allows_size(&self, size: u64) -> bool {
+        self.max_file_size.is_none_or(|max| size <= max)
+    }
+
+    pub fn symlinks(&self) -> SymlinkPolicy {
+        self.symlinks
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Start matching paths under `root`. The `.peaignore` files are read once per matcher, so
+    /// create a new one for every scan to pick up changes to them.
+    pub fn matcher(&self, root: &Path) -> RuleMatcher<'_> {
+        RuleMatcher {
+            rules: self,
+            root: root.to_path_buf(),
+            ignore_files: HashMap::new(),
+        }
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, FileErr> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        match Glob::new(pattern) {
+            Ok(glob) => builder.add(glob),
+            Err(err) => {
+                error!("invalid glob pattern {pattern:?}: {err}");
+                return Err(FileErr::InvalidConfig);
+            }
+        };
+    }
+    builder.build().map_err(|err| {
+        error!("failed to compile glob patterns {patterns:?} due to {err}");
+        FileErr::InvalidConfig
+    })
+}
+
+/// Encrypted files and the metadata files created by macOS.
+fn is_system_file(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name.ends_with(".enc") || name == ".DS_Store" || name.starts_with("._")
+}
+
+pub struct RuleMatcher<'a> {
+    rules: &'a IndexRules,
+    root: PathBuf,
+    ignore_files: HashMap<PathBuf, Option<Gitignore>>,
+}
+
+impl<'a> RuleMatcher<'a> {
+    pub fn rules(&self) -> &'a IndexRules {
+        self.rules
+    }
+
+    /// Whether to index the file, or to walk into the directory, assuming its parent directories
+    /// are allowed.
+    pub fn allows(&mut self, path: &Path, is_dir: bool) -> bool {
+        let name = match path.file_name() {
+            Some(name) => name,
+            None => return true,
+        };
+        if name == IGNORE_FILE
+            || name.to_string_lossy().ends_with(PARTIAL_UPLOAD_SUFFIX)
+            || !is_dir && is_system_file(name)
+            || !self.rules.index_hidden && name.to_string_lossy().starts_with('.')
+        {
+            return false;
+        }
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        let rules = self.rules;
+        if rules.exclude.is_match(relative) || rules.exclude.is_match(name) {
+            return false;
+        }
+        if !is_dir {
+            if let Some(include) = &rules.include {
+                if !include.is_match(relative) && !include.is_match(name) {
+                    return false;
+                }
+            }
+            if let Ok(metadata) = std::fs::metadata(path) {
+                if !rules.allows_size(metadata.len()) {
+                    return false;
+                }
+            }
+        }
+        !self.is_ignored(path, is_dir)
+    }
+
+    /// Same as `allows` but also checks the directories between the root and the path.
+    pub fn allows_nested(&mut self, path: &Path, is_dir: bool) -> bool {
+        let parents: Vec<PathBuf> = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.root) && *dir != self.root)
+            .map(Path::to_path_buf)
+            .collect();
+        parents.iter().all(|dir| self.allows(dir, true)) && self.allows(path, is_dir)
+    }
+
+    /// The `.peaignore` closest to the path decides, so deeper files can re-include paths
+    /// ignored higher up.
+    fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        let dirs: Vec<PathBuf> = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.root))
+            .map(Path::to_path_buf)
+            .collect();
+        for dir in dirs {
+            if let Some(ignore) = self.ignore_file(&dir) {
+                match ignore.matched(path, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+        }
+        false
+    }
+
+    fn ignore_file(&mut self, dir: &Path) -> Option<&Gitignore> {
+        self.ignore_files
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| {
+                let path = dir.join(IGNORE_FILE);
+                if !path.is_file() {
+                    return None;
+                }
+                let mut builder = GitignoreBuilder::new(dir);
+                if let Some(err) = builder.add(&path) {
+                    warn!("failed to read some rules of {path:?}: {err}");
+                }
+                match builder.build() {
+                    Ok(ignore) => Some(ignore),
+                    Err(err) => {
+                        warn!("ignoring {path:?} due to {err}");
+                        None
+                    }
+                }
+            })
+            .as_ref()
+    }
+}
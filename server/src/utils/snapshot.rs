@@ -0,0 +1,130 @@
+use std::sync::{Arc, RwLock};
+
+use im::HashMap;
+
+use super::{
+    backend::{distinct_tags, has_tags},
+    config::ContentRoot,
+    query::Query,
+    storage::{FileErr, FileIndex, FileMetadata, IndexChanges},
+    trash::TrashEntry,
+};
+
+/// Read only copy of the index and the trash. Queries run against it without going through the
+/// storage server, so they don't wait for writes and can run concurrently. The files are kept in a
+/// persistent map that shares its structure between consecutive snapshots, so copying a snapshot
+/// to update it only copies the parts of the map that change instead of every entry.
+#[derive(Debug, Default, Clone)]
+pub struct IndexSnapshot {
+    files: HashMap<u64, Arc<FileMetadata>>,
+    roots: Vec<ContentRoot>,
+    trash: Arc<Vec<TrashEntry>>,
+}
+
+impl IndexSnapshot {
+    pub fn new(index: &FileIndex, trash: Vec<TrashEntry>) -> Result<Self, FileErr> {
+        Ok(Self {
+            files: index
+                .files()?
+                .into_iter()
+                .map(|file| (file.id, Arc::new(file)))
+                .collect(),
+            roots: index.roots()?,
+            trash: Arc::new(trash),
+        })
+    }
+
+    /// Copy of the snapshot with the changed files and roots read from the index, and the trash
+    /// replaced if it changed.
+    pub fn updated(
+        &self,
+        index: &FileIndex,
+        changes: &IndexChanges,
+        trash: Option<Vec<TrashEntry>>,
+    ) -> Result<Self, FileErr> {
+        let mut snapshot = self.clone();
+        for id in &changes.files {
+            match index.get_file(*id) {
+                Ok(file) => {
+                    snapshot.files.insert(*id, Arc::new(file));
+                }
+                Err(FileErr::IdInvalid) => {
+                    snapshot.files.remove(id);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        if changes.roots {
+            snapshot.roots = index.roots()?;
+        }
+        if let Some(trash) = trash {
+            snapshot.trash = Arc::new(trash);
+        }
+        Ok(snapshot)
+    }
+
+    pub fn files(&self) -> Vec<FileMetadata> {
+        self.files.values().map(|file| (**file).clone()).collect()
+    }
+
+    pub fn get_file(&self, id: u64) -> Result<FileMetadata, FileErr> {
+        match self.files.get(&id) {
+            Some(file) => Ok((**file).clone()),
+            None => Err(FileErr::IdInvalid),
+        }
+    }
+
+    pub fn files_of_type(&self, ty: &str) -> Vec<FileMetadata> {
+        self.filter(|file| file.ty == ty)
+    }
+
+    pub fn files_of_tags(&self, tags: &[String]) -> Vec<FileMetadata> {
+        self.filter(|file| has_tags(file, tags))
+    }
+
+    pub fn files_of_query(&self, query: &Query) -> Vec<FileMetadata> {
+        self.filter(|file| query.matches(file))
+    }
+
+    pub fn tags(&self) -> Vec<String> {
+        distinct_tags(self.files.values().map(Arc::as_ref))
+    }
+
+    pub fn roots(&self) -> Vec<ContentRoot> {
+        self.roots.clone()
+    }
+
+    pub fn trash(&self) -> Vec<TrashEntry> {
+        self.trash.to_vec()
+    }
+
+    fn filter(&self, predicate: impl Fn(&FileMetadata) -> bool) -> Vec<FileMetadata> {
+        self.files
+            .values()
+            .filter(|file| predicate(file))
+            .map(|file| (**file).clone())
+            .collect()
+    }
+}
+
+/// The latest snapshot published by the storage server. The lock is only held to swap or clone
+/// the pointer to the snapshot, so readers never wait for a query or a write to finish.
+#[derive(Debug, Default, Clone)]
+pub struct SharedSnapshot(Arc<RwLock<Arc<IndexSnapshot>>>);
+
+impl SharedSnapshot {
+    pub fn load(&self) -> Arc<IndexSnapshot> {
+        match self.0.read() {
+            Ok(snapshot) => snapshot.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    pub fn store(&self, snapshot: IndexSnapshot) {
+        let snapshot = Arc::new(snapshot);
+        match self.0.write() {
+            Ok(mut current) => *current = snapshot,
+            Err(poisoned) => *poisoned.into_inner() = snapshot,
+        }
+    }
+}
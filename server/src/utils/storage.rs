@@ -1,22 +1,192 @@
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     env,
     fs::File,
-    hash::{Hash, Hasher},
-    io::Write,
+    io::{self, Read},
     path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
 };
 
-use crossbeam_channel::Sender;
-use log::{debug, error, info};
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use log::{error, info, warn};
+use tokio::sync::oneshot;
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone)]
+use super::{
+    backend::{open_backend, IndexBackend},
+    config::{ContentRoot, ServerConfig, SymlinkPolicy},
+    manifest::unix_seconds,
+    query::Query,
+    rules::{IndexRules, RuleMatcher, IGNORE_FILE, PARTIAL_UPLOAD_SUFFIX},
+    snapshot::{IndexSnapshot, SharedSnapshot},
+    trash::{Trash, TrashEntry},
+    uploads::{PendingUpload, PendingUploads},
+};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone, Default)]
 pub struct FileMetadata {
+    /// Display name, lossily converted if the file name isn't valid UTF-8.
     pub name: String,
+    /// Derived from the content hash so it doesn't change when the file is moved or renamed.
     pub id: u64,
+    /// The extension, or inferred from the content for files without one.
     pub ty: String,
+    #[serde(with = "lossless_path")]
     pub path: PathBuf,
+    /// Name of the content root the file is in, empty if it isn't in a registered root.
+    #[serde(default)]
+    pub root: String,
+    /// Tags derived from the names of the parent directories of the file, preceded by the tag
+    /// prefix of its root.
     pub tags: Option<Vec<String>>,
+    /// Tags assigned by the user, which are kept when the file is moved.
+    #[serde(default)]
+    pub user_tags: Vec<String>,
+    /// Hex encoded BLAKE3 hash of the file content, or of the target of a recorded link.
+    #[serde(default)]
+    pub hash: String,
+    /// Size in bytes, used together with `modified` to detect changed files when re-scanning.
+    #[serde(default)]
+    pub size: u64,
+    /// Last modification time in seconds since the unix epoch.
+    #[serde(default)]
+    pub modified: Option<u64>,
+    /// Creation time in seconds since the unix epoch, if the file system records it.
+    #[serde(default)]
+    pub created: Option<u64>,
+    /// When the current content of the file was added to the index, in seconds since the unix
+    /// epoch.
+    #[serde(default)]
+    pub indexed_at: Option<u64>,
+    /// MIME type sniffed from the content, or guessed from the extension if the content isn't
+    /// recognized. Empty for files indexed before it was added, see `FileIndex::migrate`.
+    #[serde(default)]
+    pub mime: String,
+    /// Target of the symbolic link, lossily converted, if the link itself was indexed instead of
+    /// the file it points to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+impl FileMetadata {
+    /// Path derived tags followed by the user assigned tags, without duplicates.
+    pub fn all_tags(&self) -> Vec<String> {
+        let mut tags = self.tags.clone().unwrap_or_default();
+        for tag in &self.user_tags {
+            if !tags.contains(tag) {
+                tags.push(tag.clone());
+            }
+        }
+        tags
+    }
+}
+
+/// Tags to add to and remove from the user assigned tags of files.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct TagUpdate {
+    #[serde(default)]
+    pub add: Vec<String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+/// Rename and/or move a file. `directory` is a `/` separated path relative to the content root
+/// the file is in, so it also decides the path derived tags of the file.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct FileUpdate {
+    pub name: Option<String>,
+    pub directory: Option<String>,
+}
+
+/// Summary of the changes made to the index by `FileIndex::rescan`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct RescanReport {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    /// Files and directories that couldn't be read, which are left as they are in the index.
+    pub failed: Vec<ScanFailure>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone)]
+pub struct ScanFailure {
+    #[serde(with = "lossless_path")]
+    pub path: PathBuf,
+    pub error: String,
+}
+
+/// Paths are serialized as strings, or as raw bytes if they aren't valid UTF-8, so file names
+/// from file systems with legacy encodings round trip through the index unchanged.
+pub mod lossless_path {
+    use std::path::{Path, PathBuf};
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(path) => serializer.serialize_str(path),
+            None => serializer.collect_seq(bytes(path)),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum RawPath {
+            Utf8(String),
+            Bytes(Vec<u8>),
+        }
+        Ok(match RawPath::deserialize(deserializer)? {
+            RawPath::Utf8(path) => PathBuf::from(path),
+            RawPath::Bytes(bytes) => from_bytes(bytes),
+        })
+    }
+
+    /// Same as the path itself, for optional paths.
+    pub mod option {
+        use std::path::PathBuf;
+
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            path: &Option<PathBuf>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match path {
+                Some(path) => super::serialize(path, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<PathBuf>, D::Error> {
+            #[derive(serde::Deserialize)]
+            struct Path(#[serde(with = "super")] PathBuf);
+            Ok(Option::<Path>::deserialize(deserializer)?.map(|path| path.0))
+        }
+    }
+
+    #[cfg(unix)]
+    fn bytes(path: &Path) -> Vec<u8> {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+
+    #[cfg(not(unix))]
+    fn bytes(path: &Path) -> Vec<u8> {
+        path.to_string_lossy().as_bytes().to_vec()
+    }
+
+    #[cfg(unix)]
+    fn from_bytes(bytes: Vec<u8>) -> PathBuf {
+        use std::os::unix::ffi::OsStringExt;
+        PathBuf::from(std::ffi::OsString::from_vec(bytes))
+    }
+
+    #[cfg(not(unix))]
+    fn from_bytes(bytes: Vec<u8>) -> PathBuf {
+        PathBuf::from(String::from_utf8_lossy(&bytes).to_string())
+    }
 }
 
 #[derive(Debug)]
@@ -27,54 +197,262 @@ pub enum FileErr {
     IdInvalid,
     DBError,
     FailedToCreateFile,
+    DuplicateFile,
+    FileAlreadyExists,
+    InvalidName,
+    InvalidConfig,
+    /// The file is excluded by the indexing rules.
+    Ignored,
+    UnknownRoot,
+    /// The file is in a read only content root.
+    ReadOnly,
+    /// The upload is larger than the maximum upload size.
+    TooLarge,
+    /// Another request is appending to the resumable upload.
+    UploadLocked,
+    /// A file system operation on the path failed.
+    Io {
+        path: PathBuf,
+        kind: std::io::ErrorKind,
+    },
 }
 
+impl FileErr {
+    /// Keep the path and the kind of the error, the rest of it is only logged.
+    pub fn io(path: &Path, err: &std::io::Error) -> Self {
+        Self::Io {
+            path: path.to_path_buf(),
+            kind: err.kind(),
+        }
+    }
+
+    /// The path the error is about, if known.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::Io { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+}
+
+/// Changes to the index and the trash, which are applied one at a time. Queries are served from
+/// the snapshot in `StorageHandle` instead.
 pub enum Message {
-    GetAllFiles(InfallibleMultiFileTransmitter),
-    GetAllTags(InfallibleMultiStringTransmitter),
-    GetFilesOfType(String, InfallibleMultiFileTransmitter),
-    GetFilesOfTags(Vec<String>, InfallibleMultiFileTransmitter),
-    GetFilePath(u64, FilePathTransmitter),
-    CreateFile(String, Vec<u8>, FallibleUnitTransmitter),
-    ShutDown,
+    /// Start uploading a file with the name and options, see `start_upload`.
+    StartUpload(String, UploadOptions, UploadTransmitter),
+    /// Add the streamed upload with the content hash and size to the index, see `finish_upload`.
+    FinishUpload(Upload, blake3::Hash, u64, FileTransmitter),
+    /// Start a resumable upload of a file with the name, length and options.
+    CreatePendingUpload(String, u64, UploadOptions, PendingUploadTransmitter),
+    GetPendingUpload(String, PendingUploadTransmitter),
+    /// Get the upload with the id to append to it, locking it with the token, see
+    /// `PendingUploads::lock`. Every lock request has to be followed by an `UnlockPendingUpload`
+    /// with the same token, whether or not it succeeded.
+    LockPendingUpload(String, String, PendingUploadTransmitter),
+    UnlockPendingUpload(String, String),
+    /// Add the resumable upload to the index once all of its content is received.
+    FinishPendingUpload(String, FileTransmitter),
+    TerminatePendingUpload(String, FallibleUnitTransmitter),
+    /// Paths under the content root that changed on disk, see `FileIndex::sync_paths`.
+    SyncPaths(PathBuf, Vec<PathBuf>),
+    DeleteFile(u64, TrashEntryTransmitter),
+    /// Restore the trash entry with the id.
+    RestoreFile(String, FileTransmitter),
+    PurgeFile(String, FallibleUnitTransmitter),
+    UpdateFile(u64, FileUpdate, FileTransmitter),
+    UpdateTags(u64, TagUpdate, FileTransmitter),
+    /// Update the tags of every file matching the tags and type (ignored if empty).
+    UpdateTagsOfFiles(Vec<String>, String, TagUpdate, MultiFileTransmitter),
+    /// Apply the reloaded configuration, see `StorageServer::apply_config`.
+    ReloadConfig(ServerConfig, FallibleUnitTransmitter),
+    /// Reply once the messages sent before are handled, to check that the storage server is
+    /// still responsive.
+    Ping(FallibleUnitTransmitter),
+    /// Stop once the messages sent before are handled, replying when the index is closed.
+    ShutDown(FallibleUnitTransmitter),
 }
 
-// TODO: get rid of infallible transmitters and send errors where needed
-pub type InfallibleMultiFileTransmitter = Sender<Vec<FileMetadata>>;
-pub type InfallibleMultiStringTransmitter = Sender<Vec<String>>;
-pub type FilePathTransmitter = Sender<Result<PathBuf, FileErr>>;
-pub type FallibleUnitTransmitter = Sender<Result<(), FileErr>>;
+/// Replies are sent through a oneshot channel per request, which async requesters can await
+/// without blocking and others can wait for with `blocking_recv`.
+pub type ReplyTransmitter<T> = oneshot::Sender<Result<T, FileErr>>;
+pub type FallibleUnitTransmitter = ReplyTransmitter<()>;
+pub type FileTransmitter = ReplyTransmitter<FileMetadata>;
+pub type MultiFileTransmitter = ReplyTransmitter<Vec<FileMetadata>>;
+pub type TrashEntryTransmitter = ReplyTransmitter<TrashEntry>;
+pub type UploadTransmitter = ReplyTransmitter<Upload>;
+pub type PendingUploadTransmitter = ReplyTransmitter<PendingUpload>;
+
+/// How often the storage server does housekeeping such as purging expired files from the trash.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_TRASH_RETENTION_DAYS: u64 = 30;
+/// Temporary upload files that weren't written to for this long belong to uploads that were
+/// interrupted, since running uploads keep writing to them.
+const PARTIAL_UPLOAD_MAX_IDLE: Duration = Duration::from_secs(60 * 60);
+
+/// Handle to a running storage server: changes are sent through `transmitter` and queries read
+/// the latest `snapshot`.
+#[derive(Clone)]
+pub struct StorageHandle {
+    pub transmitter: Sender<Message>,
+    pub snapshot: SharedSnapshot,
+}
 
 pub struct StorageServer {
     index: FileIndex,
+    trash: Trash,
+    snapshot: SharedSnapshot,
+    trash_retention: Duration,
+    maintenance_interval: Duration,
+    uploads: PendingUploads,
+    max_upload_size: Option<u64>,
+    upload_expiry: Duration,
+    /// Names of the roots registered from the configuration, which are unregistered when they
+    /// are removed from it.
+    configured_roots: HashSet<String>,
 }
 
 impl StorageServer {
-    fn new(index_file: &Path) -> Self {
-        Self {
-            index: FileIndex::new(index_file),
+    fn new(index_file: &Path, config: ServerConfig) -> Result<Self, FileErr> {
+        let data_dir = index_file.parent().unwrap_or_else(|| Path::new("."));
+        let trash_dir = match env::var("PEA_TRASH_DIR") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => data_dir.join("trash"),
+        };
+        let uploads_dir = match env::var("PEA_UPLOADS_DIR") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => data_dir.join("uploads"),
+        };
+        let retention_days = env::var("PEA_TRASH_RETENTION_DAYS")
+            .ok()
+            .and_then(|days| days.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_TRASH_RETENTION_DAYS);
+        let mut server = Self {
+            index: FileIndex::new(index_file)?,
+            trash: Trash::open(&trash_dir)?,
+            snapshot: SharedSnapshot::default(),
+            trash_retention: Duration::from_secs(retention_days * 24 * 60 * 60),
+            maintenance_interval: MAINTENANCE_INTERVAL,
+            uploads: PendingUploads::open(&uploads_dir)?,
+            max_upload_size: None,
+            upload_expiry: Duration::ZERO,
+            configured_roots: HashSet::new(),
+        };
+        server.apply_config(config)?;
+        Ok(server)
+    }
+
+    /// Remove the temporary files left behind by uploads that were interrupted, e.g. by a crash,
+    /// keeping the ones of resumable uploads.
+    fn remove_partial_uploads(&self) {
+        let mut dirs: Vec<PathBuf> = match self.index.roots() {
+            Ok(roots) => roots.into_iter().map(|root| root.path).collect(),
+            Err(err) => {
+                error!("failed to remove partial uploads due to {err}");
+                return;
+            }
+        };
+        if let Ok(dir) = env::var("PEA_RECEIVED_FILES_DIR") {
+            // temporary files are created under the canonical path of the directory
+            dirs.extend(std::fs::canonicalize(dir).ok());
+        }
+        let pending = self.uploads.temp_paths();
+        let cutoff = SystemTime::now() - PARTIAL_UPLOAD_MAX_IDLE;
+        for dir in dirs {
+            remove_partial_uploads(&dir, &pending, cutoff, self.index.rules.max_depth());
         }
     }
 
-    pub fn initialize(index_file: &Path) -> Sender<Message> {
+    /// Use the indexing rules and upload settings of the configuration from now on, register its
+    /// roots and unregister the roots removed from it since it was last applied. Nothing is
+    /// changed if the indexing rules or any of the roots are invalid.
+    fn apply_config(&mut self, config: ServerConfig) -> Result<(), FileErr> {
+        let rules = IndexRules::new(&config.indexing)?;
+        let roots = config
+            .roots
+            .into_iter()
+            .map(validate_root)
+            .collect::<Result<Vec<ContentRoot>, FileErr>>()?;
+        self.index.set_rules(rules);
+        let configured: HashSet<String> = roots.iter().map(|root| root.name.clone()).collect();
+        for name in self.configured_roots.difference(&configured) {
+            self.index.unregister_root(name)?;
+        }
+        self.configured_roots = configured;
+        for root in roots {
+            self.index.register_root(root)?;
+        }
+        self.max_upload_size = config.uploads.max_size;
+        self.upload_expiry = Duration::from_secs(config.uploads.expire_after);
+        Ok(())
+    }
+
+    /// Start the storage server with the configuration at `PEA_CONFIG_FILE`.
+    pub fn initialize(index_file: &Path) -> Result<StorageHandle, FileErr> {
+        Self::initialize_with_config(index_file, ServerConfig::load()?)
+    }
+
+    pub fn initialize_with_config(
+        index_file: &Path,
+        config: ServerConfig,
+    ) -> Result<StorageHandle, FileErr> {
+        Self::new(index_file, config)?.start()
+    }
+
+    /// Start the storage server with housekeeping every `interval`, purging files that have been
+    /// in the trash for longer than `trash_retention`.
+    #[cfg(test)]
+    pub(super) fn initialize_with_maintenance(
+        index_file: &Path,
+        config: ServerConfig,
+        interval: Duration,
+        trash_retention: Duration,
+    ) -> Result<StorageHandle, FileErr> {
+        let mut server = Self::new(index_file, config)?;
+        server.maintenance_interval = interval;
+        server.trash_retention = trash_retention;
+        server.start()
+    }
+
+    fn start(mut self) -> Result<StorageHandle, FileErr> {
         let (tx, rx) = crossbeam_channel::unbounded();
-        let mut server = Self::new(index_file);
+        self.snapshot
+            .store(IndexSnapshot::new(&self.index, self.trash.entries())?);
+        // the snapshot already includes everything written while opening the index
+        self.index.clear_changes();
+        self.trash.clear_changed();
+        let handle = StorageHandle {
+            transmitter: tx,
+            snapshot: self.snapshot.clone(),
+        };
         std::thread::spawn(move || {
-            server.run(rx);
+            self.run(rx);
         });
-        tx
+        Ok(handle)
     }
 
-    pub fn run(&mut self, rx: crossbeam_channel::Receiver<Message>) {
+    /// Handle messages until shut down, doing housekeeping every `maintenance_interval` however
+    /// many messages arrive in between.
+    pub fn run(mut self, rx: crossbeam_channel::Receiver<Message>) {
+        let mut next_maintenance = Instant::now();
         loop {
-            match rx.recv() {
-                Ok(message) => {
-                    if let Message::ShutDown = message {
-                        break;
-                    }
-                    self.handle_message(message);
+            let now = Instant::now();
+            if now >= next_maintenance {
+                self.run_maintenance();
+                self.publish_snapshot();
+                next_maintenance = now + self.maintenance_interval;
+            }
+            match rx.recv_timeout(next_maintenance.saturating_duration_since(Instant::now())) {
+                Ok(Message::ShutDown(tx)) => {
+                    info!("closing index");
+                    let result = self.index.close();
+                    // messages sent from now on fail instead of waiting for a reply forever
+                    drop(rx);
+                    reply(tx, result);
+                    return;
                 }
-                Err(_) => {
+                Ok(message) => self.handle_message(message),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
                     error!("failed to receive message");
                     return;
                 }
@@ -82,34 +460,159 @@ impl StorageServer {
         }
     }
 
+    /// Replace the shared snapshot with a copy of it that has the changes to the index and the
+    /// trash applied. Readers keep the previous snapshot if this fails, and the changes are
+    /// applied with the next ones.
+    fn publish_snapshot(&mut self) {
+        let trash_changed = self.trash.changed();
+        if self.index.changes() == &IndexChanges::default() && !trash_changed {
+            return;
+        }
+        let trash = trash_changed.then(|| self.trash.entries());
+        match self
+            .snapshot
+            .load()
+            .updated(&self.index, self.index.changes(), trash)
+        {
+            Ok(snapshot) => {
+                self.snapshot.store(snapshot);
+                self.index.clear_changes();
+                self.trash.clear_changed();
+            }
+            Err(err) => error!("failed to publish index snapshot due to {err}"),
+        }
+    }
+
+    /// Reply to a request that may have changed the index or the trash once the change is
+    /// published, so the requester sees it in its following queries.
+    fn reply_changed<T>(&mut self, tx: ReplyTransmitter<T>, result: Result<T, FileErr>) {
+        self.publish_snapshot();
+        reply(tx, result);
+    }
+
+    fn run_maintenance(&mut self) {
+        let report = self.trash.purge_expired(self.trash_retention);
+        if !report.failed.is_empty() {
+            error!(
+                "failed to purge {} expired files from trash",
+                report.failed.len()
+            );
+        }
+        if let Err(err) = self.uploads.purge_expired() {
+            error!("failed to purge expired uploads due to {err}");
+        }
+        self.remove_partial_uploads();
+    }
+
+    fn create_pending_upload(
+        &mut self,
+        file_name: String,
+        length: u64,
+        options: UploadOptions,
+    ) -> Result<PendingUpload, FileErr> {
+        if self
+            .max_upload_size
+            .is_some_and(|max_size| length > max_size)
+        {
+            error!("{file_name} is larger than the maximum upload size");
+            return Err(FileErr::TooLarge);
+        }
+        let upload = start_upload(&mut self.index, file_name, options, self.max_upload_size)?;
+        self.uploads.add(upload, length, self.upload_expiry)
+    }
+
     fn handle_message(&mut self, message: Message) {
         match message {
-            Message::GetAllFiles(tx) => {
-                tx.send(self.index.files()).unwrap();
+            Message::StartUpload(file_name, options, tx) => reply(
+                tx,
+                start_upload(&mut self.index, file_name, options, self.max_upload_size),
+            ),
+            Message::FinishUpload(upload, hash, size, tx) => {
+                let result = finish_upload(&mut self.index, &upload, hash, size);
+                self.reply_changed(tx, result);
+            }
+            Message::CreatePendingUpload(file_name, length, options, tx) => {
+                reply(tx, self.create_pending_upload(file_name, length, options))
+            }
+            Message::GetPendingUpload(id, tx) => reply(tx, self.uploads.get(&id)),
+            Message::LockPendingUpload(id, token, tx) => reply(tx, self.uploads.lock(&id, &token)),
+            Message::UnlockPendingUpload(id, token) => self.uploads.unlock(&id, &token),
+            Message::FinishPendingUpload(id, tx) => {
+                let result = finish_pending_upload(&mut self.index, &mut self.uploads, &id);
+                self.reply_changed(tx, result);
+            }
+            Message::TerminatePendingUpload(id, tx) => reply(tx, self.uploads.terminate(&id)),
+            Message::SyncPaths(root, paths) => {
+                match self.index.sync_paths(&root, &paths) {
+                    Ok(report) => {
+                        if report != RescanReport::default() {
+                            info!("synced changes under {root:?}: {report:?}");
+                        }
+                    }
+                    Err(err) => error!("failed to sync changes under {root:?} due to {err}"),
+                }
+                self.publish_snapshot();
+            }
+            Message::DeleteFile(id, tx) => {
+                let result = delete_file(&mut self.index, &mut self.trash, id);
+                self.reply_changed(tx, result);
             }
-            Message::GetAllTags(tx) => {
-                tx.send(self.index.tags()).unwrap();
+            Message::RestoreFile(id, tx) => {
+                let result = restore_file(&mut self.index, &mut self.trash, &id);
+                self.reply_changed(tx, result);
             }
-            Message::GetFilesOfType(ty, tx) => {
-                tx.send(self.index.files_of_type(ty)).unwrap();
+            Message::PurgeFile(id, tx) => {
+                let result = self.trash.purge(&id);
+                self.reply_changed(tx, result);
             }
-            Message::GetFilesOfTags(tags, tx) => {
-                tx.send(self.index.files_of_tags(&tags)).unwrap();
+            Message::UpdateFile(id, update, tx) => {
+                let result = self.index.update_file(id, &update);
+                self.reply_changed(tx, result);
             }
-            Message::GetFilePath(id, tx) => {
-                tx.send(self.index.get_file_path(id)).unwrap();
+            Message::UpdateTags(id, update, tx) => {
+                let result = self.index.update_tags(id, &update);
+                self.reply_changed(tx, result);
             }
-            Message::CreateFile(file_name, content, tx) => {
-                tx.send(create_file(&mut self.index, file_name, &content))
-                    .unwrap();
+            Message::UpdateTagsOfFiles(tags, ty, update, tx) => {
+                let result = self.index.files_of_tags(&tags).and_then(|files| {
+                    let ids: Vec<u64> = files
+                        .into_iter()
+                        .filter(|file| ty.is_empty() || file.ty == ty)
+                        .map(|file| file.id)
+                        .collect();
+                    self.index.update_tags_of_files(&ids, &update)
+                });
+                self.reply_changed(tx, result);
             }
-            Message::ShutDown => {
+            Message::ReloadConfig(config, tx) => {
+                let result = self.apply_config(config);
+                self.reply_changed(tx, result);
+            }
+            Message::Ping(tx) => reply(tx, Ok(())),
+            Message::ShutDown(_) => {
                 panic!("should not be called");
             }
         }
     }
 }
 
+/// Send the result of a request back, which is dropped if the requester stopped waiting for it
+/// so a single abandoned request can't take down the storage server.
+fn reply<T>(tx: ReplyTransmitter<T>, result: Result<T, FileErr>) {
+    if tx.send(result).is_err() {
+        warn!("dropped a reply since its requester stopped waiting for it");
+    }
+}
+
+impl ScanFailure {
+    fn new(path: PathBuf, err: std::io::Error) -> Self {
+        Self {
+            path,
+            error: err.to_string(),
+        }
+    }
+}
+
 impl std::fmt::Display for FileErr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -119,264 +622,1429 @@ impl std::fmt::Display for FileErr {
             FileErr::IdInvalid => write!(f, "id invalid"),
             FileErr::DBError => write!(f, "db error"),
             FileErr::FailedToCreateFile => write!(f, "failed to create file"),
+            FileErr::DuplicateFile => write!(f, "file with the same content already exists"),
+            FileErr::FileAlreadyExists => write!(f, "a file already exists at the path"),
+            FileErr::InvalidName => write!(f, "invalid file or directory name"),
+            FileErr::InvalidConfig => write!(f, "invalid configuration"),
+            FileErr::Ignored => write!(f, "file is excluded by the indexing rules"),
+            FileErr::UnknownRoot => write!(f, "no content root with the name exists"),
+            FileErr::ReadOnly => write!(f, "content root is read only"),
+            FileErr::TooLarge => write!(f, "file is larger than the maximum upload size"),
+            FileErr::UploadLocked => write!(f, "upload is being appended to by another request"),
+            FileErr::Io { path, kind } => write!(f, "{kind}: {}", path.display()),
         }
     }
 }
 
-type FileDB = HashMap<u64, FileMetadata>;
+impl std::error::Error for FileErr {}
 
 pub struct FileIndex {
-    index_file: PathBuf,
-    db: FileDB,
+    backend: Box<dyn IndexBackend>,
+    rules: IndexRules,
+    changes: IndexChanges,
+}
+
+/// What was written to the index since the changes were last taken, so the snapshot can be
+/// updated without reading the whole index again.
+#[derive(Debug, Default, PartialEq)]
+pub struct IndexChanges {
+    /// Ids of the files that were stored or removed.
+    pub files: HashSet<u64>,
+    pub roots: bool,
 }
 
 impl FileIndex {
-    pub fn new(index_file: &Path) -> Self {
-        let db = match deserialize_db(index_file) {
-            Ok(current) => current,
-            Err(_) => {
-                debug!("empty index");
-                HashMap::new()
+    pub fn new(index_file: &Path) -> Result<Self, FileErr> {
+        let mut index = Self::with_backend(open_backend(index_file)?);
+        index.migrate()?;
+        Ok(index)
+    }
+
+    pub fn close(self) -> Result<(), FileErr> {
+        self.backend.close()
+    }
+
+    pub fn with_backend(backend: Box<dyn IndexBackend>) -> Self {
+        Self {
+            backend,
+            rules: IndexRules::default(),
+            changes: IndexChanges::default(),
+        }
+    }
+
+    pub fn changes(&self) -> &IndexChanges {
+        &self.changes
+    }
+
+    pub fn clear_changes(&mut self) {
+        self.changes = IndexChanges::default();
+    }
+
+    /// Remember the files as changed once they are written to the backend.
+    fn record<'a>(&mut self, files: impl IntoIterator<Item = &'a FileMetadata>) {
+        self.changes
+            .files
+            .extend(files.into_iter().map(|file| file.id));
+    }
+
+    /// Rules applied from the next scan on. Files indexed before that are only removed by a rescan.
+    pub fn set_rules(&mut self, rules: IndexRules) {
+        self.rules = rules;
+    }
+
+    pub fn roots(&self) -> Result<Vec<ContentRoot>, FileErr> {
+        self.backend.roots()
+    }
+
+    /// Add the root or replace the settings of the root with the same name. Its files are
+    /// indexed by the next rescan.
+    pub fn register_root(&mut self, root: ContentRoot) -> Result<ContentRoot, FileErr> {
+        let root = validate_root(root)?;
+        self.backend.set_root(root.clone())?;
+        self.changes.roots = true;
+        info!("registered content root {} at {:?}", root.name, root.path);
+        Ok(root)
+    }
+
+    /// Remove the root and its files from the index, leaving the files on disk as they are.
+    pub fn unregister_root(&mut self, name: &str) -> Result<(), FileErr> {
+        let ids: Vec<u64> = self
+            .backend
+            .files()?
+            .into_iter()
+            .filter(|file| file.root == name)
+            .map(|file| file.id)
+            .collect();
+        self.backend.update_batch(Vec::new(), &ids)?;
+        self.changes.files.extend(ids);
+        self.backend.remove_root(name)?;
+        self.changes.roots = true;
+        info!("unregistered content root {name}");
+        Ok(())
+    }
+
+    fn root_named(&self, name: &str) -> Result<RootContext, FileErr> {
+        match self
+            .backend
+            .roots()?
+            .into_iter()
+            .find(|root| root.name == name)
+        {
+            Some(root) => RootContext::new(root),
+            None => {
+                error!("content root {name} does not exist");
+                Err(FileErr::UnknownRoot)
+            }
+        }
+    }
+
+    /// The registered root at `path`, or an unnamed root with the global settings.
+    fn root_at(&self, path: &Path) -> Result<RootContext, FileErr> {
+        match self
+            .backend
+            .roots()?
+            .into_iter()
+            .find(|root| root.path == path)
+        {
+            Some(root) => RootContext::new(root),
+            None => Ok(RootContext::unnamed(path)),
+        }
+    }
+
+    fn root_of_file(&self, file: &FileMetadata) -> Result<RootContext, FileErr> {
+        if !file.root.is_empty() {
+            return self.root_named(&file.root);
+        }
+        // files outside registered roots are tagged with every directory below the root
+        let depth = file.tags.as_ref().map_or(0, Vec::len);
+        match file.path.ancestors().nth(depth + 1) {
+            Some(root) => Ok(RootContext::unnamed(root)),
+            None => {
+                error!("failed to find the content root of {:?}", file.path);
+                Err(FileErr::PathDoesNotExist)
             }
+        }
+    }
+
+    fn rules_of<'a>(&'a self, root: &'a RootContext) -> &'a IndexRules {
+        root.rules.as_ref().unwrap_or(&self.rules)
+    }
+
+    fn ensure_writable(&self, id: u64) -> Result<(), FileErr> {
+        let file = match self.backend.get(id)? {
+            Some(file) => file,
+            None => return Err(FileErr::IdInvalid),
         };
-        Self {
-            db,
-            index_file: index_file.to_path_buf(),
+        if self.root_of_file(&file)?.read_only {
+            error!(
+                "can't modify {:?} in read only root {}",
+                file.path, file.root
+            );
+            return Err(FileErr::ReadOnly);
         }
+        Ok(())
     }
 
-    pub fn files(&self) -> Vec<FileMetadata> {
-        self.db.values().cloned().collect()
+    pub fn files(&self) -> Result<Vec<FileMetadata>, FileErr> {
+        self.backend.files()
     }
 
-    pub fn tags(&self) -> Vec<String> {
-        let buffer: HashSet<String> = self
-            .db
-            .values()
-            .filter_map(|each| each.tags.clone())
-            .flatten()
+    pub fn tags(&self) -> Result<Vec<String>, FileErr> {
+        self.backend.tags()
+    }
+
+    pub fn files_of_type(&self, ty: String) -> Result<Vec<FileMetadata>, FileErr> {
+        self.backend.files_of_type(&ty)
+    }
+
+    pub fn files_of_tags(&self, tags: &[String]) -> Result<Vec<FileMetadata>, FileErr> {
+        self.backend.files_of_tags(tags)
+    }
+
+    pub fn files_of_query(&self, query: &Query) -> Result<Vec<FileMetadata>, FileErr> {
+        Ok(self
+            .files()?
+            .into_iter()
+            .filter(|file| query.matches(file))
+            .collect())
+    }
+
+    pub fn get_file(&self, id: u64) -> Result<FileMetadata, FileErr> {
+        self.backend.get(id)?.ok_or(FileErr::IdInvalid)
+    }
+
+    pub fn get_file_path(&self, id: u64) -> Result<PathBuf, FileErr> {
+        self.get_file(id).map(|file| file.path)
+    }
+
+    /// Remove the file from the index. The file itself is left untouched.
+    pub fn remove(&mut self, id: u64) -> Result<FileMetadata, FileErr> {
+        match self.backend.get(id)? {
+            Some(file) => {
+                self.backend.update_batch(Vec::new(), &[id])?;
+                self.changes.files.insert(id);
+                Ok(file)
+            }
+            None => Err(FileErr::IdInvalid),
+        }
+    }
+
+    /// Add a file that was previously in the index back with the same metadata.
+    pub fn insert(&mut self, file: FileMetadata) -> Result<(), FileErr> {
+        if self.backend.get(file.id)?.is_some() {
+            return Err(FileErr::DuplicateFile);
+        }
+        let id = file.id;
+        self.backend.insert(file)?;
+        self.changes.files.insert(id);
+        Ok(())
+    }
+
+    /// Rename and/or move the file on disk and update its name, path and tags in the index. The
+    /// file is moved back if the index can't be updated.
+    pub fn update_file(&mut self, id: u64, update: &FileUpdate) -> Result<FileMetadata, FileErr> {
+        let file = match self.backend.get(id)? {
+            Some(file) => file,
+            None => return Err(FileErr::IdInvalid),
+        };
+        let root = self.root_of_file(&file)?;
+        if root.read_only {
+            error!("can't move {:?} in read only root {}", file.path, file.root);
+            return Err(FileErr::ReadOnly);
+        }
+        let name = match &update.name {
+            Some(name) => validate_name(name)?,
+            None => file.name.clone(),
+        };
+        let dir = match &update.directory {
+            Some(directory) => dir_in_root(&root.path, Some(directory))?,
+            None => match file.path.parent() {
+                Some(dir) => dir.to_path_buf(),
+                None => return Err(FileErr::PathDoesNotExist),
+            },
+        };
+        let new_path = dir.join(&name);
+        if new_path == file.path {
+            return Ok(file);
+        }
+        let is_allowed = self
+            .rules_of(&root)
+            .matcher(&root.path)
+            .allows_nested(&new_path, false);
+        if !is_allowed {
+            error!("{new_path:?} would not be indexed");
+            return Err(FileErr::Ignored);
+        }
+        if new_path.exists() {
+            error!(
+                "can't move {:?} since {new_path:?} already exists",
+                file.path
+            );
+            return Err(FileErr::FileAlreadyExists);
+        }
+        if let Err(err) = std::fs::create_dir_all(&dir) {
+            error!("failed to create {dir:?} due to {err:?}");
+            return Err(FileErr::io(&dir, &err));
+        }
+        if let Err(err) = std::fs::rename(&file.path, &new_path) {
+            error!(
+                "failed to move {:?} to {new_path:?} due to {err:?}",
+                file.path
+            );
+            return Err(FileErr::io(&file.path, &err));
+        }
+        let (ty, mime) = file_type(&new_path);
+        let updated = FileMetadata {
+            ty,
+            name,
+            mime,
+            path: new_path.clone(),
+            tags: root.tags_of_dir(&dir),
+            ..file.clone()
+        };
+        if let Err(err) = self.backend.insert(updated.clone()) {
+            error!("failed to update index for {new_path:?}, moving it back");
+            if let Err(err) = std::fs::rename(&new_path, &file.path) {
+                error!("failed to move {new_path:?} back due to {err:?}");
+            }
+            return Err(err);
+        }
+        self.changes.files.insert(id);
+        info!("moved {:?} to {new_path:?}", file.path);
+        Ok(updated)
+    }
+
+    pub fn update_tags(&mut self, id: u64, update: &TagUpdate) -> Result<FileMetadata, FileErr> {
+        let mut files = self.update_tags_of_files(&[id], update)?;
+        files.pop().ok_or(FileErr::IdInvalid)
+    }
+
+    /// Add and remove user assigned tags of all the given files in a single index update. Path
+    /// derived tags can only be changed by moving the file.
+    pub fn update_tags_of_files(
+        &mut self,
+        ids: &[u64],
+        update: &TagUpdate,
+    ) -> Result<Vec<FileMetadata>, FileErr> {
+        let add = update
+            .add
+            .iter()
+            .map(|tag| validate_tag(tag))
+            .collect::<Result<Vec<String>, FileErr>>()?;
+        let mut files = Vec::new();
+        for id in ids {
+            let mut file = match self.backend.get(*id)? {
+                Some(file) => file,
+                None => return Err(FileErr::IdInvalid),
+            };
+            file.user_tags
+                .retain(|tag| !update.remove.iter().any(|each| each.trim() == tag));
+            for tag in &add {
+                if !file.user_tags.contains(tag) {
+                    file.user_tags.push(tag.clone());
+                }
+            }
+            files.push(file);
+        }
+        self.backend.insert_batch(files.clone())?;
+        self.record(&files);
+        Ok(files)
+    }
+
+    /// Index the files in the directory that aren't indexed yet. Returns the files with the same
+    /// content as another indexed file, which are indexed as copies, see `assign_id`.
+    pub fn add_dir(&mut self, path: &Path) -> Result<Vec<PathBuf>, FileErr> {
+        let mut new_files = HashMap::new();
+        let mut duplicates = Vec::new();
+        let mut failures = Vec::new();
+        let path = &canonical_root(path)?;
+        let root = self.root_at(path)?;
+        let mut matcher = self.rules_of(&root).matcher(path);
+        let files = files_in_dir(path, root.tags_of_dir(path), &mut matcher, &mut failures)?;
+        for failure in failures {
+            error!(
+                "failed to index {:?} due to {}",
+                failure.path, failure.error
+            );
+        }
+        for mut each in files {
+            each.root = root.name.clone();
+            if self.backend.file_at(&each.path)?.is_some() {
+                continue;
+            }
+            if self.assign_id(&mut each, &new_files)? {
+                duplicates.push(each.path.clone());
+            }
+            new_files.insert(each.id, each);
+        }
+        let new_files: Vec<FileMetadata> = new_files.into_values().collect();
+        self.backend.insert_batch(new_files.clone())?;
+        self.record(&new_files);
+        Ok(duplicates)
+    }
+
+    /// Give the file an id no other file has. Files are identified by their content unless
+    /// another file with the same content already has that id, or an unrelated file whose hash
+    /// starts with the same 8 bytes. Such copies get an id derived from their content and path
+    /// instead. `pending` holds the files about to be stored along with this one.
+    ///
+    /// Returns whether another file has the same content.
+    fn assign_id(
+        &self,
+        file: &mut FileMetadata,
+        pending: &HashMap<u64, FileMetadata>,
+    ) -> Result<bool, FileErr> {
+        let mut is_copy = false;
+        let mut attempt = 0;
+        loop {
+            let other = match pending.get(&file.id) {
+                Some(other) => Some((other.path.clone(), other.hash.clone())),
+                None => self
+                    .backend
+                    .get(file.id)?
+                    .map(|other| (other.path, other.hash)),
+            };
+            match other {
+                Some((path, hash)) if path != file.path => {
+                    if hash == file.hash {
+                        info!("{:?} has the same content as {path:?}", file.path);
+                        is_copy = true;
+                    } else {
+                        warn!("{:?} and {path:?} have the same id {}", file.path, file.id);
+                    }
+                    file.id = id_of_copy(&file.hash, &file.path, attempt);
+                    attempt += 1;
+                }
+                _ => return Ok(is_copy),
+            }
+        }
+    }
+
+    /// Bring the index in sync with the directory tree at `path`. Files are compared using their
+    /// size and modification time so only new and changed files are hashed. A file that was moved
+    /// within the directory keeps its id.
+    pub fn rescan(&mut self, path: &Path) -> Result<RescanReport, FileErr> {
+        let root = canonical_root(path)?;
+        let report = self.sync(&root, std::slice::from_ref(&root))?;
+        info!("rescanned {root:?}: {report:?}");
+        Ok(report)
+    }
+
+    /// Same as `rescan` but limited to the given paths under `root`, which may be files or
+    /// directories that were created, modified or deleted since they were last indexed.
+    pub fn sync_paths(&mut self, root: &Path, paths: &[PathBuf]) -> Result<RescanReport, FileErr> {
+        let root = canonical_root(root)?;
+        let scope: Vec<PathBuf> = paths
+            .iter()
+            .filter(|each| each.starts_with(&root))
+            .map(|each| match (each.file_name(), each.parent()) {
+                // a changed ignore file can affect everything next to it
+                (Some(name), Some(parent)) if name == IGNORE_FILE => parent.to_path_buf(),
+                _ => each.clone(),
+            })
             .collect();
-        buffer.into_iter().collect()
+        self.sync(&root, &scope)
     }
 
-    pub fn files_of_type(&self, ty: String) -> Vec<FileMetadata> {
-        self.db
-            .values()
-            .filter(|each| each.ty == ty)
-            .cloned()
-            .collect()
-    }
-
-    pub fn files_of_tags(&self, tags: &Vec<String>) -> Vec<FileMetadata> {
-        self.db
-            .values()
-            .filter(|each| match &each.tags {
-                None => false,
-                Some(each_tags) => {
-                    for each in tags {
-                        if !each_tags.contains(each) {
-                            return false;
+    fn sync(&mut self, root: &Path, scope: &[PathBuf]) -> Result<RescanReport, FileErr> {
+        let mut indexed: HashMap<PathBuf, FileMetadata> = HashMap::new();
+        for path in scope {
+            for each in self.backend.files_under(path)? {
+                indexed.insert(each.path.clone(), each);
+            }
+        }
+        let mut report = RescanReport::default();
+        let content_root = self.root_at(root)?;
+        let mut matcher = self.rules_of(&content_root).matcher(root);
+        let mut walker = Walker::new(&mut matcher, &mut report.failed);
+        for path in scope {
+            if std::fs::symlink_metadata(path).is_err() {
+                continue;
+            }
+            if path == root {
+                walker.walk_dir(path, content_root.tags_of_dir(path), 0);
+                continue;
+            }
+            let parent = match path.parent() {
+                Some(parent) => parent,
+                None => continue,
+            };
+            if parent == root || walker.matcher.allows_nested(parent, true) {
+                let depth = parent
+                    .strip_prefix(root)
+                    .map_or(0, |dir| dir.iter().count());
+                walker.visit(path, content_root.tags_of_dir(parent), depth);
+            }
+        }
+        let found = walker.finish();
+        let mut upserts = HashMap::new();
+        let mut new_files = Vec::new();
+        let mut replaced = Vec::new();
+        for found in found {
+            match indexed.remove(&found.path) {
+                Some(current) => {
+                    let stat = found.stat();
+                    if current.size == stat.size && current.modified == stat.modified {
+                        continue;
+                    }
+                    let mut file = match found.metadata() {
+                        Ok(file) => file.in_root(&content_root),
+                        Err(err) => {
+                            report.failed.push(ScanFailure::new(found.path, err));
+                            continue;
                         }
+                    };
+                    file.user_tags = current.user_tags;
+                    self.assign_id(&mut file, &upserts)?;
+                    if file.id != current.id {
+                        replaced.push(current.id);
                     }
-                    true
+                    report.updated += 1;
+                    upserts.insert(file.id, file);
                 }
+                None => match found.metadata() {
+                    Ok(file) => new_files.push(file.in_root(&content_root)),
+                    Err(err) => report.failed.push(ScanFailure::new(found.path, err)),
+                },
+            }
+        }
+        let mut removals: HashMap<u64, FileMetadata> =
+            indexed.into_values().map(|each| (each.id, each)).collect();
+        // files that disappeared from one path and showed up at another with the same content
+        // were moved, so they keep their id
+        let mut removed_by_hash: HashMap<String, Vec<u64>> = HashMap::new();
+        for removed in removals.values() {
+            removed_by_hash
+                .entry(removed.hash.clone())
+                .or_default()
+                .push(removed.id);
+        }
+        for mut file in new_files {
+            let moved = removed_by_hash
+                .get_mut(&file.hash)
+                .and_then(|ids| ids.pop())
+                .and_then(|id| removals.remove(&id));
+            if let Some(moved) = moved {
+                file.id = moved.id;
+                file.user_tags = moved.user_tags;
+                file.indexed_at = moved.indexed_at;
+                report.updated += 1;
+            } else {
+                self.assign_id(&mut file, &upserts)?;
+                report.added += 1;
+            }
+            upserts.insert(file.id, file);
+        }
+        report.removed = removals.len();
+        let removals: Vec<u64> = removals
+            .into_keys()
+            .chain(replaced)
+            .filter(|id| !upserts.contains_key(id))
+            .collect();
+        if !upserts.is_empty() || !removals.is_empty() {
+            let changed: Vec<u64> = upserts.keys().chain(&removals).copied().collect();
+            self.backend
+                .update_batch(upserts.into_values().collect(), &removals)?;
+            self.changes.files.extend(changed);
+        }
+        Ok(report)
+    }
+
+    /// Fill in the metadata missing from files indexed by older versions, which can be told apart
+    /// by their missing MIME type.
+    fn migrate(&mut self) -> Result<(), FileErr> {
+        let now = unix_seconds(SystemTime::now());
+        let outdated: Vec<FileMetadata> = self
+            .backend
+            .files()?
+            .into_iter()
+            .filter(|file| file.mime.is_empty())
+            .map(|mut file| {
+                file.created = file.created.or(file_stat(&file.path).created);
+                file.indexed_at = file.indexed_at.or(now);
+                file.mime = file_type(&file.path).1;
+                file
             })
-            .cloned()
-            .collect()
+            .collect();
+        if outdated.is_empty() {
+            return Ok(());
+        }
+        info!("migrating metadata of {} indexed files", outdated.len());
+        self.backend.insert_batch(outdated.clone())?;
+        self.record(&outdated);
+        Ok(())
     }
+}
 
-    pub fn get_file_path(&self, id: u64) -> Result<PathBuf, FileErr> {
-        match self.db.get(&id) {
-            Some(metadata) => Ok(metadata.path.clone()),
-            None => Err(FileErr::IdInvalid),
+/// What to do when an upload has the same name as a file that already exists.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    #[default]
+    Reject,
+    /// Replace the existing file, which is removed from the index.
+    Overwrite,
+    /// Save the upload with a numeric suffix, e.g. `photo (1).jpg`.
+    Rename,
+    /// Keep the existing file if it has the same content, reject the upload otherwise.
+    SkipIfIdentical,
+}
+
+/// Where and how an uploaded file is saved.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct UploadOptions {
+    /// Name of the content root, the received files dir if not set.
+    pub root: Option<String>,
+    /// `/` separated path of the directory relative to the root, the root itself if not set.
+    #[serde(default)]
+    pub directory: Option<String>,
+    /// User assigned tags of the file.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+}
+
+/// An upload being streamed to a temporary file next to where it will be saved.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone)]
+pub struct Upload {
+    /// Normalised name, see `sanitize_file_name`.
+    pub file_name: String,
+    #[serde(flatten)]
+    pub options: UploadOptions,
+    /// Ignored by the indexing rules until it's renamed by `finish_upload`.
+    #[serde(with = "lossless_path")]
+    pub temp_path: PathBuf,
+    /// Uploads larger than this many bytes are rejected while streaming.
+    pub max_size: Option<u64>,
+}
+
+/// Check that the file can be uploaded with the name and options, creating the directory it's
+/// uploaded to if needed, and pick the temporary file to stream the content to.
+pub fn start_upload(
+    index: &mut FileIndex,
+    file_name: String,
+    mut options: UploadOptions,
+    max_size: Option<u64>,
+) -> Result<Upload, FileErr> {
+    let file_name = sanitize_file_name(&file_name)?;
+    options.tags = options
+        .tags
+        .iter()
+        .map(|tag| validate_tag(tag))
+        .collect::<Result<Vec<String>, FileErr>>()?;
+    let context = upload_root(index, options.root.as_deref())?;
+    if context.read_only {
+        error!(
+            "can't upload {file_name} to read only root {}",
+            context.name
+        );
+        return Err(FileErr::ReadOnly);
+    }
+    if !context.path.exists() {
+        info!("creating relieved dir");
+        if let Err(err) = std::fs::create_dir(&context.path) {
+            error!("received_dir creation failed due to {err:?}");
+            return Err(FileErr::io(&context.path, &err));
         }
     }
+    // the rules match paths relative to the root, which has to be resolved like the path
+    let root = canonical_root(&context.path)?;
+    let dir = dir_in_root(&root, options.directory.as_deref())?;
+    let path = dir.join(&file_name);
+    if !index
+        .rules_of(&context)
+        .matcher(&root)
+        .allows_nested(&path, false)
+    {
+        error!("{path:?} is excluded by the indexing rules");
+        return Err(FileErr::Ignored);
+    }
+    if options.on_conflict == ConflictPolicy::Reject && path.exists() {
+        error!("can't upload {file_name} since {path:?} already exists");
+        return Err(FileErr::FileAlreadyExists);
+    }
+    if let Err(err) = std::fs::create_dir_all(&dir) {
+        error!("failed to create {dir:?} due to {err:?}");
+        return Err(FileErr::io(&dir, &err));
+    }
+    let temp_name = format!(".{}{PARTIAL_UPLOAD_SUFFIX}", uuid::Uuid::new_v4());
+    Ok(Upload {
+        file_name,
+        options,
+        temp_path: dir.join(temp_name),
+        max_size,
+    })
+}
 
-    pub fn add_dir(&mut self, path: &Path) -> Result<(), FileErr> {
-        let new_files = files_in_dir(path, None)?;
-        for each in new_files {
-            self.add_file_to_db(each);
+/// Move the fully streamed upload into place and add it to the index, returning the stored file,
+/// or the existing file if the upload was skipped. The temporary file is removed if the upload
+/// isn't moved into place.
+pub fn finish_upload(
+    index: &mut FileIndex,
+    upload: &Upload,
+    hash: blake3::Hash,
+    size: u64,
+) -> Result<FileMetadata, FileErr> {
+    let result = save_upload(index, upload, hash, size);
+    if upload.temp_path.exists() {
+        if let Err(err) = std::fs::remove_file(&upload.temp_path) {
+            error!("failed to remove {:?} due to {err:?}", upload.temp_path);
         }
-        serialize_db(&self.index_file, &self.db)
     }
+    result
+}
 
-    fn add_file(&mut self, path: &Path) -> Result<(), FileErr> {
-        if path.is_dir() {
-            panic!("use `add_dir` to add directory");
+fn save_upload(
+    index: &mut FileIndex,
+    upload: &Upload,
+    hash: blake3::Hash,
+    size: u64,
+) -> Result<FileMetadata, FileErr> {
+    let options = &upload.options;
+    let root = upload_root(index, options.root.as_deref())?;
+    if !index.rules_of(&root).allows_size(size) {
+        error!("{} is too large to be indexed", upload.file_name);
+        return Err(FileErr::Ignored);
+    }
+    let dir = dir_in_root(&canonical_root(&root.path)?, options.directory.as_deref())?;
+    let mut path = dir.join(&upload.file_name);
+    let mut replaced = None;
+    if path.exists() {
+        let existing = index.backend.file_at(&path)?;
+        match options.on_conflict {
+            ConflictPolicy::Reject => {
+                error!("can't save upload since {path:?} already exists");
+                return Err(FileErr::FileAlreadyExists);
+            }
+            ConflictPolicy::Overwrite => replaced = existing,
+            ConflictPolicy::Rename => path = free_path(&dir, &upload.file_name),
+            ConflictPolicy::SkipIfIdentical => {
+                let is_identical = match &existing {
+                    Some(file) => file.hash == hash.to_hex().as_str(),
+                    None => content_hash(&path).is_ok_and(|existing| existing == hash),
+                };
+                if !is_identical {
+                    error!("can't save upload since {path:?} has a different content");
+                    return Err(FileErr::FileAlreadyExists);
+                }
+                if let Some(file) = existing {
+                    info!("skipping upload identical to {path:?}");
+                    return Ok(file);
+                }
+                // an identical file that isn't indexed is replaced by the upload to index it
+            }
+        }
+    }
+    let overwrites = path.exists();
+    if let Err(err) = std::fs::rename(&upload.temp_path, &path) {
+        error!(
+            "failed to move {:?} to {path:?} due to {err:?}",
+            upload.temp_path
+        );
+        return Err(FileErr::io(&upload.temp_path, &err));
+    }
+    let tags = path.parent().and_then(|parent| root.tags_of_dir(parent));
+    let mut file = match hashed_file_metadata(&path, tags, hash) {
+        Ok(file) => FileMetadata {
+            user_tags: options.tags.clone(),
+            ..file.in_root(&root)
+        },
+        Err(err) => {
+            error!("failed to read {path:?} due to {err:?}");
+            return Err(FileErr::io(&path, &err));
         }
-        self.add_file_to_db(file_metadata(path, None));
-        serialize_db(&self.index_file, &self.db)
+    };
+    index.assign_id(&mut file, &HashMap::new())?;
+    let removals: Vec<u64> = replaced
+        .iter()
+        .map(|replaced| replaced.id)
+        .filter(|id| *id != file.id)
+        .collect();
+    if let Err(err) = index.backend.update_batch(vec![file.clone()], &removals) {
+        // an overwritten file is already gone, so only new files are cleaned up
+        if !overwrites {
+            if let Err(err) = std::fs::remove_file(&path) {
+                error!("failed to remove {path:?} due to {err:?}");
+            }
+        }
+        return Err(err);
+    }
+    index.changes.files.extend(removals);
+    index.changes.files.insert(file.id);
+    Ok(file)
+}
+
+/// The first path in the directory named like `file_name` with a `(n)` suffix that doesn't exist.
+fn free_path(dir: &Path, file_name: &str) -> PathBuf {
+    let name = Path::new(file_name);
+    let stem = name
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = name
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|n| dir.join(format!("{stem} ({n}){extension}")))
+        .find(|path| !path.exists())
+        .expect("expect a free name")
+}
+
+/// Keep only the last component of the client supplied name, so clients can't pick the directory,
+/// and drop control characters.
+pub fn sanitize_file_name(file_name: &str) -> Result<String, FileErr> {
+    let name = file_name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = validate_name(&name)?;
+    if name.len() > MAX_NAME_LENGTH {
+        error!("{name:?} is longer than {MAX_NAME_LENGTH} bytes");
+        return Err(FileErr::InvalidName);
     }
+    Ok(name)
+}
 
-    fn add_file_to_db(&mut self, file: FileMetadata) {
-        if self.db.contains_key(&file.id) {
-            panic!("duplicate id for {file:?}");
+/// Add the resumable upload to the index once its temporary file has all of its content. The
+/// upload is finished either way, since a rejected upload can't be resumed.
+pub fn finish_pending_upload(
+    index: &mut FileIndex,
+    uploads: &mut PendingUploads,
+    id: &str,
+) -> Result<FileMetadata, FileErr> {
+    let pending = uploads.get(id)?;
+    if pending.offset() != pending.length {
+        error!("upload {id} is missing some of its content");
+        return Err(FileErr::FailedToCreateFile);
+    }
+    uploads.remove(id)?;
+    // the content arrives over several requests, so it's hashed once it's complete
+    match content_hash(&pending.upload.temp_path) {
+        Ok(hash) => finish_upload(index, &pending.upload, hash, pending.length),
+        Err(err) => {
+            error!(
+                "failed to read {:?} due to {err:?}",
+                pending.upload.temp_path
+            );
+            let _ = std::fs::remove_file(&pending.upload.temp_path);
+            Err(FileErr::io(&pending.upload.temp_path, &err))
         }
-        self.db.insert(file.id, file);
     }
 }
 
+fn upload_root(index: &FileIndex, root: Option<&str>) -> Result<RootContext, FileErr> {
+    match root {
+        Some(name) => index.root_named(name),
+        None => Ok(RootContext::unnamed(&PathBuf::from(
+            env::var("PEA_RECEIVED_FILES_DIR").expect("PEA_RECEIVED_FILES_DIR not set"),
+        ))),
+    }
+}
+
+/// Save the file with the content as if it was uploaded, see `start_upload`.
 pub fn create_file(
     index: &mut FileIndex,
     file_name: String,
     content: &[u8],
-) -> Result<(), FileErr> {
-    let received_dir =
-        PathBuf::from(env::var("PEA_RECEIVED_FILES_DIR").expect("PEA_RECEIVED_FILES_DIR not set"));
-    if !received_dir.exists() {
-        info!("creating relieved dir");
-        let result = std::fs::create_dir(&received_dir);
-        if result.is_err() {
-            error!("received_dir creation failed due to {result:?}");
-            return Err(FileErr::FailedToCreateFile);
+    root: Option<&str>,
+) -> Result<FileMetadata, FileErr> {
+    let options = UploadOptions {
+        root: root.map(str::to_string),
+        ..Default::default()
+    };
+    let upload = start_upload(index, file_name, options, None)?;
+    if let Err(err) = std::fs::write(&upload.temp_path, content) {
+        error!("failed to write {:?} due to {err:?}", upload.temp_path);
+        let _ = std::fs::remove_file(&upload.temp_path);
+        return Err(FileErr::io(&upload.temp_path, &err));
+    }
+    finish_upload(index, &upload, blake3::hash(content), content.len() as u64)
+}
+
+/// Move the file to the trash and remove it from the index.
+pub fn delete_file(
+    index: &mut FileIndex,
+    trash: &mut Trash,
+    id: u64,
+) -> Result<TrashEntry, FileErr> {
+    index.ensure_writable(id)?;
+    let file = index.remove(id)?;
+    match trash.add(file.clone()) {
+        Ok(entry) => {
+            info!("moved {:?} to trash", file.path);
+            Ok(entry)
+        }
+        Err(err) => {
+            error!("failed to move {:?} to trash due to {err}", file.path);
+            index.insert(file)?;
+            Err(err)
+        }
+    }
+}
+
+/// Move the file of the trash entry back to its original path and add it to the index.
+pub fn restore_file(
+    index: &mut FileIndex,
+    trash: &mut Trash,
+    id: &str,
+) -> Result<FileMetadata, FileErr> {
+    let entry = trash.get(id)?.clone();
+    if index.get_file_path(entry.file.id).is_ok() {
+        return Err(FileErr::DuplicateFile);
+    }
+    let file = trash.restore(id)?;
+    if let Err(err) = index.insert(file.clone()) {
+        error!("failed to index restored {:?}, moving it back", file.path);
+        if let Err(err) = trash.undo_restore(entry) {
+            error!("failed to move {:?} back to trash due to {err}", file.path);
+        }
+        return Err(err);
+    }
+    info!("restored {:?} from trash", file.path);
+    Ok(file)
+}
+
+fn files_in_dir(
+    path: &Path,
+    tags: Option<Vec<String>>,
+    matcher: &mut RuleMatcher,
+    failures: &mut Vec<ScanFailure>,
+) -> Result<Vec<FileMetadata>, FileErr> {
+    if !path.is_dir() {
+        error!("path {path:?} is not a directory");
+        return Err(FileErr::PathDoesNotExist);
+    }
+    let mut walker = Walker::new(matcher, failures);
+    walker.walk_dir(path, tags, 0);
+    let mut files = Vec::new();
+    for found in walker.finish() {
+        match found.metadata() {
+            Ok(file) => files.push(file),
+            Err(err) => failures.push(ScanFailure::new(found.path, err)),
+        }
+    }
+    Ok(files)
+}
+
+/// A file to index found while walking a directory, with the tags derived from its parent
+/// directories.
+struct FoundFile {
+    path: PathBuf,
+    tags: Option<Vec<String>>,
+    /// Index the symbolic link itself rather than the file it points to.
+    is_link: bool,
+    /// Whether the path goes through a followed symbolic link.
+    through_link: bool,
+    /// Device and inode numbers, which are the same for all the hard links to a file.
+    inode: Option<(u64, u64)>,
+}
+
+impl FoundFile {
+    fn metadata(&self) -> io::Result<FileMetadata> {
+        if self.is_link {
+            link_metadata(&self.path, self.tags.clone())
+        } else {
+            file_metadata(&self.path, self.tags.clone())
+        }
+    }
+
+    fn stat(&self) -> FileStat {
+        if self.is_link {
+            stat_of(&self.path, std::fs::symlink_metadata(&self.path))
+        } else {
+            file_stat(&self.path)
+        }
+    }
+}
+
+/// Finds the files to index in directory trees, skipping what the rules exclude and handling
+/// symbolic links according to the policy of the rules. Links to directories are followed after
+/// everything else is walked and only into directories that weren't walked yet, which also stops
+/// link loops. Paths that can't be read are added to `failures` and skipped.
+struct Walker<'m, 'r> {
+    matcher: &'m mut RuleMatcher<'r>,
+    failures: &'m mut Vec<ScanFailure>,
+    symlinks: SymlinkPolicy,
+    max_depth: usize,
+    /// Canonical paths of the walked directories.
+    visited: HashSet<PathBuf>,
+    /// Links to directories waiting to be followed, along with their tags and depth.
+    links: VecDeque<(PathBuf, Option<Vec<String>>, usize)>,
+    found: Vec<FoundFile>,
+}
+
+impl<'m, 'r> Walker<'m, 'r> {
+    fn new(matcher: &'m mut RuleMatcher<'r>, failures: &'m mut Vec<ScanFailure>) -> Self {
+        let rules = matcher.rules();
+        Self {
+            symlinks: rules.symlinks(),
+            max_depth: rules.max_depth(),
+            matcher,
+            failures,
+            visited: HashSet::new(),
+            links: VecDeque::new(),
+            found: Vec::new(),
         }
     }
-    let path = received_dir.join(file_name);
-    match File::create(&path) {
-        Ok(mut file) => match Write::write_all(&mut file, content) {
-            Ok(_) => index.add_file(&path),
+
+    /// Walk the directory `depth` levels below the root, whose files get the tags.
+    fn walk_dir(&mut self, path: &Path, tags: Option<Vec<String>>, depth: usize) {
+        self.walk(path, tags, depth, false);
+        self.follow_links();
+    }
+
+    /// Visit the file, directory or link in the directory `depth` levels below the root, whose
+    /// files get the tags.
+    fn visit(&mut self, path: &Path, tags: Option<Vec<String>>, depth: usize) {
+        self.visit_entry(path, tags, depth, false);
+        self.follow_links();
+    }
+
+    /// The found files, leaving out hard links and links to files that were already found.
+    /// Paths that don't go through links are kept over the ones that do.
+    fn finish(mut self) -> Vec<FoundFile> {
+        self.found
+            .sort_by(|a, b| (a.through_link, &a.path).cmp(&(b.through_link, &b.path)));
+        let mut inodes = HashSet::new();
+        self.found.retain(|each| match each.inode {
+            Some(inode) if !inodes.insert(inode) => {
+                info!("skipping {:?} since the file is already indexed", each.path);
+                false
+            }
+            _ => true,
+        });
+        self.found
+    }
+
+    fn follow_links(&mut self) {
+        while let Some((path, tags, depth)) = self.links.pop_front() {
+            self.walk(&path, tags, depth, true);
+        }
+    }
+
+    fn walk(&mut self, path: &Path, tags: Option<Vec<String>>, depth: usize, through_link: bool) {
+        match std::fs::canonicalize(path) {
+            Ok(canonical) if self.visited.contains(&canonical) => {
+                warn!("skipping {path:?} since {canonical:?} is already indexed");
+                return;
+            }
+            Ok(canonical) => {
+                self.visited.insert(canonical);
+            }
             Err(err) => {
-                error!("failed to write to file failed due to {err:?}");
-                Err(FileErr::FailedToCreateFile)
+                self.failures
+                    .push(ScanFailure::new(path.to_path_buf(), err));
+                return;
             }
-        },
-        Err(err) => {
-            error!("failed to create file failed due to {err:?}");
-            Err(FileErr::FailedToCreateFile)
+        }
+        if depth > self.max_depth {
+            let err = io::Error::other(format!("nested deeper than {} levels", self.max_depth));
+            self.failures
+                .push(ScanFailure::new(path.to_path_buf(), err));
+            return;
+        }
+        let entries = match std::fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(err) => {
+                self.failures
+                    .push(ScanFailure::new(path.to_path_buf(), err));
+                return;
+            }
+        };
+        for entry in entries {
+            match entry {
+                Ok(entry) => self.visit_entry(&entry.path(), tags.clone(), depth, through_link),
+                Err(err) => self
+                    .failures
+                    .push(ScanFailure::new(path.to_path_buf(), err)),
+            }
+        }
+    }
+
+    fn visit_entry(
+        &mut self,
+        path: &Path,
+        tags: Option<Vec<String>>,
+        depth: usize,
+        through_link: bool,
+    ) {
+        let metadata = match std::fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                self.failures
+                    .push(ScanFailure::new(path.to_path_buf(), err));
+                return;
+            }
+        };
+        let is_link = metadata.file_type().is_symlink();
+        let metadata = match self.symlinks {
+            _ if !is_link => metadata,
+            SymlinkPolicy::Ignore => return,
+            SymlinkPolicy::Record => {
+                if self.matcher.allows(path, false) {
+                    self.found.push(FoundFile {
+                        path: path.to_path_buf(),
+                        tags,
+                        is_link: true,
+                        through_link,
+                        inode: None,
+                    });
+                }
+                return;
+            }
+            SymlinkPolicy::Follow => match std::fs::metadata(path) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    self.failures
+                        .push(ScanFailure::new(path.to_path_buf(), err));
+                    return;
+                }
+            },
+        };
+        if metadata.is_dir() {
+            if !self.matcher.allows(path, true) {
+                return;
+            }
+            let mut tags = tags.unwrap_or_default();
+            tags.push(path.file_name().unwrap().to_string_lossy().to_string());
+            if is_link {
+                self.links
+                    .push_back((path.to_path_buf(), Some(tags), depth + 1));
+            } else {
+                self.walk(path, Some(tags), depth + 1, through_link);
+            }
+        } else if self.matcher.allows(path, false) {
+            self.found.push(FoundFile {
+                path: path.to_path_buf(),
+                tags,
+                is_link: false,
+                through_link: through_link || is_link,
+                inode: inode(&metadata),
+            });
         }
     }
 }
 
-fn deserialize_db(path: &Path) -> Result<FileDB, FileErr> {
-    Ok(read_index_file(path)?
-        .into_iter()
-        .map(|each| (each.id, each))
-        .collect())
+#[cfg(unix)]
+fn inode(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
 }
 
-fn read_index_file(path: &Path) -> Result<Vec<FileMetadata>, FileErr> {
-    match std::fs::read_to_string(path) {
-        Ok(content) => match serde_json::from_str::<Vec<FileMetadata>>(&content) {
-            Ok(res) => Ok(res),
-            Err(_) => Err(FileErr::IndexDoesNotExist),
-        },
-        Err(_) => Err(FileErr::IndexDoesNotExist),
-    }
-}
-
-fn serialize_db(path: &Path, db: &FileDB) -> Result<(), FileErr> {
-    let values: Vec<FileMetadata> = db.values().cloned().collect();
-    let parent_dir = path.parent().unwrap();
-    match std::fs::create_dir_all(parent_dir) {
-        Ok(_) => match serde_json::to_string_pretty(&values) {
-            Ok(body) => {
-                let f = std::fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open(path);
-                match f {
-                    Ok(mut file) => {
-                        file.write_all(body.as_bytes())
-                            .expect("writing to index file must succeed");
-                        Ok(())
-                    }
-                    Err(err) => {
-                        error!("failed to write to index file due to {err:?}");
-                        Err(FileErr::DBError)
-                    }
-                }
+#[cfg(not(unix))]
+fn inode(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Remove the temporary upload files in the directory and up to `depth` levels below it that
+/// weren't modified since `cutoff`, except the ones in `keep`. Symbolic links aren't followed.
+fn remove_partial_uploads(dir: &Path, keep: &HashSet<PathBuf>, cutoff: SystemTime, depth: usize) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("failed to look for partial uploads in {dir:?} due to {err}");
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            if depth > 0 {
+                remove_partial_uploads(&path, keep, cutoff, depth - 1);
             }
-            Err(err) => {
-                error!("db serialization failed due to {err:?}");
-                Err(FileErr::DBError)
+        } else if file_type.is_file()
+            && path.to_string_lossy().ends_with(PARTIAL_UPLOAD_SUFFIX)
+            && !keep.contains(&path)
+            && entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified < cutoff)
+        {
+            match std::fs::remove_file(&path) {
+                Ok(()) => info!("removed partial upload {path:?}"),
+                Err(err) => error!("failed to remove partial upload {path:?} due to {err:?}"),
             }
-        },
+        }
+    }
+}
+
+/// The root with its name, path and tag prefix normalized, if all of its settings are valid.
+fn validate_root(mut root: ContentRoot) -> Result<ContentRoot, FileErr> {
+    root.name = validate_name(&root.name)?;
+    root.path = canonical_root(&root.path)?;
+    root.tag_prefix = root.tag_prefix.as_deref().map(validate_tag).transpose()?;
+    if let Some(indexing) = &root.indexing {
+        IndexRules::new(indexing)?;
+    }
+    Ok(root)
+}
+
+fn canonical_root(path: &Path) -> Result<PathBuf, FileErr> {
+    match std::fs::canonicalize(path) {
+        Ok(root) => Ok(root),
         Err(err) => {
-            error!("failed to create dir: {parent_dir:?} to store index file due to {err:?}");
-            Err(FileErr::DBError)
+            error!("failed to resolve {path:?} due to {err:?}");
+            Err(FileErr::io(path, &err))
         }
     }
 }
 
-fn files_in_dir(path: &Path, tags: Option<Vec<String>>) -> Result<Vec<FileMetadata>, FileErr> {
-    if !path.is_dir() {
-        error!("path {path:?} is not a directory");
-        return Err(FileErr::PathDoesNotExist);
+/// Content root of the files being indexed or modified, with its settings resolved.
+struct RootContext {
+    path: PathBuf,
+    /// Empty for directories outside the registered roots.
+    name: String,
+    tag_prefix: Option<String>,
+    read_only: bool,
+    /// Replaces the global rules if set.
+    rules: Option<IndexRules>,
+}
+
+impl RootContext {
+    fn new(root: ContentRoot) -> Result<Self, FileErr> {
+        Ok(Self {
+            rules: root.indexing.as_ref().map(IndexRules::new).transpose()?,
+            path: root.path,
+            name: root.name,
+            tag_prefix: root.tag_prefix,
+            read_only: root.read_only,
+        })
     }
-    let mut metadata = Vec::new();
-    for child_path in std::fs::read_dir(path)
-        .expect("expect iteration over directory to succeed")
-        .map(|each| each.expect("expect dir entry to be valid").path())
-    {
-        if is_system_file(&child_path) {
-            continue;
+
+    fn unnamed(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            name: String::new(),
+            tag_prefix: None,
+            read_only: false,
+            rules: None,
         }
-        if child_path.is_dir() {
-            let new_tag = child_path
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .to_string();
-            let new_tags = match &tags {
-                Some(tags) => {
-                    let mut buffer = tags.clone();
-                    buffer.push(new_tag);
-                    Some(buffer)
-                }
-                None => Some(vec![new_tag]),
-            };
-            metadata.extend(files_in_dir(&child_path, new_tags)?);
-        } else if child_path.extension().is_some() {
-            metadata.push(file_metadata(&child_path, tags.clone()));
+    }
+
+    /// Tags of the files directly inside `dir`, which are the tag prefix of the root followed by
+    /// the names of the directories between the root and `dir`.
+    fn tags_of_dir(&self, dir: &Path) -> Option<Vec<String>> {
+        let dirs = dir.strip_prefix(&self.path).ok()?;
+        let tags: Vec<String> = self
+            .tag_prefix
+            .iter()
+            .cloned()
+            .chain(dirs.iter().map(|each| each.to_string_lossy().to_string()))
+            .collect();
+        if tags.is_empty() {
+            None
+        } else {
+            Some(tags)
         }
     }
-    Ok(metadata)
 }
 
-fn is_system_file(path: &Path) -> bool {
-    if path.is_dir() {
-        return false;
+impl FileMetadata {
+    fn in_root(self, root: &RootContext) -> Self {
+        Self {
+            root: root.name.clone(),
+            ..self
+        }
     }
-    let file_name = path.file_name().unwrap().to_string_lossy();
-    let extension = match path.extension() {
-        Some(extension) => extension.to_string_lossy(),
-        None => std::borrow::Cow::Borrowed(""),
-    };
-    if extension == "enc" {
-        return true;
+}
+
+fn validate_tag(tag: &str) -> Result<String, FileErr> {
+    let trimmed = tag.trim();
+    if trimmed.is_empty() {
+        error!("{tag:?} is not a valid tag");
+        Err(FileErr::InvalidName)
+    } else {
+        Ok(trimmed.to_string())
     }
-    if file_name == ".DS_Store" {
-        return true;
+}
+
+/// The directory at the `/` separated path relative to the root, which can't leave the root.
+fn dir_in_root(root: &Path, directory: Option<&str>) -> Result<PathBuf, FileErr> {
+    let mut dir = root.to_path_buf();
+    for each in directory.unwrap_or_default().split('/') {
+        if !each.is_empty() {
+            dir.push(validate_name(each)?);
+        }
     }
-    return file_name.trim().starts_with("._");
+    Ok(dir)
 }
 
-fn file_metadata(path: &Path, tags: Option<Vec<String>>) -> FileMetadata {
-    let name = path
-        .file_name()
-        .expect("expect filename")
-        .to_str()
-        .expect("expect valid file name")
-        .to_string();
-    let ty = path
-        .extension()
-        .expect("expect valid file extension")
-        .to_str()
-        .expect("expect properly formatted extension")
-        .to_string();
-    let abs_path = std::fs::canonicalize(path).expect("expect canonical path");
-    let mut hasher = DefaultHasher::new();
-    abs_path.hash(&mut hasher);
-    let id = hasher.finish();
-    FileMetadata {
+/// Longest file name most file systems support, in bytes.
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Make sure the name is a single path component so it can't be used to escape the content root.
+fn validate_name(name: &str) -> Result<String, FileErr> {
+    let trimmed = name.trim();
+    let is_valid = !trimmed.is_empty()
+        && trimmed != "."
+        && trimmed != ".."
+        && !trimmed.contains(['/', '\\', '\0']);
+    if is_valid {
+        Ok(trimmed.to_string())
+    } else {
+        error!("{name:?} is not a valid name");
+        Err(FileErr::InvalidName)
+    }
+}
+
+/// Metadata of the file at the path. Symbolic links in the path aren't resolved, so files found
+/// through a followed link are indexed at the path of the link.
+fn file_metadata(path: &Path, tags: Option<Vec<String>>) -> std::io::Result<FileMetadata> {
+    let hash = content_hash(path)?;
+    hashed_file_metadata(path, tags, hash)
+}
+
+/// Same as `file_metadata` for a file whose content hash is already known.
+fn hashed_file_metadata(
+    path: &Path,
+    tags: Option<Vec<String>>,
+    hash: blake3::Hash,
+) -> std::io::Result<FileMetadata> {
+    let abs_path = std::path::absolute(path)?;
+    let name = file_name(&abs_path)?;
+    let stat = file_stat(&abs_path);
+    let (ty, mime) = file_type(&abs_path);
+    Ok(FileMetadata {
         name,
-        id,
+        id: id_of_hash(&hash),
         ty,
+        mime,
         path: abs_path,
+        root: String::new(),
         tags,
+        user_tags: Vec::new(),
+        hash: hash.to_hex().to_string(),
+        size: stat.size,
+        modified: stat.modified,
+        created: stat.created,
+        indexed_at: unix_seconds(SystemTime::now()),
+        link: None,
+    })
+}
+
+/// Metadata of the symbolic link itself, which is identified by its target.
+fn link_metadata(path: &Path, tags: Option<Vec<String>>) -> std::io::Result<FileMetadata> {
+    let abs_path = std::path::absolute(path)?;
+    let name = file_name(&abs_path)?;
+    let target = std::fs::read_link(&abs_path)?;
+    let hash = blake3::hash(target.as_os_str().as_encoded_bytes());
+    let stat = stat_of(&abs_path, std::fs::symlink_metadata(&abs_path));
+    Ok(FileMetadata {
+        name,
+        id: id_of_hash(&hash),
+        ty: "link".to_string(),
+        mime: "inode/symlink".to_string(),
+        path: abs_path,
+        root: String::new(),
+        tags,
+        user_tags: Vec::new(),
+        hash: hash.to_hex().to_string(),
+        size: stat.size,
+        modified: stat.modified,
+        created: stat.created,
+        indexed_at: unix_seconds(SystemTime::now()),
+        link: Some(target.to_string_lossy().to_string()),
+    })
+}
+
+fn file_name(path: &Path) -> std::io::Result<String> {
+    match path.file_name() {
+        Some(name) => Ok(name.to_string_lossy().to_string()),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "path has no file name",
+        )),
     }
 }
 
+#[derive(Default)]
+struct FileStat {
+    size: u64,
+    modified: Option<u64>,
+    created: Option<u64>,
+}
+
+fn file_stat(path: &Path) -> FileStat {
+    stat_of(path, std::fs::metadata(path))
+}
+
+fn stat_of(path: &Path, metadata: io::Result<std::fs::Metadata>) -> FileStat {
+    match metadata {
+        Ok(metadata) => FileStat {
+            size: metadata.len(),
+            modified: metadata.modified().ok().and_then(unix_seconds),
+            created: metadata.created().ok().and_then(unix_seconds),
+        },
+        Err(err) => {
+            warn!("failed to read metadata of {path:?} due to {err:?}");
+            FileStat::default()
+        }
+    }
+}
+
+/// Type and MIME type of the file. The MIME type is sniffed from the magic bytes at the start of
+/// the file, falling back to guessing it from the extension for formats without a signature such
+/// as plain text. Files without an extension get the type of their sniffed content.
+fn file_type(path: &Path) -> (String, String) {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_string());
+    if let Ok(Some(kind)) = infer::get_from_path(path) {
+        let ty = extension.unwrap_or_else(|| kind.extension().to_string());
+        return (ty, kind.mime_type().to_string());
+    }
+    match extension {
+        Some(extension) => {
+            let mime = mime_guess::from_ext(&extension)
+                .first_or_octet_stream()
+                .essence_str()
+                .to_string();
+            (extension, mime)
+        }
+        None if is_text(path) => ("txt".to_string(), "text/plain".to_string()),
+        None => (String::new(), "application/octet-stream".to_string()),
+    }
+}
+
+fn is_text(path: &Path) -> bool {
+    let mut buffer = Vec::new();
+    let read = File::open(path).and_then(|file| file.take(8192).read_to_end(&mut buffer));
+    if read.is_err() || buffer.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(&buffer) {
+        Ok(_) => true,
+        // the buffer may end in the middle of a character
+        Err(err) => err.error_len().is_none(),
+    }
+}
+
+fn content_hash(path: &Path) -> std::io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// File ids are the first 8 bytes of the content hash.
+pub fn id_of_hash(hash: &blake3::Hash) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&hash.as_bytes()[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Id of a file whose content id is already taken, see `FileIndex::assign_id`. The path is
+/// included so copies get different ids, and the next `attempt` is used if the id is taken too.
+fn id_of_copy(hash: &str, path: &Path, attempt: u64) -> u64 {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(hash.as_bytes());
+    hasher.update(path.as_os_str().as_encoded_bytes());
+    hasher.update(&attempt.to_le_bytes());
+    id_of_hash(&hasher.finalize())
+}
+
 pub fn copy_files(src: &Path, dest: &Path) -> std::io::Result<()> {
     println!("src: {src:?} dest: {dest:?}");
     if !src.exists() || !dest.exists() {
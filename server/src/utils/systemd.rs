@@ -0,0 +1,53 @@
+use std::{
+    env,
+    ffi::OsStr,
+    io,
+    os::unix::{ffi::OsStrExt, net::UnixDatagram},
+    time::Duration,
+};
+
+/// Tell the service manager about a state change, e.g. `READY=1`, see `sd_notify(3)`. Returns
+/// whether the state was sent, which it isn't if the server wasn't started with `NOTIFY_SOCKET`.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => notify_socket(&socket, state).map(|_| true),
+        None => Ok(false),
+    }
+}
+
+/// Send the state to the datagram socket at `socket`, which is in the abstract namespace if it
+/// starts with `@`.
+pub fn notify_socket(socket: &OsStr, state: &str) -> io::Result<()> {
+    let sender = UnixDatagram::unbound()?;
+    match socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => send_to_abstract(&sender, name, state),
+        None => sender.send_to(state.as_bytes(), socket).map(|_| ()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_to_abstract(sender: &UnixDatagram, name: &[u8], state: &str) -> io::Result<()> {
+    use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+    let address = SocketAddr::from_abstract_name(name)?;
+    sender.send_to_addr(state.as_bytes(), &address).map(|_| ())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_to_abstract(_: &UnixDatagram, _: &[u8], _: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
+
+/// How often the service manager expects `WATCHDOG=1`, if the watchdog is enabled for this
+/// process.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec)).filter(|interval| !interval.is_zero())
+}
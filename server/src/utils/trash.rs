@@ -0,0 +1,193 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::{error, info};
+
+use super::{
+    manifest::{self, now},
+    storage::{FileErr, FileMetadata},
+};
+
+/// A deleted file waiting in the trash to be restored or purged.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone)]
+pub struct TrashEntry {
+    /// Unique id of the entry, since the same file can be in the trash more than once.
+    #[serde(default = "new_trash_id")]
+    pub id: String,
+    /// Metadata of the file at the time it was deleted, including its original path and tags.
+    pub file: FileMetadata,
+    pub trash_path: PathBuf,
+    /// Seconds since the unix epoch.
+    pub deleted_at: u64,
+}
+
+/// Outcome of `Trash::purge_expired`.
+#[derive(Debug, Default)]
+pub struct PurgeReport {
+    pub purged: usize,
+    /// Ids of the entries that couldn't be purged, with the reason.
+    pub failed: Vec<(String, FileErr)>,
+}
+
+/// Server managed directory holding deleted files. The metadata of the files in the trash is kept
+/// in a manifest inside the directory, keyed by the entry id.
+pub struct Trash {
+    dir: PathBuf,
+    entries: HashMap<String, TrashEntry>,
+    /// Whether the entries changed since `clear_changed` was last called.
+    changed: bool,
+}
+
+impl Trash {
+    pub fn open(dir: &Path) -> Result<Self, FileErr> {
+        let entries = manifest::load::<TrashEntry>(dir)?
+            .into_iter()
+            .map(|each| (each.id.clone(), each))
+            .collect();
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            entries,
+            changed: false,
+        })
+    }
+
+    pub fn entries(&self) -> Vec<TrashEntry> {
+        self.entries.values().cloned().collect()
+    }
+
+    pub fn get(&self, id: &str) -> Result<&TrashEntry, FileErr> {
+        self.entries.get(id).ok_or(FileErr::IdInvalid)
+    }
+
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    pub fn clear_changed(&mut self) {
+        self.changed = false;
+    }
+
+    /// Move the file into the trash.
+    pub fn add(&mut self, file: FileMetadata) -> Result<TrashEntry, FileErr> {
+        if let Err(err) = std::fs::create_dir_all(&self.dir) {
+            error!("failed to create trash dir {:?} due to {err:?}", self.dir);
+            return Err(FileErr::io(&self.dir, &err));
+        }
+        let id = new_trash_id();
+        let trash_path = self.dir.join(format!("{id}-{}", file.name));
+        move_file(&file.path, &trash_path)?;
+        let entry = TrashEntry {
+            id,
+            file,
+            trash_path,
+            deleted_at: now(),
+        };
+        self.entries.insert(entry.id.clone(), entry.clone());
+        if let Err(err) = self.save() {
+            let _ = move_file(&entry.trash_path, &entry.file.path);
+            self.entries.remove(&entry.id);
+            return Err(err);
+        }
+        Ok(entry)
+    }
+
+    /// Move the file back to where it was deleted from.
+    pub fn restore(&mut self, id: &str) -> Result<FileMetadata, FileErr> {
+        let entry = self.get(id)?.clone();
+        let original_path = &entry.file.path;
+        if original_path.exists() {
+            error!("can't restore {id} since {original_path:?} already exists");
+            return Err(FileErr::FileAlreadyExists);
+        }
+        if let Some(parent) = original_path.parent() {
+            if let Err(err) = std::fs::create_dir_all(parent) {
+                error!("failed to create {parent:?} due to {err:?}");
+                return Err(FileErr::io(parent, &err));
+            }
+        }
+        move_file(&entry.trash_path, original_path)?;
+        self.entries.remove(id);
+        if let Err(err) = self.save() {
+            let _ = move_file(original_path, &entry.trash_path);
+            self.entries.insert(entry.id.clone(), entry);
+            return Err(err);
+        }
+        Ok(entry.file)
+    }
+
+    /// Move a restored file back into the trash under its old entry, e.g. when it couldn't be
+    /// added to the index again.
+    pub fn undo_restore(&mut self, entry: TrashEntry) -> Result<(), FileErr> {
+        move_file(&entry.file.path, &entry.trash_path)?;
+        self.entries.insert(entry.id.clone(), entry);
+        self.save()
+    }
+
+    /// Permanently delete the file from the trash.
+    pub fn purge(&mut self, id: &str) -> Result<(), FileErr> {
+        let entry = match self.entries.remove(id) {
+            Some(entry) => entry,
+            None => return Err(FileErr::IdInvalid),
+        };
+        if let Err(err) = std::fs::remove_file(&entry.trash_path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                error!("failed to delete {:?} due to {err:?}", entry.trash_path);
+                let err = FileErr::io(&entry.trash_path, &err);
+                self.entries.insert(id.to_string(), entry);
+                return Err(err);
+            }
+        }
+        self.save()
+    }
+
+    /// Purge every file that has been in the trash for longer than `retention`. Files that can't
+    /// be purged are kept in the trash and tried again the next time.
+    pub fn purge_expired(&mut self, retention: Duration) -> PurgeReport {
+        let cutoff = now().saturating_sub(retention.as_secs());
+        let expired: Vec<String> = self
+            .entries
+            .values()
+            .filter(|each| each.deleted_at <= cutoff)
+            .map(|each| each.id.clone())
+            .collect();
+        let mut report = PurgeReport::default();
+        for id in expired {
+            match self.purge(&id) {
+                Ok(()) => report.purged += 1,
+                Err(err) => {
+                    error!("failed to purge {id} from trash due to {err}");
+                    report.failed.push((id, err));
+                }
+            }
+        }
+        if report.purged > 0 {
+            info!("purged {} expired files from trash", report.purged);
+        }
+        report
+    }
+
+    fn save(&mut self) -> Result<(), FileErr> {
+        self.changed = true;
+        let entries: Vec<&TrashEntry> = self.entries.values().collect();
+        manifest::save(&self.dir, &entries)
+    }
+}
+
+/// Rename the file, falling back to copying when `dest` is on a different file system.
+fn move_file(src: &Path, dest: &Path) -> Result<(), FileErr> {
+    if std::fs::rename(src, dest).is_ok() {
+        return Ok(());
+    }
+    let result = std::fs::copy(src, dest).and_then(|_| std::fs::remove_file(src));
+    result.map_err(|err| {
+        error!("failed to move {src:?} to {dest:?} due to {err:?}");
+        FileErr::io(src, &err)
+    })
+}
+
+fn new_trash_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
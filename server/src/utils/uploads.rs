@@ -0,0 +1,176 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::{error, info};
+
+use super::{
+    manifest::{self, now},
+    storage::{FileErr, Upload},
+};
+
+/// A resumable upload waiting for the rest of its content.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq, Clone)]
+pub struct PendingUpload {
+    pub id: String,
+    pub upload: Upload,
+    /// Size of the complete file in bytes.
+    pub length: u64,
+    /// Seconds since the unix epoch.
+    pub expires_at: u64,
+}
+
+impl PendingUpload {
+    /// Number of bytes received so far, which is the size of the temporary file.
+    pub fn offset(&self) -> u64 {
+        std::fs::metadata(&self.upload.temp_path)
+            .map(|metadata| metadata.len())
+            .unwrap_or_default()
+    }
+}
+
+/// Resumable uploads that haven't been completed yet. The content received so far is kept in the
+/// temporary files of the uploads and the rest in a manifest inside the directory, so uploads can
+/// be resumed after the server restarts.
+pub struct PendingUploads {
+    dir: PathBuf,
+    uploads: HashMap<String, PendingUpload>,
+    /// Uploads a request is appending to, which can't be appended to by another request or be
+    /// terminated until it is done, with the token of the request that locked them.
+    locked: HashMap<String, String>,
+}
+
+impl PendingUploads {
+    pub fn open(dir: &Path) -> Result<Self, FileErr> {
+        let uploads = manifest::load::<PendingUpload>(dir)?
+            .into_iter()
+            .map(|each| (each.id.clone(), each))
+            .collect();
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            uploads,
+            locked: HashMap::new(),
+        })
+    }
+
+    /// The upload unless it expired.
+    pub fn get(&self, id: &str) -> Result<PendingUpload, FileErr> {
+        match self.uploads.get(id) {
+            Some(upload) if upload.expires_at > now() => Ok(upload.clone()),
+            _ => Err(FileErr::IdInvalid),
+        }
+    }
+
+    /// The upload, unless it expired, reserved for appending to it until it is unlocked with the
+    /// same token.
+    pub fn lock(&mut self, id: &str, token: &str) -> Result<PendingUpload, FileErr> {
+        let pending = self.get(id)?;
+        if self.locked.contains_key(id) {
+            error!("upload {id} is already being appended to");
+            return Err(FileErr::UploadLocked);
+        }
+        self.locked.insert(id.to_string(), token.to_string());
+        Ok(pending)
+    }
+
+    /// Unlock the upload if it was locked with the token. Unlocking with another token, e.g. of a
+    /// request that failed to lock it, leaves the lock alone.
+    pub fn unlock(&mut self, id: &str, token: &str) {
+        if self.locked.get(id).is_some_and(|owner| owner == token) {
+            self.locked.remove(id);
+        }
+    }
+
+    /// Temporary files of the uploads, including the expired ones that weren't purged yet.
+    pub fn temp_paths(&self) -> HashSet<PathBuf> {
+        self.uploads
+            .values()
+            .map(|each| each.upload.temp_path.clone())
+            .collect()
+    }
+
+    /// Start waiting for the content of the upload, creating its empty temporary file.
+    pub fn add(
+        &mut self,
+        upload: Upload,
+        length: u64,
+        expire_after: Duration,
+    ) -> Result<PendingUpload, FileErr> {
+        if let Err(err) = std::fs::create_dir_all(&self.dir) {
+            error!("failed to create uploads dir {:?} due to {err:?}", self.dir);
+            return Err(FileErr::io(&self.dir, &err));
+        }
+        if let Err(err) = std::fs::File::create(&upload.temp_path) {
+            error!("failed to create {:?} due to {err:?}", upload.temp_path);
+            return Err(FileErr::io(&upload.temp_path, &err));
+        }
+        let pending = PendingUpload {
+            id: uuid::Uuid::new_v4().to_string(),
+            upload,
+            length,
+            expires_at: now().saturating_add(expire_after.as_secs()),
+        };
+        self.uploads.insert(pending.id.clone(), pending.clone());
+        if let Err(err) = self.save() {
+            self.uploads.remove(&pending.id);
+            remove_content(&pending);
+            return Err(err);
+        }
+        Ok(pending)
+    }
+
+    /// Stop tracking the upload, leaving its temporary file to the caller.
+    pub fn remove(&mut self, id: &str) -> Result<PendingUpload, FileErr> {
+        let pending = self.uploads.remove(id).ok_or(FileErr::IdInvalid)?;
+        if let Err(err) = self.save() {
+            self.uploads.insert(pending.id.clone(), pending);
+            return Err(err);
+        }
+        Ok(pending)
+    }
+
+    /// Cancel the upload, deleting the content received so far.
+    pub fn terminate(&mut self, id: &str) -> Result<(), FileErr> {
+        if self.locked.contains_key(id) {
+            error!("can't terminate upload {id} while it is being appended to");
+            return Err(FileErr::UploadLocked);
+        }
+        let pending = self.remove(id)?;
+        remove_content(&pending);
+        Ok(())
+    }
+
+    /// Terminate every upload that expired, except the ones still being appended to.
+    pub fn purge_expired(&mut self) -> Result<usize, FileErr> {
+        let now = now();
+        let expired: Vec<String> = self
+            .uploads
+            .values()
+            .filter(|each| each.expires_at <= now && !self.locked.contains_key(&each.id))
+            .map(|each| each.id.clone())
+            .collect();
+        for id in &expired {
+            self.terminate(id)?;
+        }
+        if !expired.is_empty() {
+            info!("purged {} expired uploads", expired.len());
+        }
+        Ok(expired.len())
+    }
+
+    fn save(&self) -> Result<(), FileErr> {
+        let uploads: Vec<&PendingUpload> = self.uploads.values().collect();
+        manifest::save(&self.dir, &uploads)
+    }
+}
+
+fn remove_content(pending: &PendingUpload) {
+    let path = &pending.upload.temp_path;
+    if let Err(err) = std::fs::remove_file(path) {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            error!("failed to delete {path:?} due to {err:?}");
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use log::{debug, error, info};
+use notify::{Event, RecursiveMode, Watcher};
+
+use super::storage::{FileErr, Message};
+
+/// Keeps the content roots watched for as long as it is alive.
+pub struct RootWatcher {
+    _watchers: Vec<notify::RecommendedWatcher>,
+}
+
+/// Watch the content roots and send the changed paths to the storage server. Events are batched
+/// until no new events arrive for `debounce`, so copying a large directory results in a few index
+/// updates instead of one per event. Each root is also rescanned once watching starts to pick up
+/// changes made while it wasn't watched.
+pub fn watch_roots(
+    roots: &[PathBuf],
+    storage_tx: Sender<Message>,
+    debounce: Duration,
+) -> Result<RootWatcher, FileErr> {
+    let mut watchers = Vec::new();
+    for root in roots {
+        let root = match std::fs::canonicalize(root) {
+            Ok(root) => root,
+            Err(err) => {
+                error!("failed to resolve content root {root:?} due to {err:?}");
+                return Err(FileErr::io(root, &err));
+            }
+        };
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut watcher =
+            match notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+                Ok(event) => {
+                    let _ = tx.send(event.paths);
+                }
+                Err(err) => error!("file system watcher failed due to {err:?}"),
+            }) {
+                Ok(watcher) => watcher,
+                Err(err) => {
+                    error!("failed to create file system watcher due to {err:?}");
+                    return Err(FileErr::PathDoesNotExist);
+                }
+            };
+        if let Err(err) = watcher.watch(&root, RecursiveMode::Recursive) {
+            error!("failed to watch {root:?} due to {err:?}");
+            return Err(FileErr::PathDoesNotExist);
+        }
+        info!("watching {root:?} for changes");
+        if storage_tx
+            .send(Message::SyncPaths(root.clone(), vec![root.clone()]))
+            .is_err()
+        {
+            error!("failed to send initial rescan of {root:?} to storage server");
+        }
+        let storage_tx = storage_tx.clone();
+        std::thread::spawn(move || debounce_events(&root, rx, storage_tx, debounce));
+        watchers.push(watcher);
+    }
+    Ok(RootWatcher {
+        _watchers: watchers,
+    })
+}
+
+fn debounce_events(
+    root: &Path,
+    rx: Receiver<Vec<PathBuf>>,
+    storage_tx: Sender<Message>,
+    debounce: Duration,
+) {
+    // don't let a steady stream of events delay updating the index indefinitely
+    let max_delay = debounce * 10;
+    let mut pending: HashSet<PathBuf> = HashSet::new();
+    let mut first_event = Instant::now();
+    loop {
+        match rx.recv_timeout(debounce) {
+            Ok(paths) => {
+                if pending.is_empty() {
+                    first_event = Instant::now();
+                }
+                pending.extend(paths);
+                if first_event.elapsed() < max_delay {
+                    continue;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        if pending.is_empty() {
+            continue;
+        }
+        debug!("{} paths changed under {root:?}", pending.len());
+        let paths = pending.drain().collect();
+        if storage_tx
+            .send(Message::SyncPaths(root.to_path_buf(), paths))
+            .is_err()
+        {
+            error!("failed to send changed paths to storage server");
+            return;
+        }
+    }
+}